@@ -8,9 +8,11 @@ use opvm2::{
     register::Register,
 };
 
+opvm2::plugin_name!("debugger");
+
 #[plugin_fn]
-pub fn name() -> FnResult<String> {
-    Ok("debugger".to_string())
+pub fn version() -> FnResult<String> {
+    Ok(env!("CARGO_PKG_VERSION").to_string())
 }
 
 // todo: handle_error should be implemented
@@ -113,7 +115,17 @@ pub fn handle_instruction(Json(ins): Json<OnInstructionValue>) -> FnResult<Optio
                 unsafe { print(format!("Instruction: {:?}\n", ins))? };
             }
             "registers" | "r" => {
-                unsafe { print(format!("{:?}\n", all_registers()?))? };
+                let registers = unsafe { all_registers()? };
+                let mut output = String::new();
+                for (register, value) in registers.iter() {
+                    let value = match register.is_float() {
+                        true => f64::from_bits(value as u64).to_string(),
+                        false => format!("{:#X}", value),
+                    };
+                    output.push_str(&format!("{}: {}\n", register, value));
+                }
+                output.push_str(&format!("pc: {:#X}\n", registers.check_pc()));
+                unsafe { print(output)? };
             }
             "labels" | "l" => {
                 unsafe { print(format!("{:?}\n", get_labels()?))? };
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use opvm2::{
+    instruction::Instruction,
+    operand::Operand,
+    parser::program::{LabelValue, Program},
+};
+
+const PROGRAM: &str = r"
+fizz: 'Fizz'
+buzz: 'Buzz'
+       mov ra, 1
+start: call calc_fizz
+       call calc_buzz
+       call print_number
+       putc 10
+       inc ra
+       test ra, 20
+       jle start
+       jmp end
+print_number:
+       pop rc
+       test rc, rc
+       jnz skip
+       pop rc
+       test rc, rc
+       jnz skip
+       print ra
+skip:  ret
+calc_fizz:
+       mov rb, ra
+       mod rb, 3
+       test rb, rb
+       jnz skip_fizz
+       puts fizz
+       push 1
+       ret
+skip_fizz:
+       push 0
+       ret
+calc_buzz:
+       mov rb, ra
+       mod rb, 5
+       test rb, rb
+       jnz skip_buzz
+       puts buzz
+       push 1
+       ret
+skip_buzz:
+       push 0
+       ret
+end:
+";
+
+// the previous format: every instruction is a 128 bit word, with 32 bit operands.
+fn encode_fixed(instruction: &Instruction, literal_map: &BTreeMap<String, usize>) -> [u8; 16] {
+    let operand_count = match (&instruction.lhs, &instruction.rhs) {
+        (Operand::None, Operand::None) => 0,
+        (Operand::None, _) => 1,
+        _ => 2,
+    };
+    let mut word = 0u128;
+    word |= (instruction.opcode.to_u8() as u128) << 122;
+    word |= (operand_count as u128) << 120;
+    word |= (instruction.lhs.operand_type() as u128 & 0b11) << 118;
+    word |= (instruction.lhs.encode(literal_map).unwrap() as u32 as u128) << 86;
+    word |= (instruction.rhs.operand_type() as u128 & 0b11) << 84;
+    word |= (instruction.rhs.encode(literal_map).unwrap() as u32 as u128) << 52;
+    word.to_le_bytes()
+}
+
+fn decode_fixed(bytes: &[u8]) -> (Instruction, usize) {
+    let word = u128::from_le_bytes(bytes[..16].try_into().unwrap());
+    let opcode = opvm2::opcode::Opcode::from_u8((word >> 122) as u8);
+    let lhs = Operand::decode(((word >> 118) & 0b11) as u8, (word >> 86) as u32 as u64).unwrap();
+    let rhs = Operand::decode(((word >> 84) & 0b11) as u8, (word >> 52) as u32 as u64).unwrap();
+    match (word >> 120) & 0b11 {
+        0 => (Instruction::new_e(opcode), 16),
+        1 => (Instruction::new_l(opcode, lhs), 16),
+        _ => (Instruction::new(opcode, lhs, rhs), 16),
+    }
+}
+
+// code labels are instruction indexes, which is close enough to their addresses for measuring.
+fn literal_map(program: &Program) -> BTreeMap<String, usize> {
+    program
+        .labels
+        .list
+        .iter()
+        .map(|(name, value)| match value {
+            LabelValue::Address(index) => (name.clone(), index * 4),
+            _ => (name.clone(), 0),
+        })
+        .collect()
+}
+
+fn decode_all(code: &[u8], decode: impl Fn(&[u8]) -> (Instruction, usize)) -> usize {
+    let mut position = 0;
+    let mut count = 0;
+    while position < code.len() {
+        let (instruction, length) = decode(&code[position..]);
+        black_box(instruction);
+        position += length;
+        count += 1;
+    }
+    count
+}
+
+fn encoding(c: &mut Criterion) {
+    let program = Program::from(PROGRAM);
+    let literal_map = literal_map(&program);
+    let fixed: Vec<u8> = program
+        .instructions
+        .iter()
+        .flat_map(|instruction| encode_fixed(instruction, &literal_map))
+        .collect();
+    let variable: Vec<u8> = program
+        .instructions
+        .iter()
+        .flat_map(|instruction| instruction.encode(&literal_map).unwrap())
+        .collect();
+    println!(
+        "{} instructions: {} bytes fixed, {} bytes variable length",
+        program.instructions.len(),
+        fixed.len(),
+        variable.len()
+    );
+
+    let mut group = c.benchmark_group("decode");
+    group.bench_function("fixed", |b| b.iter(|| decode_all(&fixed, decode_fixed)));
+    group.bench_function("variable", |b| {
+        b.iter(|| decode_all(&variable, |bytes| Instruction::decode(bytes).unwrap()))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("fixed", |b| {
+        b.iter(|| {
+            for instruction in program.instructions.iter() {
+                black_box(encode_fixed(instruction, &literal_map));
+            }
+        })
+    });
+    group.bench_function("variable", |b| {
+        b.iter(|| {
+            for instruction in program.instructions.iter() {
+                black_box(instruction.encode(&literal_map).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encoding);
+criterion_main!(benches);
@@ -0,0 +1,394 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{parser::data::unescape, register::Register};
+
+/// A constant expression, evaluated when the program is assembled, e.g. `BUF_SIZE * 4 + 1`.
+///
+/// Names refer to `equ` constants or labels, so an expression containing names can only be
+/// evaluated once the program is laid out in memory.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Expr {
+    Number(i128),
+    Name(String),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+impl BinaryOp {
+    // binding strength, higher binds tighter.
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::Xor => 2,
+            BinaryOp::And => 3,
+            BinaryOp::Shl | BinaryOp::Shr => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 6,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Or => "|",
+            BinaryOp::Xor => "^",
+        }
+    }
+
+    const ALL: [BinaryOp; 10] = [
+        BinaryOp::Shl,
+        BinaryOp::Shr,
+        BinaryOp::Add,
+        BinaryOp::Sub,
+        BinaryOp::Mul,
+        BinaryOp::Div,
+        BinaryOp::Mod,
+        BinaryOp::And,
+        BinaryOp::Or,
+        BinaryOp::Xor,
+    ];
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Expr, String> {
+        let mut parser = ExprParser { input, position: 0 };
+        let expr = parser.expression(0)?;
+        parser.skip_whitespace();
+        if parser.position != input.len() {
+            return Err(format!(
+                "Unexpected `{}` in expression `{}`",
+                &input[parser.position..],
+                input
+            ));
+        }
+        Ok(expr)
+    }
+
+    /// Evaluates the expression, looking names up with `resolve`.
+    pub fn evaluate(&self, resolve: &impl Fn(&str) -> Option<i128>) -> Result<i128, String> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Name(name) => {
+                resolve(name).ok_or_else(|| format!("`{}` is not a constant or label", name))?
+            }
+            Expr::Negate(e) => e.evaluate(resolve)?.wrapping_neg(),
+            Expr::Not(e) => !e.evaluate(resolve)?,
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(resolve)?, rhs.evaluate(resolve)?);
+                match op {
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Mod if rhs == 0 => {
+                        return Err("Division by zero in constant expression".to_string())
+                    }
+                    // `i128::MIN / -1` doesn't fit.
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or_else(overflow)?,
+                    BinaryOp::Mod => lhs.checked_rem(rhs).ok_or_else(overflow)?,
+                    BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::And => lhs & rhs,
+                    BinaryOp::Or => lhs | rhs,
+                    BinaryOp::Xor => lhs ^ rhs,
+                }
+            }
+        })
+    }
+
+    /// The names used in the expression.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => vec![],
+            Expr::Name(name) => vec![name.as_str()],
+            Expr::Negate(e) | Expr::Not(e) => e.names(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut names = lhs.names();
+                names.extend(rhs.names());
+                names
+            }
+        }
+    }
+}
+
+fn overflow() -> String {
+    "Overflow in constant expression".to_string()
+}
+
+/// Parses a number: decimal, hex with `0x`, binary with `0b` or octal with `0o`. Digits can be
+/// separated with `_`, e.g. `1_000_000`, and a `u8`, `u16`, `u32` or `u64` suffix gives the
+/// number a size it has to fit in, e.g. `0xFFu8`.
+pub fn parse_number(i: &str) -> Result<i128, String> {
+    let (digits, bits) = match ["u8", "u16", "u32", "u64"]
+        .into_iter()
+        .find(|suffix| i.ends_with(suffix))
+    {
+        Some(suffix) => (
+            &i[..i.len() - suffix.len()],
+            suffix[1..].parse::<u32>().ok(),
+        ),
+        None => (i, None),
+    };
+    let (digits, radix) = match digits.get(..2) {
+        Some("0x") => (&digits[2..], 16),
+        Some("0b") => (&digits[2..], 2),
+        Some("0o") => (&digits[2..], 8),
+        _ => (digits, 10),
+    };
+    let digits = digits.replace('_', "");
+    // from_str_radix accepts a sign, which belongs to the expression instead.
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(format!("Invalid number `{}`", i));
+    }
+    let value =
+        i128::from_str_radix(&digits, radix).map_err(|_| format!("Number `{}` is too large", i))?;
+    match bits {
+        Some(bits) if value >= 1i128 << bits => {
+            Err(format!("Number `{}` doesn't fit in {} bits", i, bits))
+        }
+        _ => Ok(value),
+    }
+}
+
+struct ExprParser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    // precedence climbing: parses operators that bind at least as tight as `min_precedence`.
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        loop {
+            self.skip_whitespace();
+            let op = match BinaryOp::ALL
+                .into_iter()
+                .find(|op| self.rest().starts_with(op.symbol()))
+            {
+                Some(op) if op.precedence() >= min_precedence => op,
+                _ => return Ok(lhs),
+            };
+            self.position += op.symbol().len();
+            let rhs = self.expression(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        if let Some(c @ ('-' | '~' | '+')) = self.rest().chars().next() {
+            self.position += 1;
+            let operand = self.unary()?;
+            return Ok(match c {
+                '-' => Expr::Negate(Box::new(operand)),
+                '~' => Expr::Not(Box::new(operand)),
+                _ => operand,
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let rest = self.rest();
+        let c = rest
+            .chars()
+            .next()
+            .ok_or_else(|| format!("Expression `{}` ends unexpectedly", self.input))?;
+        if c == '(' {
+            self.position += 1;
+            let expr = self.expression(0)?;
+            self.skip_whitespace();
+            if !self.rest().starts_with(')') {
+                return Err(format!("Missing `)` in expression `{}`", self.input));
+            }
+            self.position += 1;
+            return Ok(expr);
+        }
+        if c == '\'' || c == '"' {
+            return self.character(c);
+        }
+
+        let length = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let word = &rest[..length];
+        if word.is_empty() {
+            return Err(format!(
+                "Unexpected `{}` in expression `{}`",
+                rest, self.input
+            ));
+        }
+        self.position += length;
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(word).map(Expr::Number);
+        }
+        if Register::try_from(word.to_string()).is_ok() {
+            return Err(format!(
+                "Register `{}` can't be used in constant expression `{}`",
+                word, self.input
+            ));
+        }
+        Ok(Expr::Name(word.to_string()))
+    }
+
+    // a character literal such as 'A' or '\n'.
+    fn character(&mut self, quote: char) -> Result<Expr, String> {
+        let rest = &self.rest()[1..];
+        let mut escaped = false;
+        let end = rest
+            .char_indices()
+            .find(|(_, c)| {
+                let end = !escaped && *c == quote;
+                escaped = !escaped && *c == '\\';
+                end
+            })
+            .map(|(index, _)| index)
+            .ok_or_else(|| format!("Unterminated character in expression `{}`", self.input))?;
+        let bytes = unescape(&rest[..end])?;
+        if bytes.len() != 1 {
+            return Err(format!(
+                "Character literal {}{}{} must be a single byte",
+                quote,
+                &rest[..end],
+                quote
+            ));
+        }
+        self.position += end + 2;
+        Ok(Expr::Number(bytes[0] as i128))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_number, BinaryOp, Expr};
+
+    fn evaluate(input: &str) -> Result<i128, String> {
+        Expr::parse(input)?.evaluate(&|name| match name {
+            "SIZE" => Some(16),
+            "start" => Some(32),
+            "end" => Some(96),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn can_evaluate_expressions() {
+        assert_eq!(evaluate("SIZE * 4 + 1"), Ok(65));
+        assert_eq!(evaluate("1 + SIZE * 4"), Ok(65));
+        assert_eq!(evaluate("(1 + SIZE) * 4"), Ok(68));
+        assert_eq!(evaluate("end - start"), Ok(64));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3));
+        assert_eq!(evaluate("-2 * -3"), Ok(6));
+        assert_eq!(evaluate("1 << 4 | 0x3 & ~1"), Ok(18));
+        assert_eq!(evaluate("17 % 5 ^ 1"), Ok(3));
+        assert_eq!(evaluate("'A' + 1"), Ok(66));
+        assert_eq!(evaluate(r"'\n'"), Ok(10));
+        assert_eq!(evaluate("','"), Ok(44));
+    }
+
+    #[test]
+    fn can_parse_numbers() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert_eq!(parse_number("0x2A"), Ok(42));
+        assert_eq!(parse_number("0b10_1010"), Ok(42));
+        assert_eq!(parse_number("0o755"), Ok(493));
+        assert_eq!(parse_number("1_000_000"), Ok(1_000_000));
+        assert_eq!(parse_number("0xFFu8"), Ok(255));
+        assert_eq!(parse_number("65535u16"), Ok(65535));
+        assert_eq!(
+            parse_number("0x100u8"),
+            Err("Number `0x100u8` doesn't fit in 8 bits".to_string())
+        );
+        assert_eq!(
+            parse_number("0b102"),
+            Err("Invalid number `0b102`".to_string())
+        );
+        assert_eq!(parse_number("0x"), Err("Invalid number `0x`".to_string()));
+        assert_eq!(
+            parse_number("12abc"),
+            Err("Invalid number `12abc`".to_string())
+        );
+    }
+
+    #[test]
+    fn can_parse_expressions() {
+        assert_eq!(
+            Expr::parse("table + 16"),
+            Ok(Expr::Binary(
+                BinaryOp::Add,
+                Box::new(Expr::Name("table".to_string())),
+                Box::new(Expr::Number(16))
+            ))
+        );
+        assert_eq!(
+            Expr::parse("end - start").unwrap().names(),
+            vec!["end", "start"]
+        );
+    }
+
+    #[test]
+    fn can_reject_invalid_expressions() {
+        assert_eq!(
+            evaluate("ra + 1"),
+            Err("Register `ra` can't be used in constant expression `ra + 1`".to_string())
+        );
+        assert_eq!(
+            evaluate("missing + 1"),
+            Err("`missing` is not a constant or label".to_string())
+        );
+        assert_eq!(
+            evaluate("(1 + 2"),
+            Err("Missing `)` in expression `(1 + 2`".to_string())
+        );
+        assert_eq!(
+            evaluate("1 +"),
+            Err("Expression `1 +` ends unexpectedly".to_string())
+        );
+        assert_eq!(
+            evaluate("4 / (SIZE - 16)"),
+            Err("Division by zero in constant expression".to_string())
+        );
+        assert_eq!(
+            evaluate("(1 << 127) / -1"),
+            Err("Overflow in constant expression".to_string())
+        );
+        assert_eq!(
+            evaluate("(1 << 127) % -1"),
+            Err("Overflow in constant expression".to_string())
+        );
+        assert_eq!(
+            evaluate("'ab'"),
+            Err("Character literal 'ab' must be a single byte".to_string())
+        );
+    }
+}
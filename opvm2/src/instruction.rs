@@ -37,91 +37,238 @@ impl Instruction {
         }
     }
 
-    // fuck it, we use a 128 bit instruction size, aka 16 bytes.
-    // data map: [0-127]
-    // [0-5] = opcode
-    // [6-7] = operand count (none, lhs, rhs, both)
-    // [8-40] = if plugin opcode, this is where the plugin string comes from (address, 32 bits)
-    // [41-42] = lhs type (register, number, address, literal address?)
-    // [43-75] = lhs (32 bit operator)
-    // [76-77] = rhs type (register, number, address, literal address?)
-    // [78-110] = rhs (32 bit operator)
-    // [111-127] = 16 bits reserved
-    // 32 bit memory address
-    // operand mapping:
-    // [0-4] = register
-    // [0-32] = number
-    // [0-32] = address
-
-    pub fn encode(&self, literal_map: &BTreeMap<String, usize>) -> u128 {
-        let mut instruction = 0u128;
-        instruction |= (self.opcode.to_u8() as u128) << 122;
-        instruction |= (self.operand_count() as u128) << 120;
-        instruction |= (self.lhs.operand_type() as u128) << 118;
-        instruction |= (self.lhs.encode(literal_map) as u128) << 86;
-        instruction |= (self.rhs.operand_type() as u128) << 84;
-        instruction |= (self.rhs.encode(literal_map) as u128) << 52;
-        // last 32 bits is for opcode custom name.
+    // instructions are variable length, so that e.g. `ret` takes 2 bytes and `mov ra, 1` takes 4:
+    // [0] = opcode
+    // [1] = operand types, lhs in the low 4 bits and rhs in the high 4 bits (none, register,
+    //       number, address, offset, relative, float)
+    // an offset operand holds its register in the low byte and its displacement above it, zigzag
+    // encoded like relative operands.
+    // if plugin opcode, the address of the plugin name (LEB128)
+    // lhs, if it has one (LEB128)
+    // rhs, if it has one (LEB128)
+    // LEB128 stores 7 bits per byte, with the high bit set on every byte but the last, so small
+    // numbers, registers and addresses take a byte or two and a full 64 bit value takes 10.
+
+    /// Encodes the instruction into as few bytes as possible.
+    pub fn encode(&self, literal_map: &BTreeMap<String, usize>) -> Result<Vec<u8>, String> {
+        self.encode_padded(literal_map, 0)
+    }
+
+    /// Encodes the instruction into at least `length` bytes, by padding its last operand.
+    ///
+    /// Used when the address of a label has to stay put while the code is laid out.
+    pub fn encode_padded(
+        &self,
+        literal_map: &BTreeMap<String, usize>,
+        length: usize,
+    ) -> Result<Vec<u8>, String> {
+        let mut values = vec![];
         if self.opcode.is_plugin() {
-            // we should maybe insert this label into memory to use it there?
-            instruction |= (self.opcode.get_plugin_address(literal_map) as u128) << 20;
+            values.push(self.opcode.get_plugin_address(literal_map) as u64);
+        }
+        for operand in [&self.lhs, &self.rhs] {
+            if operand.operand_type() != 0 {
+                values.push(operand.encode(literal_map)?);
+            }
         }
 
-        // try to reconstruct:
-        // println!("Opcode: {}", instruction >> 122);
-        // println!("Operand Count: {}", (instruction >> 120) & 0b11);
-        // println!("LHS Type: {}", (instruction >> 118) & 0b11);
-        // println!("LHS: {}", (instruction >> 86) & 0xFFFFFFFF);
-        // println!("RHS Type: {}", (instruction >> 84) & 0b11);
-        // println!("RHS: {}", (instruction >> 52) & 0xFFFFFFFF);
-        // // reconstruct the instruction
-        // println!("{:?}", Self::decode(instruction));
-        instruction
+        let mut bytes = vec![
+            self.opcode.to_u8(),
+            self.lhs.operand_type() | self.rhs.operand_type() << 4,
+        ];
+        for (index, value) in values.iter().enumerate() {
+            let min_length = match index == values.len() - 1 {
+                true => length.saturating_sub(bytes.len()),
+                false => 0,
+            };
+            write_leb128(&mut bytes, *value, min_length);
+        }
+        Ok(bytes)
     }
 
-    pub fn decode(instruction: u128) -> Instruction {
-        let mut opcode = Opcode::from_u8((instruction >> 122) as u8);
+    /// Decodes the instruction at the start of `bytes`, returning it with its length in bytes.
+    pub fn decode(bytes: &[u8]) -> Result<(Instruction, usize), String> {
+        let (opcode, types) = match bytes {
+            [opcode, types, ..] => (*opcode, *types),
+            _ => return Err("Instruction ends unexpectedly".to_string()),
+        };
+        let mut position = 2;
+        let mut opcode = Opcode::from_u8(opcode);
         if opcode.is_plugin() {
-            opcode = Opcode::Plugin(PluginValue::Address(
-                ((instruction >> 20) & 0xFFFFFFFF) as u32,
-            ));
+            let address = read_leb128(bytes, &mut position)?;
+            opcode = Opcode::Plugin(PluginValue::Address(address as u32));
         }
 
-        let operand_count = (instruction >> 120) & 0b11;
-        match operand_count {
-            1 => {
-                let lhs_type = (instruction >> 118) & 0b11;
-                let lhs =
-                    Operand::decode(lhs_type as u8, ((instruction >> 86) & 0xFFFFFFFF) as u32);
-                Instruction::new_l(opcode, lhs)
+        let mut operands = [Operand::None, Operand::None];
+        for (index, operand_type) in [types & 0xF, types >> 4].into_iter().enumerate() {
+            if operand_type != 0 {
+                operands[index] =
+                    Operand::decode(operand_type, read_leb128(bytes, &mut position)?)?;
             }
-            2 => {
-                let lhs_type = (instruction >> 118) & 0b11;
-                let lhs =
-                    Operand::decode(lhs_type as u8, ((instruction >> 86) & 0xFFFFFFFF) as u32);
-                let rhs_type = (instruction >> 84) & 0b11;
-                let rhs =
-                    Operand::decode(rhs_type as u8, ((instruction >> 52) & 0xFFFFFFFF) as u32);
-                Instruction::new(opcode, lhs, rhs)
+        }
+        let [lhs, rhs] = operands;
+        Ok((Instruction::new(opcode, lhs, rhs), position))
+    }
+}
+
+fn write_leb128(bytes: &mut Vec<u8>, mut value: u64, min_length: usize) {
+    let mut length = 1;
+    while value >= 0x80 || length < min_length {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+        length += 1;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_leb128(bytes: &[u8], position: &mut usize) -> Result<u64, String> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes
+            .get(*position)
+            .ok_or_else(|| "Instruction ends unexpectedly".to_string())?;
+        *position += 1;
+        if shift >= 64 {
+            // padding can only add zeroes.
+            if byte & 0x7F != 0 {
+                return Err("Operand doesn't fit in 64 bits".to_string());
             }
-            _ => Instruction::new_e(opcode),
+        } else {
+            value |= ((byte & 0x7F) as u64) << shift;
+        }
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::Instruction;
+    use crate::{
+        opcode::{Opcode, PluginValue},
+        operand::{Offset, Operand},
+        register::Register,
+    };
+
+    fn round_trip(instruction: &Instruction) -> usize {
+        let bytes = instruction.encode(&BTreeMap::new()).unwrap();
+        assert_eq!(
+            Instruction::decode(&bytes),
+            Ok((instruction.clone(), bytes.len()))
+        );
+        bytes.len()
+    }
+
+    #[test]
+    fn can_round_trip_immediates() {
+        for (value, length) in [
+            (0, 4),
+            (0x7F, 4),
+            (0x80, 5),
+            (u32::MAX as usize, 8),
+            (u32::MAX as usize + 1, 8),
+            (0x1234_5678_9ABC_DEF0, 12),
+            (usize::MAX, 13),
+        ] {
+            let instruction = Instruction::new(
+                Opcode::Mov,
+                Operand::Register(Register::Ra),
+                Operand::Number(value),
+            );
+            assert_eq!(round_trip(&instruction), length, "{:#X}", value);
+        }
+    }
+
+    #[test]
+    fn can_round_trip_instructions() {
+        assert_eq!(round_trip(&Instruction::new_e(Opcode::Return)), 2);
+        assert_eq!(
+            round_trip(&Instruction::new_l(
+                Opcode::Jmp,
+                Operand::Label(crate::parser::program::LabelValue::Address(300))
+            )),
+            4
+        );
+        assert_eq!(
+            round_trip(&Instruction::new(
+                Opcode::Test,
+                Operand::Number(u64::MAX as usize - 1),
+                Operand::Number(7),
+            )),
+            13
+        );
+        assert_eq!(
+            round_trip(&Instruction::new_l(
+                Opcode::Plugin(PluginValue::Address(0x1000)),
+                Operand::Register(Register::Rb)
+            )),
+            5
+        );
+    }
+
+    #[test]
+    fn can_pad_instructions() {
+        let instruction = Instruction::new_l(Opcode::Push, Operand::Number(5));
+        let bytes = instruction.encode_padded(&BTreeMap::new(), 8).unwrap();
+        assert_eq!(bytes.len(), 8);
+        assert_eq!(Instruction::decode(&bytes), Ok((instruction, 8)));
+        assert_eq!(
+            Instruction::decode(&[Opcode::Push.to_u8(), 2, 0x80]),
+            Err("Instruction ends unexpectedly".to_string())
+        );
+    }
+
+    #[test]
+    fn can_report_bad_registers() {
+        // e.g. when jumping into data.
+        assert_eq!(
+            Instruction::decode(&[Opcode::Push.to_u8(), 1, 0x7F]),
+            Err(format!(
+                "Bad register 0x7F, there are {} registers",
+                crate::register::COUNT
+            ))
+        );
+    }
+
+    #[test]
+    fn can_round_trip_relative_operands() {
+        for (offset, length) in [(0, 3), (-1, 3), (63, 3), (-64, 3), (64, 4), (-300, 4)] {
+            let instruction = Instruction::new_l(Opcode::Jmp, Operand::Relative(offset));
+            assert_eq!(round_trip(&instruction), length, "{}", offset);
+        }
+        for offset in [isize::MAX, isize::MIN] {
+            let instruction = Instruction::new_l(Opcode::Call, Operand::Relative(offset));
+            assert_eq!(round_trip(&instruction), 12);
         }
+        assert_eq!(Operand::Relative(-3).get_relative(10), Some(7));
     }
 
-    pub fn get_u8_array(data: u128) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        for i in 0..16 {
-            let byte = (data >> (i * 8)) as u8;
-            bytes.push(byte);
+    #[test]
+    fn can_round_trip_float_operands() {
+        for (value, length) in [(0.0, 4), (1.5, 6), (-2.0, 5), (0.1, 13), (f64::INFINITY, 6)] {
+            let instruction = Instruction::new(
+                Opcode::Fadd,
+                Operand::Register(Register::F0),
+                Operand::Float(value),
+            );
+            assert_eq!(round_trip(&instruction), length, "{}", value);
         }
-        bytes
     }
 
-    fn operand_count(&self) -> u8 {
-        match (&self.lhs, &self.rhs) {
-            (Operand::None, Operand::None) => 0,
-            (Operand::None, _) => 1,
-            _ => 2,
+    #[test]
+    fn can_round_trip_offset_operands() {
+        for (displacement, length) in [(0, 4), (-8, 5), (8, 5), (-0x1000, 6)] {
+            let instruction = Instruction::new(
+                Opcode::Mov,
+                Operand::Offset(Offset::new(Register::Bp, displacement)),
+                Operand::Register(Register::Sp),
+            );
+            assert_eq!(round_trip(&instruction), length, "{}", displacement);
         }
     }
 }
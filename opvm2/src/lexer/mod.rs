@@ -1,34 +1,56 @@
 pub mod token;
 
-use self::token::{Expression, ExpressionOffset, LabelWithLiteral, SideType, Token, TokenType};
+use crate::preprocessor::Line;
+
+use self::token::{
+    Constant, DataDefinition, Expression, ExpressionOffset, LabelWithLiteral, SideType, Token,
+    TokenType,
+};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until1, take_while, take_while1},
+    bytes::complete::{tag, take_while, take_while1},
     character::complete::one_of,
     combinator::{opt, recognize},
-    error::Error,
+    error::{Error, ErrorKind},
+    multi::separated_list1,
     sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
 
+#[cfg(test)]
 pub(crate) fn lex_input(i: &str) -> Result<Vec<Vec<Token>>, String> {
+    let lines: Vec<Line> = i
+        .lines()
+        .enumerate()
+        .map(|(line_num, line)| Line {
+            text: line.to_string(),
+            location: crate::preprocessor::Location {
+                file: None,
+                line: line_num,
+            },
+        })
+        .collect();
+    lex_lines(&lines)
+}
+
+pub(crate) fn lex_lines(lines: &[Line]) -> Result<Vec<Vec<Token>>, String> {
     // line by line lexing
     let mut tokens: Vec<Vec<Token>> = vec![];
     let mut errors: Vec<String> = vec![];
-    for (line_num, line) in i.lines().enumerate() {
-        let result = lex_line(line);
+    for line in lines {
+        let result = lex_line(&line.text);
         match result {
             Ok((_, line_tokens)) => {
-                if line_tokens.len() > 0 {
+                if !line_tokens.is_empty() {
                     tokens.push(line_tokens);
                 }
             }
             Err(e) => {
-                errors.push(format!("Line {}: {}", line_num, e));
+                errors.push(format!("{}: {}", line.location, e));
             }
         }
     }
-    if errors.len() > 0 {
+    if !errors.is_empty() {
         Err(errors.join("\n"))
     } else {
         Ok(tokens)
@@ -62,9 +84,11 @@ fn lex_line(i: &str) -> IResult<&str, Vec<Token>> {
         }
 
         let res = build_token(comment(og), TokenType::Comment)
+            .or_else(|_| constant(og))
             .or_else(|_| literal(og))
             .or_else(|_| build_token(label(og), TokenType::Label))
             .or_else(|_| build_token(directive(og), TokenType::Directive))
+            .or_else(|_| data_definition(og))
             .or_else(|_| expression(og));
         if let Ok((out, tok)) = res {
             tokens.push(tok);
@@ -90,11 +114,7 @@ fn comment(i: &str) -> IResult<&str, &str> {
 
 fn literal(i: &str) -> IResult<&str, Token> {
     let (left, label) = label(i)?;
-    let (left, literal) = alt((
-        literal_single_quote,
-        literal_double_quote,
-        literal_value_only,
-    ))(left)?;
+    let (left, literal) = alt((literal_quoted, literal_value_only))(left)?;
     // if we couldn't parse any literal out of this, then we return an error.
     if literal.len() == 0 {
         return Err(nom::Err::Error(nom::error::Error::new(
@@ -111,32 +131,96 @@ fn literal(i: &str) -> IResult<&str, Token> {
     ))
 }
 
+// a number, e.g. `42`, `0b1010` or `1_000u16`.
 fn literal_value_only(i: &str) -> IResult<&str, &str> {
-    preceded(opt(whitespace), take_while(|c| (c as char).is_digit(10)))(i)
-}
-
-fn literal_single_quote(i: &str) -> IResult<&str, &str> {
     preceded(
         opt(whitespace),
-        delimited(tag("'"), take_while(|c| c != '\''), tag("'")),
+        recognize(preceded(
+            take_while1(|c: char| c.is_ascii_digit()),
+            take_while(|c: char| c.is_alphanumeric() || c == '_'),
+        )),
     )(i)
 }
 
-fn literal_double_quote(i: &str) -> IResult<&str, &str> {
-    preceded(
+fn literal_quoted(i: &str) -> IResult<&str, &str> {
+    preceded(opt(whitespace), quoted)(i)
+}
+
+// a string in single or double quotes, returned without the quotes and with its escape
+// sequences (e.g. `\'`) left as they are.
+fn quoted(i: &str) -> IResult<&str, &str> {
+    let quote = match i.chars().next() {
+        Some(c @ ('\'' | '"')) => c,
+        _ => return Err(nom::Err::Error(Error::new(i, ErrorKind::Char))),
+    };
+    let mut escaped = false;
+    for (index, c) in i.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return Ok((&i[index + 1..], &i[1..index]));
+        }
+    }
+    Err(nom::Err::Error(Error::new(i, ErrorKind::Char)))
+}
+
+fn whitespace1(i: &str) -> IResult<&str, &str> {
+    take_while1(|c| c == ' ')(i)
+}
+
+fn constant(i: &str) -> IResult<&str, Token> {
+    let (i, name) = preceded(opt(whitespace), take_until_whitespace)(i)?;
+    let (i, _) = tuple((opt(tag(":")), whitespace1, tag("equ"), whitespace1))(i)?;
+    let (i, value) = take_while1(|c| c != ';')(i)?;
+    Ok((
+        i,
+        Token::Constant(Constant {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+        }),
+    ))
+}
+
+fn data_definition(i: &str) -> IResult<&str, Token> {
+    let (i, times) = preceded(
         opt(whitespace),
-        delimited(tag("\""), take_while(|c| c != '"'), tag("\"")),
-    )(i)
+        opt(delimited(
+            terminated(tag("times"), whitespace1),
+            take_until_whitespace,
+            whitespace1,
+        )),
+    )(i)?;
+    let (i, directive) = terminated(
+        alt((tag("db"), tag("dw"), tag("dd"), tag("dq"))),
+        whitespace1,
+    )(i)?;
+    let (i, values) = separated_list1(
+        tag(","),
+        delimited(
+            opt(whitespace),
+            alt((
+                recognize(quoted),
+                take_while1(|c| c != ',' && c != ';' && c != ' '),
+            )),
+            opt(whitespace),
+        ),
+    )(i)?;
+    Ok((
+        i,
+        Token::Data(DataDefinition {
+            directive: directive.to_string(),
+            times: times.map(|t| t.to_string()),
+            values: values.into_iter().map(|v| v.to_string()).collect(),
+        }),
+    ))
 }
 
 fn take_until_whitespace(i: &str) -> IResult<&str, &str> {
     take_while1(|c| (c as char).is_alphanumeric() || c == '_')(i)
 }
 
-fn take_until_whitespace_with_offset(i: &str) -> IResult<&str, &str> {
-    recognize(delimited(tag("["), take_until1("]"), tag("]")))(i)
-}
-
 fn expression(i: &str) -> IResult<&str, Token> {
     // this expression needs an opcode, and then operands, potentially registers or literals.
     let (i, opcode) = preceded(
@@ -155,19 +239,14 @@ fn expression(i: &str) -> IResult<&str, Token> {
         ));
     }
 
-    let mut parser = tuple((
-        preceded(opt(whitespace), opt(take_until_whitespace)),
-        opt(tag::<_, _, Error<_>>(",")),
-        preceded(
-            opt(whitespace),
-            opt(alt((
-                take_until_whitespace,
-                take_until_whitespace_with_offset,
-            ))),
-        ),
-    ));
-
-    let (i, (lhs, _, rhs)) = parser(i)?;
+    let (i, lhs) = preceded(opt(whitespace), opt(operand))(i)?;
+    let (i, rhs) = match lhs {
+        Some(_) => opt(preceded(
+            delimited(opt(whitespace), tag(","), opt(whitespace)),
+            operand,
+        ))(i)?,
+        None => (i, None),
+    };
 
     Ok((
         i,
@@ -179,13 +258,49 @@ fn expression(i: &str) -> IResult<&str, Token> {
     ))
 }
 
+// an operand runs up to the next `,` or `;` that isn't inside of quotes or brackets, so
+// that it can hold an expression such as `SIZE * 4` or `','`.
+fn operand(i: &str) -> IResult<&str, &str> {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut end = i.len();
+    for (index, c) in i.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' => quote = Some(c),
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth = depth.saturating_sub(1),
+            ',' | ';' if depth == 0 => {
+                end = index;
+                break;
+            }
+            _ => (),
+        }
+    }
+    let operand = i[..end].trim_end();
+    if operand.is_empty() {
+        return Err(nom::Err::Error(Error::new(i, ErrorKind::TakeWhile1)));
+    }
+    Ok((&i[operand.len()..], operand))
+}
+
 fn side_type_or_none(i: Option<&str>) -> SideType {
     match i {
         Some(s) => {
-            let e_offset = expression_offset(s).map(|(_, e)| SideType::Offset(e));
-            match e_offset {
-                Ok(e) => e,
-                Err(_) => SideType::Normal(s.to_string()),
+            // only a whole operand in brackets is an offset.
+            match expression_offset(s) {
+                Ok(("", e)) => SideType::Offset(e),
+                _ => SideType::Normal(s.to_string()),
             }
         }
         None => SideType::None,
@@ -227,7 +342,9 @@ fn directive(i: &str) -> IResult<&str, &str> {
 
 #[cfg(test)]
 mod test {
-    use crate::lexer::token::{Expression, ExpressionOffset, LabelWithLiteral, SideType, Token};
+    use crate::lexer::token::{
+        Constant, DataDefinition, Expression, ExpressionOffset, LabelWithLiteral, SideType, Token,
+    };
 
     #[test]
     fn can_parse_comments() {
@@ -527,4 +644,115 @@ mod test {
             ))
         );
     }
+
+    #[test]
+    fn can_parse_data_definitions() {
+        assert_eq!(
+            super::lex_line("table: dq 1, 0x2, -3 ; fibonacci"),
+            Ok((
+                "",
+                vec![
+                    Token::Label("table".to_string()),
+                    Token::Data(DataDefinition {
+                        directive: "dq".to_string(),
+                        times: None,
+                        values: vec!["1".to_string(), "0x2".to_string(), "-3".to_string()]
+                    }),
+                    Token::Comment("fibonacci".to_string())
+                ]
+            ))
+        );
+        assert_eq!(
+            super::lex_line("  buf: times 64 db 0"),
+            Ok((
+                "",
+                vec![
+                    Token::Label("buf".to_string()),
+                    Token::Data(DataDefinition {
+                        directive: "db".to_string(),
+                        times: Some("64".to_string()),
+                        values: vec!["0".to_string()]
+                    })
+                ]
+            ))
+        );
+        assert_eq!(
+            super::lex_line(r"db 'it\'s, ok', 10"),
+            Ok((
+                "",
+                vec![Token::Data(DataDefinition {
+                    directive: "db".to_string(),
+                    times: None,
+                    values: vec![r"'it\'s, ok'".to_string(), "10".to_string()]
+                })]
+            ))
+        );
+    }
+
+    #[test]
+    fn can_parse_constants() {
+        assert_eq!(
+            super::lex_line("SIZE equ 64 ; bytes"),
+            Ok((
+                "",
+                vec![
+                    Token::Constant(Constant {
+                        name: "SIZE".to_string(),
+                        value: "64".to_string()
+                    }),
+                    Token::Comment("bytes".to_string())
+                ]
+            ))
+        );
+        assert_eq!(
+            super::lex_line("  COUNT: equ SIZE"),
+            Ok((
+                "",
+                vec![Token::Constant(Constant {
+                    name: "COUNT".to_string(),
+                    value: "SIZE".to_string()
+                })]
+            ))
+        );
+    }
+
+    #[test]
+    fn can_parse_expression_operands() {
+        assert_eq!(
+            super::lex_line("mov ra, SIZE * 4 + 1 ; scaled"),
+            Ok((
+                "",
+                vec![
+                    Token::Expression(Expression {
+                        opcode: "mov".to_string(),
+                        lhs: SideType::Normal("ra".to_string()),
+                        rhs: SideType::Normal("SIZE * 4 + 1".to_string()),
+                    }),
+                    Token::Comment("scaled".to_string())
+                ]
+            ))
+        );
+        assert_eq!(
+            super::lex_line("mov ra, ','"),
+            Ok((
+                "",
+                vec![Token::Expression(Expression {
+                    opcode: "mov".to_string(),
+                    lhs: SideType::Normal("ra".to_string()),
+                    rhs: SideType::Normal("','".to_string()),
+                })]
+            ))
+        );
+        assert_eq!(
+            super::lex_line("jmp table + 16"),
+            Ok((
+                "",
+                vec![Token::Expression(Expression {
+                    opcode: "jmp".to_string(),
+                    lhs: SideType::Normal("table + 16".to_string()),
+                    rhs: SideType::None,
+                })]
+            ))
+        );
+    }
 }
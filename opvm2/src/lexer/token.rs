@@ -5,6 +5,8 @@ pub enum Token {
     LabelWithLiteral(LabelWithLiteral),
     Directive(String),
     Expression(Expression),
+    Data(DataDefinition),
+    Constant(Constant),
 }
 
 #[derive(Debug, PartialEq)]
@@ -40,3 +42,18 @@ pub struct ExpressionOffset {
     pub operator: Option<String>,
     pub rhs: Option<String>,
 }
+
+/// A `db`, `dw`, `dd` or `dq` line, with its values as written (strings keep their quotes).
+#[derive(Debug, PartialEq)]
+pub struct DataDefinition {
+    pub directive: String,
+    pub times: Option<String>,
+    pub values: Vec<String>,
+}
+
+/// A `name equ value` line.
+#[derive(Debug, PartialEq)]
+pub struct Constant {
+    pub name: String,
+    pub value: String,
+}
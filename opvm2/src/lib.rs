@@ -1,8 +1,10 @@
+pub mod expr;
 pub mod instruction;
 pub mod lexer;
 pub mod opcode;
 pub mod operand;
 pub mod parser;
 pub mod plugin_interface;
+pub mod preprocessor;
 pub mod register;
 pub mod stack;
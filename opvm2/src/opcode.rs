@@ -20,6 +20,14 @@ pub enum Opcode {
     Push,
     Pop,
     Dup,
+    Swap,
+    Over,
+    Rot,
+    Drop,
+    Pick,
+    Depth,
+    Pushall,
+    Popall,
     /* Program Flow */
     Test,
     Jmp,
@@ -33,9 +41,42 @@ pub enum Opcode {
     Jnz,
     Call,
     Return,
+    Lea,
+    Enter,
+    Leave,
+    /* Floating point */
+    Fadd,
+    Fsub,
+    Fmul,
+    Fdiv,
+    Fsqrt,
+    Fcmp,
+    Itof,
+    Ftoi,
+    Printf,
+    /* Interrupts */
+    Int,
+    Iret,
+    Cli,
+    Sti,
+    Ivt,
+    Timer,
+    /* Threads */
+    Spawn,
+    Yield,
+    Join,
+    Send,
+    Recv,
+    Lock,
+    Unlock,
     /* Various */
     Assert,
     Print,
+    Printx,
+    Putc,
+    Puts,
+    Getc,
+    Readln,
     Sleep,
     Nop,
     Halt,
@@ -61,6 +102,30 @@ impl Display for PluginValue {
 }
 
 impl Opcode {
+    /// Whether the opcode runs the code at the address in its operand, by jumping there or, for
+    /// `spawn`, in a new thread.
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            Self::Jmp
+                | Self::Je
+                | Self::Jne
+                | Self::Jle
+                | Self::Jge
+                | Self::Jl
+                | Self::Jg
+                | Self::Jz
+                | Self::Jnz
+                | Self::Call
+                | Self::Spawn
+        )
+    }
+
+    /// Whether the opcode's rhs is an address, like `lea`'s, or the handler `ivt` installs.
+    pub fn takes_address(&self) -> bool {
+        matches!(self, Self::Lea | Self::Ivt)
+    }
+
     pub fn is_plugin(&self) -> bool {
         match &self {
             Self::Plugin(_) => true,
@@ -114,6 +179,44 @@ impl Opcode {
             Self::Nop => 27,
             Self::Halt => 28,
             Self::Plugin(_) => 29,
+            Self::Putc => 30,
+            Self::Puts => 31,
+            Self::Getc => 32,
+            Self::Readln => 33,
+            Self::Printx => 34,
+            Self::Lea => 35,
+            Self::Enter => 36,
+            Self::Leave => 37,
+            Self::Swap => 38,
+            Self::Over => 39,
+            Self::Rot => 40,
+            Self::Drop => 41,
+            Self::Pick => 42,
+            Self::Depth => 43,
+            Self::Pushall => 44,
+            Self::Popall => 45,
+            Self::Fadd => 46,
+            Self::Fsub => 47,
+            Self::Fmul => 48,
+            Self::Fdiv => 49,
+            Self::Fsqrt => 50,
+            Self::Fcmp => 51,
+            Self::Itof => 52,
+            Self::Ftoi => 53,
+            Self::Printf => 54,
+            Self::Int => 55,
+            Self::Iret => 56,
+            Self::Cli => 57,
+            Self::Sti => 58,
+            Self::Ivt => 59,
+            Self::Timer => 60,
+            Self::Spawn => 61,
+            Self::Yield => 62,
+            Self::Join => 63,
+            Self::Send => 64,
+            Self::Recv => 65,
+            Self::Lock => 66,
+            Self::Unlock => 67,
             #[allow(unreachable_patterns)]
             _ => 27, // anything else is nop rn
         }
@@ -151,6 +254,44 @@ impl Opcode {
             27 => Opcode::Nop,
             28 => Opcode::Halt,
             29 => Self::Plugin(PluginValue::None),
+            30 => Opcode::Putc,
+            31 => Opcode::Puts,
+            32 => Opcode::Getc,
+            33 => Opcode::Readln,
+            34 => Opcode::Printx,
+            35 => Opcode::Lea,
+            36 => Opcode::Enter,
+            37 => Opcode::Leave,
+            38 => Opcode::Swap,
+            39 => Opcode::Over,
+            40 => Opcode::Rot,
+            41 => Opcode::Drop,
+            42 => Opcode::Pick,
+            43 => Opcode::Depth,
+            44 => Opcode::Pushall,
+            45 => Opcode::Popall,
+            46 => Opcode::Fadd,
+            47 => Opcode::Fsub,
+            48 => Opcode::Fmul,
+            49 => Opcode::Fdiv,
+            50 => Opcode::Fsqrt,
+            51 => Opcode::Fcmp,
+            52 => Opcode::Itof,
+            53 => Opcode::Ftoi,
+            54 => Opcode::Printf,
+            55 => Opcode::Int,
+            56 => Opcode::Iret,
+            57 => Opcode::Cli,
+            58 => Opcode::Sti,
+            59 => Opcode::Ivt,
+            60 => Opcode::Timer,
+            61 => Opcode::Spawn,
+            62 => Opcode::Yield,
+            63 => Opcode::Join,
+            64 => Opcode::Send,
+            65 => Opcode::Recv,
+            66 => Opcode::Lock,
+            67 => Opcode::Unlock,
             _ => Opcode::Nop,
         }
     }
@@ -171,6 +312,36 @@ impl From<String> for Opcode {
             "push" => Self::Push,
             "pop" => Self::Pop,
             "dup" => Self::Dup,
+            "swap" => Self::Swap,
+            "over" => Self::Over,
+            "rot" => Self::Rot,
+            "drop" => Self::Drop,
+            "pick" => Self::Pick,
+            "depth" => Self::Depth,
+            "pushall" => Self::Pushall,
+            "popall" => Self::Popall,
+            "fadd" => Self::Fadd,
+            "fsub" => Self::Fsub,
+            "fmul" => Self::Fmul,
+            "fdiv" => Self::Fdiv,
+            "fsqrt" => Self::Fsqrt,
+            "fcmp" => Self::Fcmp,
+            "itof" => Self::Itof,
+            "ftoi" => Self::Ftoi,
+            "printf" => Self::Printf,
+            "int" => Self::Int,
+            "iret" => Self::Iret,
+            "cli" => Self::Cli,
+            "sti" => Self::Sti,
+            "ivt" => Self::Ivt,
+            "timer" => Self::Timer,
+            "spawn" => Self::Spawn,
+            "yield" => Self::Yield,
+            "join" => Self::Join,
+            "send" => Self::Send,
+            "recv" => Self::Recv,
+            "lock" => Self::Lock,
+            "unlock" => Self::Unlock,
             "test" => Self::Test,
             "jmp" => Self::Jmp,
             "je" => Self::Je,
@@ -183,8 +354,16 @@ impl From<String> for Opcode {
             "jnz" => Self::Jnz,
             "call" => Self::Call,
             "ret" => Self::Return,
+            "lea" => Self::Lea,
+            "enter" => Self::Enter,
+            "leave" => Self::Leave,
             "assert" => Self::Assert,
             "print" => Self::Print,
+            "printx" => Self::Printx,
+            "putc" => Self::Putc,
+            "puts" => Self::Puts,
+            "getc" => Self::Getc,
+            "readln" => Self::Readln,
             "sleep" => Self::Sleep,
             "nop" => Self::Nop,
             "hlt" => Self::Halt,
@@ -208,6 +387,36 @@ impl Display for Opcode {
             Self::Push => write!(f, "push"),
             Self::Pop => write!(f, "pop"),
             Self::Dup => write!(f, "dup"),
+            Self::Swap => write!(f, "swap"),
+            Self::Over => write!(f, "over"),
+            Self::Rot => write!(f, "rot"),
+            Self::Drop => write!(f, "drop"),
+            Self::Pick => write!(f, "pick"),
+            Self::Depth => write!(f, "depth"),
+            Self::Pushall => write!(f, "pushall"),
+            Self::Popall => write!(f, "popall"),
+            Self::Fadd => write!(f, "fadd"),
+            Self::Fsub => write!(f, "fsub"),
+            Self::Fmul => write!(f, "fmul"),
+            Self::Fdiv => write!(f, "fdiv"),
+            Self::Fsqrt => write!(f, "fsqrt"),
+            Self::Fcmp => write!(f, "fcmp"),
+            Self::Itof => write!(f, "itof"),
+            Self::Ftoi => write!(f, "ftoi"),
+            Self::Printf => write!(f, "printf"),
+            Self::Int => write!(f, "int"),
+            Self::Iret => write!(f, "iret"),
+            Self::Cli => write!(f, "cli"),
+            Self::Sti => write!(f, "sti"),
+            Self::Ivt => write!(f, "ivt"),
+            Self::Timer => write!(f, "timer"),
+            Self::Spawn => write!(f, "spawn"),
+            Self::Yield => write!(f, "yield"),
+            Self::Join => write!(f, "join"),
+            Self::Send => write!(f, "send"),
+            Self::Recv => write!(f, "recv"),
+            Self::Lock => write!(f, "lock"),
+            Self::Unlock => write!(f, "unlock"),
             Self::Test => write!(f, "test"),
             Self::Jmp => write!(f, "jmp"),
             Self::Je => write!(f, "je"),
@@ -220,8 +429,16 @@ impl Display for Opcode {
             Self::Jnz => write!(f, "jnz"),
             Self::Call => write!(f, "call"),
             Self::Return => write!(f, "ret"),
+            Self::Lea => write!(f, "lea"),
+            Self::Enter => write!(f, "enter"),
+            Self::Leave => write!(f, "leave"),
             Self::Assert => write!(f, "assert"),
             Self::Print => write!(f, "print"),
+            Self::Printx => write!(f, "printx"),
+            Self::Putc => write!(f, "putc"),
+            Self::Puts => write!(f, "puts"),
+            Self::Getc => write!(f, "getc"),
+            Self::Readln => write!(f, "readln"),
             Self::Sleep => write!(f, "sleep"),
             Self::Nop => write!(f, "nop"),
             Self::Halt => write!(f, "hlt"),
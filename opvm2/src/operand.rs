@@ -1,7 +1,11 @@
 use extism_pdk::{FromBytes, Json, ToBytes};
 use serde::{Deserialize, Serialize};
 
-use crate::{parser::program::LabelValue, register::Register};
+use crate::{
+    expr::Expr,
+    parser::program::LabelValue,
+    register::{Register, Registers},
+};
 
 // operands have different types. for now we only have registers.
 // we will add more types in the future.
@@ -12,15 +16,68 @@ pub enum Operand {
     Register(Register), // todo: this can't just be any number mapped, because what if it's just a regular address? need some way to denote "hey this is a register, not an address."
     Number(usize),
     Label(LabelValue), // todo: this will need to be interpreted as an address at runtime, need to map into memory and store this value later.
-    Offset(Offset),    // this will get confusing, maybe split this up
+    /// The word in memory at a register plus a displacement, e.g. `[bp - 8]`.
+    Offset(Offset),
+    /// An expression using labels, evaluated once they have addresses.
+    Expression(Expr),
+    /// An address relative to the end of the instruction, which branches and `lea` use so that
+    /// the code can be loaded anywhere.
+    Relative(isize),
+    /// A floating point immediate, e.g. `1.5`.
+    Float(f64),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToBytes, FromBytes)]
 #[encoding(Json)]
 pub struct Offset {
-    pub lhs_operand: String,
-    pub operator: Option<String>,
-    pub rhs_operand: Option<String>,
+    pub register: Register,
+    pub displacement: isize,
+}
+
+impl Offset {
+    pub fn new(register: Register, displacement: isize) -> Offset {
+        Offset {
+            register,
+            displacement,
+        }
+    }
+
+    /// Parses the parts of `[register + displacement]` or `[register - displacement]`.
+    pub fn parse(
+        register: String,
+        operator: Option<String>,
+        displacement: Option<String>,
+    ) -> Result<Offset, String> {
+        let register = Register::try_from(register)
+            .map_err(|e| format!("Memory has to be addressed through a register: {}", e))?;
+        let displacement = match (operator.as_deref(), displacement) {
+            (None, None) => 0,
+            (Some(operator), Some(displacement)) => {
+                let value = match Operand::try_from(displacement.clone())? {
+                    Operand::Number(value) => value as isize,
+                    _ => {
+                        return Err(format!(
+                            "Displacement `{}` has to be a constant",
+                            displacement
+                        ))
+                    }
+                };
+                match operator {
+                    "-" => value.wrapping_neg(),
+                    _ => value,
+                }
+            }
+            _ => return Err(format!("`{}` is missing its displacement", register)),
+        };
+        Ok(Offset::new(register, displacement))
+    }
+
+    /// The address the operand refers to.
+    pub fn address(&self, registers: &Registers) -> usize {
+        registers
+            .get(&self.register)
+            .wrapping_add_signed(self.displacement)
+    }
 }
 
 impl TryFrom<String> for Operand {
@@ -37,25 +94,29 @@ impl TryFrom<String> for Operand {
         }
 
         // start trying from.
-        if let Ok(val) = value.parse::<usize>() {
-            return Ok(Operand::Number(val));
-        }
-
         if let Ok(val) = value.clone().try_into() {
             return Ok(Operand::Register(val));
         }
 
-        if let Ok(val) = value.clone().try_into() {
-            return Ok(Operand::Label(LabelValue::Literal(val)));
+        let is_name = value.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if is_name && value.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return Ok(Operand::Label(LabelValue::Literal(value)));
         }
 
-        if value.starts_with("0x") {
-            if let Ok(val) = usize::from_str_radix(value.trim_start_matches("0x"), 16) {
-                return Ok(Operand::Number(val));
+        if value.contains('.') {
+            if let Ok(float) = value.parse::<f64>() {
+                return Ok(Operand::Float(float));
             }
         }
 
-        Err(format!("Invalid operand: {}", value))
+        // anything else has to be a number or a constant expression, e.g. `SIZE * 4` or `'A'`.
+        let expr = Expr::parse(&value)?;
+        if expr.names().is_empty() {
+            let val = expr.evaluate(&|_| None)?;
+            let val = fit(val).ok_or_else(|| out_of_range(&value))?;
+            return Ok(Operand::Number(val as usize));
+        }
+        Ok(Operand::Expression(expr))
     }
 }
 
@@ -67,6 +128,15 @@ impl Operand {
         }
     }
 
+    /// The address the operand refers to, if it is relative to `next`, the end of its
+    /// instruction.
+    pub fn get_relative(&self, next: usize) -> Option<usize> {
+        match self {
+            Operand::Relative(offset) => Some(next.wrapping_add_signed(*offset)),
+            _ => None,
+        }
+    }
+
     pub fn get_number(&self) -> Result<usize, String> {
         match self {
             Operand::Number(number) => Ok(*number),
@@ -81,33 +151,60 @@ impl Operand {
             Operand::Number(_) => 2,
             Operand::Label(_) => 3,
             Operand::Offset(_) => 4,
+            // evaluates to a number.
+            Operand::Expression(_) => 2,
+            Operand::Relative(_) => 5,
+            Operand::Float(_) => 6,
         }
     }
 
-    pub fn encode(&self, literal_map: &std::collections::BTreeMap<String, usize>) -> usize {
-        match self {
-            Operand::Register(register) => register.encode() as usize,
-            Operand::Number(number) => *number as usize,
+    /// Encodes the operand into 64 bits, failing if the value doesn't fit.
+    pub fn encode(
+        &self,
+        literal_map: &std::collections::BTreeMap<String, usize>,
+    ) -> Result<u64, String> {
+        let value = match self {
+            Operand::Register(register) => return Ok(register.encode() as u64),
+            // zigzag encoded, so that jumping back a few bytes doesn't take 10 bytes.
+            Operand::Relative(offset) => return Ok(zigzag(*offset)),
+            // the bytes are swapped, so that the zeroes at the end of round numbers like `1.5`
+            // aren't stored.
+            Operand::Float(float) => return Ok(float.to_bits().swap_bytes()),
+            // the register in the low byte, and the displacement above it.
+            Operand::Offset(offset) => {
+                return Ok(zigzag(offset.displacement) << 8 | offset.register.encode() as u64)
+            }
+            Operand::Number(number) => *number as i128,
             Operand::Label(label) => match label {
                 LabelValue::Literal(literal) => {
-                    if let Some(res) = literal_map.get(literal) {
-                        return *res;
-                    }
-                    0usize
+                    literal_map.get(literal).copied().unwrap_or(0) as i128
                 }
-                LabelValue::Address(address) => *address,
+                LabelValue::Address(address) => *address as i128,
+                // operands refer to data by name, which is resolved through the map.
+                LabelValue::Data { .. } => 0,
+                LabelValue::Constant(value) => *value as i128,
             },
-            _ => 0usize,
-        }
+            Operand::Expression(expr) => {
+                expr.evaluate(&|name| literal_map.get(name).map(|v| *v as i128))?
+            }
+            _ => 0,
+        };
+        fit(value).ok_or_else(|| out_of_range(value))
     }
 
-    pub fn decode(operand_type: u8, operand: u32) -> Operand {
-        match operand_type {
-            1 => Operand::Register(Register::decode(operand)),
+    pub fn decode(operand_type: u8, operand: u64) -> Result<Operand, String> {
+        Ok(match operand_type {
+            1 => Operand::Register(Register::decode(operand as u32)?),
             2 => Operand::Number(operand as usize),
             3 => Operand::Label(LabelValue::Address(operand as usize)),
+            4 => Operand::Offset(Offset::new(
+                Register::decode(operand as u32 & 0xFF)?,
+                unzigzag(operand >> 8),
+            )),
+            5 => Operand::Relative(unzigzag(operand)),
+            6 => Operand::Float(f64::from_bits(operand.swap_bytes())),
             _ => Operand::None,
-        }
+        })
     }
 
     /// Returns `true` if the operand is [`Offset`].
@@ -118,3 +215,24 @@ impl Operand {
         matches!(self, Self::Offset(..))
     }
 }
+
+// zigzag encoded, so that small negative values like jumping back a few bytes don't take 10 bytes.
+fn zigzag(value: isize) -> u64 {
+    ((value as i64) << 1 ^ (value as i64) >> 63) as u64
+}
+
+fn unzigzag(value: u64) -> isize {
+    ((value >> 1) as i64 ^ -((value & 1) as i64)) as isize
+}
+
+// negative values are stored as two's complement.
+fn fit(value: i128) -> Option<u64> {
+    match value {
+        v if v < 0 => i64::try_from(v).ok().map(|v| v as u64),
+        v => u64::try_from(v).ok(),
+    }
+}
+
+fn out_of_range(value: impl std::fmt::Display) -> String {
+    format!("Value `{}` doesn't fit in a 64-bit operand", value)
+}
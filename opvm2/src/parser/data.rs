@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use crate::{expr::Expr, lexer::token::DataDefinition};
+
+/// The size in bytes of a unit of `db`, `dw`, `dd` and `dq`.
+pub fn unit_size(directive: &str) -> Option<usize> {
+    match directive {
+        "db" => Some(1),
+        "dw" => Some(2),
+        "dd" => Some(4),
+        "dq" => Some(8),
+        _ => None,
+    }
+}
+
+/// Turns a data definition into the bytes it places in memory, little endian.
+///
+/// Numbers must fit in a unit (negative numbers are stored as two's complement),
+/// strings are stored as bytes and padded with zeroes to a whole number of units.
+pub fn encode(
+    definition: &DataDefinition,
+    constants: &HashMap<String, usize>,
+) -> Result<Vec<u8>, String> {
+    let directive = definition.directive.as_str();
+    let size =
+        unit_size(directive).ok_or_else(|| format!("Unknown data directive `{}`", directive))?;
+    let mut bytes = vec![];
+    for value in definition.values.iter() {
+        if let Some(string) = strip_quotes(value) {
+            let mut string = unescape(string)?;
+            string.resize(string.len().div_ceil(size) * size, 0);
+            bytes.extend(string);
+            continue;
+        }
+        let number = value_of(value, constants)
+            .ok_or_else(|| format!("Invalid value `{}` for `{}`", value, directive))?;
+        let bits = size as u32 * 8;
+        if number < -(1i128 << (bits - 1)) || number >= 1i128 << bits {
+            return Err(format!("Value `{}` doesn't fit in `{}`", value, directive));
+        }
+        bytes.extend_from_slice(&number.to_le_bytes()[..size]);
+    }
+
+    let times = match &definition.times {
+        Some(times) => value_of(times, constants)
+            .and_then(|t| usize::try_from(t).ok())
+            .ok_or_else(|| format!("Invalid count `{}` for `times`", times))?,
+        None => 1,
+    };
+    Ok(bytes.repeat(times))
+}
+
+/// Replaces the escape sequences of a string: `\n`, `\r`, `\t`, `\0`, `\\`, `\'`, `\"`
+/// and `\xHH`.
+pub fn unescape(i: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(i.len());
+    let mut chars = i.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('\'') => b'\'',
+            Some('"') => b'"',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .ok_or_else(|| format!("Invalid escape sequence `\\x{}`", hex))?
+            }
+            Some(c) => return Err(format!("Unknown escape sequence `\\{}`", c)),
+            None => return Err("Unfinished escape sequence at the end of a string".to_string()),
+        };
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+fn strip_quotes(value: &str) -> Option<&str> {
+    ['\'', '"'].into_iter().find_map(|quote| {
+        value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+    })
+}
+
+/// Evaluates a constant expression, which can use the constants defined with `equ` so far.
+pub fn value_of(value: &str, constants: &HashMap<String, usize>) -> Option<i128> {
+    Expr::parse(value)
+        .and_then(|expr| expr.evaluate(&|name| constants.get(name).map(|c| *c as i128)))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{encode, unescape};
+    use crate::lexer::token::DataDefinition;
+
+    fn definition(directive: &str, times: Option<&str>, values: &[&str]) -> DataDefinition {
+        DataDefinition {
+            directive: directive.to_string(),
+            times: times.map(|t| t.to_string()),
+            values: values.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn can_encode_numbers() {
+        let constants = HashMap::from([("SIZE".to_string(), 3)]);
+        assert_eq!(
+            encode(
+                &definition("db", None, &["1", "0xff", "-1", "SIZE"]),
+                &constants
+            ),
+            Ok(vec![1, 0xff, 0xff, 3])
+        );
+        assert_eq!(
+            encode(&definition("dw", None, &["0x1234"]), &constants),
+            Ok(vec![0x34, 0x12])
+        );
+        assert_eq!(
+            encode(&definition("dq", None, &["-2"]), &constants),
+            Ok(vec![0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
+        );
+        assert_eq!(
+            encode(&definition("db", None, &["256"]), &constants),
+            Err("Value `256` doesn't fit in `db`".to_string())
+        );
+        assert_eq!(
+            encode(&definition("dd", None, &["label"]), &constants),
+            Err("Invalid value `label` for `dd`".to_string())
+        );
+    }
+
+    #[test]
+    fn can_encode_strings_and_repeat() {
+        let constants = HashMap::from([("COUNT".to_string(), 2)]);
+        assert_eq!(
+            encode(&definition("db", None, &["'hi\\n'", "0"]), &constants),
+            Ok(b"hi\n\0".to_vec())
+        );
+        assert_eq!(
+            encode(&definition("dw", None, &["\"abc\""]), &constants),
+            Ok(b"abc\0".to_vec())
+        );
+        assert_eq!(
+            encode(&definition("db", Some("COUNT"), &["1", "2"]), &constants),
+            Ok(vec![1, 2, 1, 2])
+        );
+        assert_eq!(
+            encode(&definition("db", Some("-1"), &["1"]), &constants),
+            Err("Invalid count `-1` for `times`".to_string())
+        );
+    }
+
+    #[test]
+    fn can_unescape_strings() {
+        assert_eq!(unescape(r"a\tb\0\\\'\x41"), Ok(b"a\tb\0\\'A".to_vec()));
+        assert_eq!(
+            unescape(r"\q"),
+            Err("Unknown escape sequence `\\q`".to_string())
+        );
+        assert_eq!(
+            unescape(r"\x4"),
+            Err("Invalid escape sequence `\\x4`".to_string())
+        );
+    }
+}
@@ -1,3 +1,5 @@
 // the idea of the parser is to turn the lexed input into a program that can be evaluated
 
+pub mod data;
 pub mod program;
+pub mod section;
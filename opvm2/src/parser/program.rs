@@ -1,19 +1,125 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
 use extism_pdk::{FromBytes, Json, ToBytes};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    expr,
     instruction::Instruction,
     lexer::token::{SideType, Token},
+    opcode::Opcode,
     operand::{Offset, Operand},
+    preprocessor::{Line, Preprocessor},
+};
+
+use super::{
+    data,
+    section::{Section, Sections},
 };
 
+// bytes reserved per unit by `resb`, `resw`, `resd` and `resq`.
+fn reservation_size(directive: &str) -> Option<usize> {
+    match directive {
+        "resb" => Some(1),
+        "resw" => Some(2),
+        "resd" => Some(4),
+        "resq" => Some(8),
+        _ => None,
+    }
+}
+
+// where data defined in `section` is placed: .text only holds instructions, so its data goes
+// to .data.
+fn data_section(section: Section) -> Section {
+    match section {
+        Section::Text => Section::Data,
+        section => section,
+    }
+}
+
+// an operand as it was written, for errors.
+fn side_text(side: &SideType) -> String {
+    match side {
+        SideType::None => String::new(),
+        SideType::Normal(text) => text.clone(),
+        SideType::Offset(offset) => format!(
+            "[{}{}{}]",
+            offset.lhs,
+            offset.operator.as_deref().unwrap_or_default(),
+            offset.rhs.as_deref().unwrap_or_default()
+        ),
+    }
+}
+
+// what an operand of a float instruction has to be.
+enum Kind {
+    FloatRegister,
+    IntegerRegister,
+    /// A float register or immediate. Integer immediates are converted, and memory holds
+    /// whatever was stored there.
+    Float,
+    /// Anything but a float register or immediate.
+    Integer,
+}
+
+impl Kind {
+    // the operand to encode, or `None` if it's not of this kind. `constants` are the `equ`
+    // constants defined so far, which float operands can use like numbers.
+    fn check(&self, operand: Operand, constants: &HashMap<String, usize>) -> Option<Operand> {
+        let valid = match (self, &operand) {
+            (_, Operand::None) => true,
+            (Kind::FloatRegister, Operand::Register(r)) => r.is_float(),
+            (Kind::FloatRegister, _) => false,
+            (Kind::IntegerRegister, Operand::Register(r)) => !r.is_float(),
+            (Kind::IntegerRegister, _) => false,
+            (Kind::Float, Operand::Number(number)) => {
+                return Some(Operand::Float(*number as isize as f64))
+            }
+            (Kind::Float, Operand::Label(LabelValue::Literal(name))) => {
+                return constants
+                    .get(name)
+                    .map(|value| Operand::Float(*value as f64))
+            }
+            (Kind::Float, Operand::Expression(expr)) => {
+                return expr
+                    .evaluate(&|name| constants.get(name).map(|value| *value as i128))
+                    .ok()
+                    .map(|value| Operand::Float(value as f64))
+            }
+            (Kind::Float, Operand::Register(r)) => r.is_float(),
+            (Kind::Float, _) => true,
+            (Kind::Integer, Operand::Register(r)) => !r.is_float(),
+            (Kind::Integer, Operand::Float(_)) => false,
+            (Kind::Integer, _) => true,
+        };
+        valid.then_some(operand)
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Kind::FloatRegister => "a float register (f0 to f7)",
+            Kind::IntegerRegister => "an integer register",
+            Kind::Float => "a float register or a number",
+            Kind::Integer => "an integer",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, FromBytes, ToBytes, Clone)]
 #[encoding(Json)]
 pub enum LabelValue {
     Address(usize),
     Literal(String),
+    /// An offset into one of the data sections, relocated when the program is compiled.
+    Data {
+        section: Section,
+        offset: usize,
+    },
+    /// A value defined with `equ`, used as is.
+    Constant(usize),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, FromBytes, ToBytes, Clone)]
@@ -44,17 +150,42 @@ pub struct Program {
     pub instructions: Vec<Instruction>,
     pub labels: Labels,
     pub plugins: Vec<Vec<u8>>,
+    pub sections: Sections,
+    /// Labels the source declares `global`, which an object compiled from it exports.
+    #[serde(default)]
+    pub globals: Vec<String>,
 }
 
 impl Program {
     pub fn from(input: &str) -> Self {
-        let tokens = crate::lexer::lex_input(input);
-        match tokens {
-            Ok(tokens) => Self::new(tokens),
+        match Self::parse(input) {
+            Ok(program) => program,
             Err(e) => panic!("Error: {}", e),
         }
     }
 
+    /// Preprocesses, lexes and parses the input, returning the first stage's errors.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut preprocessor = Preprocessor::new();
+        let mut program = Self::from_lines(preprocessor.run(input)?)?;
+        program.globals = preprocessor.globals;
+        Ok(program)
+    }
+
+    /// Like [`Program::parse`], but `%include`s are also looked up next to the file.
+    pub fn parse_file(path: &Path, include_paths: &[PathBuf]) -> Result<Self, String> {
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.include_paths = include_paths.to_vec();
+        let mut program = Self::from_lines(preprocessor.run_file(path)?)?;
+        program.globals = preprocessor.globals;
+        Ok(program)
+    }
+
+    fn from_lines(lines: Vec<Line>) -> Result<Self, String> {
+        let tokens = crate::lexer::lex_lines(&lines)?;
+        Self::tokens_to_program(tokens)
+    }
+
     pub fn new(tokens: Vec<Vec<Token>>) -> Self {
         let result = Self::tokens_to_program(tokens);
         match result {
@@ -69,32 +200,101 @@ impl Program {
         let mut labels: Labels = Labels {
             list: Default::default(),
         };
-        //let mut directives = Vec::new();
+        let mut section = Section::Text;
+        let mut sections = Sections::default();
+        // constants defined so far, which data definitions can use.
+        let mut constants: HashMap<String, usize> = HashMap::new();
         for token_list in tokens {
+            // data defined in .text goes to .data, along with the label of its line.
+            let has_data = token_list.iter().any(|t| matches!(t, Token::Data(_)));
             for token in token_list {
                 match token {
                     Token::Label(l) => {
                         // mark the location at which the label is located at, use instructions.len()
+                        let value = match section {
+                            Section::Text if !has_data => LabelValue::Address(instructions.len()),
+                            _ => {
+                                let section = data_section(section);
+                                LabelValue::Data {
+                                    section,
+                                    offset: sections.size(section),
+                                }
+                            }
+                        };
+                        labels.list.insert(l, value);
+                    }
+                    Token::LabelWithLiteral(l) => {
+                        // a number is stored like `dq`, a string as is with a null terminator.
+                        let bytes = if l.value.starts_with(|c: char| c.is_ascii_digit()) {
+                            let val = expr::parse_number(&l.value)?;
+                            let val = u64::try_from(val)
+                                .map_err(|_| format!("Number `{}` is too large", l.value))?;
+                            val.to_le_bytes().to_vec()
+                        } else {
+                            let mut bytes = data::unescape(&l.value)?;
+                            bytes.push(0);
+                            bytes
+                        };
+                        let section = data_section(section);
+                        let offset = sections.push(section, &bytes)?;
                         labels
                             .list
-                            .insert(l, LabelValue::Address(instructions.len()));
+                            .insert(l.name, LabelValue::Data { section, offset });
                     }
-                    Token::LabelWithLiteral(l) => {
-                        // see if we can parse the l.value as a number
-                        if let Ok(val) = l.value.parse::<usize>() {
-                            labels.list.insert(l.name, LabelValue::Address(val));
-                            continue;
+                    Token::Directive(d) => section = d.as_str().try_into()?,
+                    Token::Data(d) => {
+                        if section == Section::Bss {
+                            return Err(format!(
+                                "`{}` is only allowed in {} or {}, not {}",
+                                d.directive,
+                                Section::Rodata,
+                                Section::Data,
+                                section
+                            ));
                         }
-
-                        // todo, get rid of this "literal" space and make it an address always after mapping.
-                        labels.list.insert(l.name, LabelValue::Literal(l.value));
+                        sections.push(data_section(section), &data::encode(&d, &constants)?)?;
+                    }
+                    Token::Constant(c) => {
+                        let value = data::value_of(&c.value, &constants)
+                            .and_then(|value| usize::try_from(value).ok())
+                            .ok_or_else(|| {
+                                format!("Invalid value `{}` for `{}`", c.value, c.name)
+                            })?;
+                        constants.insert(c.name.clone(), value);
+                        labels.list.insert(c.name, LabelValue::Constant(value));
                     }
-                    Token::Directive(_) => todo!(),
                     Token::Expression(e) => {
+                        if let Some(size) = reservation_size(&e.opcode) {
+                            if section != Section::Bss {
+                                return Err(format!(
+                                    "`{}` is only allowed in {}, not {}",
+                                    e.opcode,
+                                    Section::Bss,
+                                    section
+                                ));
+                            }
+                            let count = Self::parse_side_type(e.lhs)?.get_number()?;
+                            sections.bss += size * count;
+                            continue;
+                        }
+                        if section != Section::Text {
+                            return Err(format!(
+                                "Instruction `{}` is in {}, instructions belong in {}",
+                                e.opcode,
+                                section,
+                                Section::Text
+                            ));
+                        }
+                        let (lhs_text, rhs_text) = (side_text(&e.lhs), side_text(&e.rhs));
                         let lhs = Self::parse_side_type(e.lhs)?;
                         let rhs = Self::parse_side_type(e.rhs)?;
                         let instruction = Instruction::new(e.opcode.into(), lhs, rhs);
-                        instructions.push(instruction);
+                        instructions.push(Self::check_float_operands(
+                            instruction,
+                            &lhs_text,
+                            &rhs_text,
+                            &constants,
+                        )?);
                     } // will require multiple passes if the labels are not defined in order (above the expression it's used in.)
                     _ => (),
                 }
@@ -104,26 +304,61 @@ impl Program {
             instructions,
             labels,
             plugins: vec![],
+            sections,
+            globals: vec![],
         })
     }
 
     fn parse_side_type(s: SideType) -> Result<Operand, String> {
         Ok(match s {
             SideType::Normal(rhs) => rhs.try_into()?,
-            SideType::Offset(offset) => Operand::Offset(Offset {
-                lhs_operand: offset.lhs,
-                operator: offset.operator,
-                rhs_operand: offset.rhs,
-            }),
+            SideType::Offset(offset) => {
+                Operand::Offset(Offset::parse(offset.lhs, offset.operator, offset.rhs)?)
+            }
             SideType::None => Operand::None,
         })
     }
 
+    // float instructions read their operands as floats, so integer immediates are converted and
+    // registers have to be of the right kind.
+    fn check_float_operands(
+        mut instruction: Instruction,
+        lhs_text: &str,
+        rhs_text: &str,
+        constants: &HashMap<String, usize>,
+    ) -> Result<Instruction, String> {
+        let (lhs, rhs) = match instruction.opcode {
+            Opcode::Fadd
+            | Opcode::Fsub
+            | Opcode::Fmul
+            | Opcode::Fdiv
+            | Opcode::Fsqrt
+            | Opcode::Fcmp => (Kind::FloatRegister, Kind::Float),
+            Opcode::Itof => (Kind::FloatRegister, Kind::Integer),
+            Opcode::Ftoi => (Kind::IntegerRegister, Kind::Float),
+            Opcode::Printf => (Kind::Float, Kind::Integer),
+            _ => return Ok(instruction),
+        };
+        let opcode = instruction.opcode.clone();
+        let error = |kind: &Kind, text: &str| {
+            format!("`{}` takes {}, not `{}`", opcode, kind.describe(), text)
+        };
+        instruction.lhs = lhs
+            .check(instruction.lhs, constants)
+            .ok_or_else(|| error(&lhs, lhs_text))?;
+        instruction.rhs = rhs
+            .check(instruction.rhs, constants)
+            .ok_or_else(|| error(&rhs, rhs_text))?;
+        Ok(instruction)
+    }
+
     pub fn empty() -> Program {
         Program {
             instructions: vec![],
             labels: Labels::new(),
             plugins: vec![],
+            sections: Sections::default(),
+            globals: vec![],
         }
     }
 }
@@ -166,7 +401,9 @@ mod test {
                     ),
                 ],
                 labels: Labels::new(),
-                plugins: vec![]
+                plugins: vec![],
+                sections: Sections::default(),
+                globals: vec![]
             }
         )
     }
@@ -205,7 +442,9 @@ mod test {
                     ("start".to_string(), LabelValue::Address(0)),
                     ("end".to_string(), LabelValue::Address(2))
                 ]),
-                plugins: vec![]
+                plugins: vec![],
+                sections: Sections::default(),
+                globals: vec![]
             }
         )
     }
@@ -248,14 +487,28 @@ mod test {
                     ),
                 ],
                 labels: Labels::from(vec![
-                    ("custom".to_string(), LabelValue::Address(10)),
+                    (
+                        "custom".to_string(),
+                        LabelValue::Data {
+                            section: Section::Data,
+                            offset: 0
+                        }
+                    ),
                     (
                         "custom_str".to_string(),
-                        LabelValue::Literal("this is my custom value".to_string())
+                        LabelValue::Data {
+                            section: Section::Data,
+                            offset: 8
+                        }
                     ),
                     ("end".to_string(), LabelValue::Address(2))
                 ]),
-                plugins: vec![]
+                plugins: vec![],
+                sections: Sections {
+                    data: [&10u64.to_le_bytes()[..], b"this is my custom value\0"].concat(),
+                    ..Sections::default()
+                },
+                globals: vec![]
             }
         )
     }
@@ -276,14 +529,12 @@ mod test {
                 instructions: vec![Instruction::new(
                     Opcode::Mov,
                     Operand::Register(Register::Ra),
-                    Operand::Offset(Offset {
-                        lhs_operand: "rb".to_string(),
-                        operator: Some("+".to_string()),
-                        rhs_operand: Some("1".to_string()),
-                    })
+                    Operand::Offset(Offset::new(Register::Rb, 1))
                 ),],
                 labels: Labels::new(),
-                plugins: vec![]
+                plugins: vec![],
+                sections: Sections::default(),
+                globals: vec![]
             }
         );
 
@@ -301,15 +552,241 @@ mod test {
                 instructions: vec![Instruction::new(
                     Opcode::Mov,
                     Operand::Register(Register::Ra),
-                    Operand::Offset(Offset {
-                        lhs_operand: "rb".to_string(),
-                        operator: None,
-                        rhs_operand: None,
-                    })
+                    Operand::Offset(Offset::new(Register::Rb, 0))
                 ),],
                 labels: Labels::new(),
-                plugins: vec![]
+                plugins: vec![],
+                sections: Sections::default(),
+                globals: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn can_parse_float_immediates() -> Result<(), String> {
+        let program = Program::parse("fadd f0, -1.5\nmov f7, 2.5e3\nputc '.'")?;
+        assert_eq!(
+            program.instructions,
+            vec![
+                Instruction::new(
+                    Opcode::Fadd,
+                    Operand::Register(Register::F0),
+                    Operand::Float(-1.5),
+                ),
+                Instruction::new(
+                    Opcode::Mov,
+                    Operand::Register(Register::F7),
+                    Operand::Float(2500.0),
+                ),
+                Instruction::new_l(Opcode::Putc, Operand::Number('.' as usize)),
+            ]
+        );
+        // integer immediates and constants of float operands are converted.
+        let program = Program::parse(
+            "TWO equ 2\nfadd f0, 2\nfcmp f1, -3\nprintf 1, 2\nfadd f0, TWO\nfmul f0, TWO * 2",
+        )?;
+        assert_eq!(program.instructions[0].rhs, Operand::Float(2.0));
+        assert_eq!(program.instructions[1].rhs, Operand::Float(-3.0));
+        assert_eq!(program.instructions[2].lhs, Operand::Float(1.0));
+        assert_eq!(program.instructions[2].rhs, Operand::Number(2));
+        assert_eq!(program.instructions[3].rhs, Operand::Float(2.0));
+        assert_eq!(program.instructions[4].rhs, Operand::Float(4.0));
+        Ok(())
+    }
+
+    #[test]
+    fn can_report_float_operand_errors() {
+        assert_eq!(
+            Program::parse("fadd ra, f0"),
+            Err("`fadd` takes a float register (f0 to f7), not `ra`".to_string())
+        );
+        assert_eq!(
+            Program::parse("fmul f0, rb"),
+            Err("`fmul` takes a float register or a number, not `rb`".to_string())
+        );
+        assert_eq!(
+            Program::parse("itof ra, rb"),
+            Err("`itof` takes a float register (f0 to f7), not `ra`".to_string())
+        );
+        assert_eq!(
+            Program::parse("itof f0, f1"),
+            Err("`itof` takes an integer, not `f1`".to_string())
+        );
+        assert_eq!(
+            Program::parse("ftoi f0, f1"),
+            Err("`ftoi` takes an integer register, not `f0`".to_string())
+        );
+        assert_eq!(
+            Program::parse("fadd f0, message\nmessage: 'hi'"),
+            Err("`fadd` takes a float register or a number, not `message`".to_string())
+        );
+        assert_eq!(
+            Program::parse("printf ra, 2"),
+            Err("`printf` takes a float register or a number, not `ra`".to_string())
+        );
+    }
+
+    #[test]
+    fn can_parse_stack_addressing() -> Result<(), String> {
+        let program = Program::parse("mov [bp-8], ra\nmov rb, [sp + 0x10]")?;
+        assert_eq!(
+            program.instructions,
+            vec![
+                Instruction::new(
+                    Opcode::Mov,
+                    Operand::Offset(Offset::new(Register::Bp, -8)),
+                    Operand::Register(Register::Ra),
+                ),
+                Instruction::new(
+                    Opcode::Mov,
+                    Operand::Register(Register::Rb),
+                    Operand::Offset(Offset::new(Register::Sp, 16)),
+                ),
+            ]
+        );
+        assert_eq!(
+            Program::parse("mov ra, [rb - SIZE]"),
+            Err("Displacement `SIZE` has to be a constant".to_string())
+        );
+        assert_eq!(
+            Program::parse("mov ra, [buffer]"),
+            Err(
+                "Memory has to be addressed through a register: \"buffer\" is not a valid register"
+                    .to_string()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_sections() -> Result<(), String> {
+        let program = Program::parse(
+            r"
+            section .data
+            greeting: 'hi'
+            section .bss
+            buffer: resb 10
+            words: resw 3
+            section .rodata
+            name: 'opvm'
+            section .text
+            start: puts greeting
+        ",
+        )?;
+        assert_eq!(
+            program.sections,
+            Sections {
+                rodata: b"opvm\0".to_vec(),
+                data: b"hi\0".to_vec(),
+                bss: 16,
+            }
+        );
+        assert_eq!(program.instructions.len(), 1);
+        assert_eq!(
+            program.labels,
+            Labels::from(vec![
+                (
+                    "greeting".to_string(),
+                    LabelValue::Data {
+                        section: Section::Data,
+                        offset: 0
+                    }
+                ),
+                (
+                    "buffer".to_string(),
+                    LabelValue::Data {
+                        section: Section::Bss,
+                        offset: 0
+                    }
+                ),
+                (
+                    "words".to_string(),
+                    LabelValue::Data {
+                        section: Section::Bss,
+                        offset: 10
+                    }
+                ),
+                (
+                    "name".to_string(),
+                    LabelValue::Data {
+                        section: Section::Rodata,
+                        offset: 0
+                    }
+                ),
+                ("start".to_string(), LabelValue::Address(0)),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_reject_misplaced_section_contents() {
+        assert_eq!(
+            Program::parse("section .data\nmov ra, 1"),
+            Err("Instruction `mov` is in .data, instructions belong in .text".to_string())
+        );
+        assert_eq!(
+            Program::parse("buffer: resb 4"),
+            Err("`resb` is only allowed in .bss, not .text".to_string())
+        );
+        assert_eq!(
+            Program::parse("section .bss\nname: 'opvm'"),
+            Err(".bss can't hold initialized data".to_string())
+        );
+        assert_eq!(
+            Program::parse("section .heap"),
+            Err("Unknown section `.heap`".to_string())
+        );
+    }
+
+    #[test]
+    fn can_parse_data_definitions() -> Result<(), String> {
+        let program = Program::parse(
+            r"
+            COUNT equ 3
+            section .data
+            table: dw 1, 2
+            buffer: times COUNT db 0xff
+            message: db 'hi\n', 0
+            section .text
+            mov ra, COUNT
+        ",
+        )?;
+        assert_eq!(
+            program.sections.data,
+            vec![1, 0, 2, 0, 0xff, 0xff, 0xff, b'h', b'i', b'\n', 0]
+        );
+        assert_eq!(program.labels.list["COUNT"], LabelValue::Constant(3));
+        assert_eq!(
+            program.labels.list["message"],
+            LabelValue::Data {
+                section: Section::Data,
+                offset: 7
             }
         );
+        // outside of the data sections, data goes to .data.
+        let program = Program::parse("table: dq 1, 2, 3, 5, 8\nbuf: times 64 db 0\nmov ra, 1")?;
+        assert_eq!(program.sections.data.len(), 5 * 8 + 64);
+        assert_eq!(
+            program.sections.data[..16],
+            [1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            program.labels.list["buf"],
+            LabelValue::Data {
+                section: Section::Data,
+                offset: 40
+            }
+        );
+        assert_eq!(program.instructions.len(), 1);
+        assert_eq!(
+            Program::parse("section .bss\ntable: dq 1"),
+            Err("`dq` is only allowed in .rodata or .data, not .bss".to_string())
+        );
+        assert_eq!(
+            Program::parse("SIZE equ ra"),
+            Err("Invalid value `ra` for `SIZE`".to_string())
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub enum Section {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+}
+
+impl Section {
+    /// The sections holding data, in the order they are laid out in memory.
+    pub const DATA_SECTIONS: [Section; 3] = [Section::Rodata, Section::Data, Section::Bss];
+}
+
+impl TryFrom<&str> for Section {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "text" => Ok(Section::Text),
+            "rodata" => Ok(Section::Rodata),
+            "data" => Ok(Section::Data),
+            "bss" => Ok(Section::Bss),
+            _ => Err(format!("Unknown section `.{}`", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Section::Text => "text",
+            Section::Rodata => "rodata",
+            Section::Data => "data",
+            Section::Bss => "bss",
+        };
+        write!(f, ".{}", name)
+    }
+}
+
+/// The contents of the data sections, the code of `.text` is kept as instructions.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Sections {
+    pub rodata: Vec<u8>,
+    pub data: Vec<u8>,
+    /// `.bss` is zeroed, so only its size is kept.
+    pub bss: usize,
+}
+
+impl Sections {
+    pub fn size(&self, section: Section) -> usize {
+        match section {
+            Section::Text => 0,
+            Section::Rodata => self.rodata.len(),
+            Section::Data => self.data.len(),
+            Section::Bss => self.bss,
+        }
+    }
+
+    /// Appends initialized data, returning its offset in the section.
+    pub fn push(&mut self, section: Section, bytes: &[u8]) -> Result<usize, String> {
+        let offset = self.size(section);
+        match section {
+            Section::Rodata => self.rodata.extend_from_slice(bytes),
+            Section::Data => self.data.extend_from_slice(bytes),
+            _ => return Err(format!("{} can't hold initialized data", section)),
+        }
+        Ok(offset)
+    }
+}
@@ -8,6 +8,38 @@ use crate::{
 use extism_pdk::*;
 use serde::{Deserialize, Serialize};
 
+/// The custom section [`plugin_name!`] keeps the name of a plugin in, so that it can be found
+/// without loading it.
+pub const NAME_SECTION: &str = "opvm2_name";
+
+/// Exports the `name` function every plugin has, returning `$name`, and keeps the name in the
+/// [`NAME_SECTION`] of the module as well.
+#[macro_export]
+macro_rules! plugin_name {
+    ($name:literal) => {
+        // the section name has to be a literal, it's `NAME_SECTION`.
+        #[link_section = "opvm2_name"]
+        #[used]
+        static PLUGIN_NAME: [u8; $name.len()] = $crate::plugin_interface::name_bytes($name);
+
+        #[extism_pdk::plugin_fn]
+        pub fn name() -> extism_pdk::FnResult<String> {
+            Ok($name.to_string())
+        }
+    };
+}
+
+/// The bytes of `name` as an array, for [`plugin_name!`].
+pub const fn name_bytes<const N: usize>(name: &str) -> [u8; N] {
+    let mut bytes = [0; N];
+    let mut index = 0;
+    while index < N {
+        bytes[index] = name.as_bytes()[index];
+        index += 1;
+    }
+    bytes
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, FromBytes, ToBytes, Clone)]
 #[encoding(Json)]
 pub struct OnInstructionValue {
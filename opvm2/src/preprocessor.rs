@@ -0,0 +1,760 @@
+// the preprocessor runs on the source text before it is lexed, expanding `%define`
+// constants and `%macro` definitions into plain instructions.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+// guards against macros that (indirectly) invoke themselves.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// Where a line of preprocessed source came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// `None` for source that wasn't read from a file.
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}: Line {}", file, self.line),
+            None => write!(f, "Line {}", self.line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub text: String,
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Preprocessor {
+    /// Directories searched for `%include`d files, after the directory of the including file.
+    pub include_paths: Vec<PathBuf>,
+    defines: HashMap<String, String>,
+    macros: HashMap<String, Macro>,
+    // counts expansions so that local labels are unique per expansion.
+    expansions: usize,
+    // the files being included, outermost first, to detect cycles.
+    including: Vec<(PathBuf, String)>,
+    // counts included files so that their private labels are unique per file.
+    files: usize,
+    /// The labels the root source declares `global`.
+    pub globals: Vec<String>,
+}
+
+pub fn preprocess(input: &str) -> Result<Vec<Line>, String> {
+    Preprocessor::new().run(input)
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Preprocesses source that wasn't read from a file, includes are relative to the
+    /// current directory.
+    pub fn run(&mut self, input: &str) -> Result<Vec<Line>, String> {
+        self.run_source(input, None, true)
+    }
+
+    pub fn run_file(&mut self, path: &Path) -> Result<Vec<Line>, String> {
+        let name = path.display().to_string();
+        let input = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", name, e))?;
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| format!("{}: {}", name, e))?;
+        self.including.push((canonical, name));
+        let result = self.run_source(&input, Some(path), true);
+        self.including.pop();
+        result
+    }
+
+    // labels of the root source are visible everywhere, labels of included files are
+    // private to the file unless they are declared `global`.
+    fn run_source(
+        &mut self,
+        input: &str,
+        path: Option<&Path>,
+        root: bool,
+    ) -> Result<Vec<Line>, String> {
+        let file = path.map(|p| p.display().to_string());
+        // every line, along with whether it comes from this file rather than an include.
+        let mut output: Vec<(Line, bool)> = vec![];
+        let mut errors: Vec<String> = vec![];
+        let mut globals: Vec<String> = vec![];
+        // the macro currently being defined, along with where it started.
+        let mut definition: Option<(String, Macro, Location)> = None;
+        for (line_num, text) in input.lines().enumerate() {
+            let location = Location {
+                file: file.clone(),
+                line: line_num,
+            };
+            let (directive, rest) = split_directive(text);
+            if let Some((name, mac, _)) = definition.as_mut() {
+                match directive {
+                    Some("%endmacro") => {
+                        let name = name.clone();
+                        let (_, mac, _) = definition.take().unwrap();
+                        self.macros.insert(name, mac);
+                    }
+                    Some("%macro") => errors.push(format!(
+                        "{}: Macro `{}` can't be defined inside macro `{}`",
+                        location, rest, name
+                    )),
+                    _ => mac.body.push(text.to_string()),
+                }
+                continue;
+            }
+
+            let result = match directive {
+                Some("%define") => self.define(rest).map(|_| vec![]),
+                Some("%macro") => self.start_macro(rest).map(|(name, mac)| {
+                    definition = Some((name, mac, location.clone()));
+                    vec![]
+                }),
+                Some("%endmacro") => Err("`%endmacro` without `%macro`".to_string()),
+                Some("%include") => {
+                    // errors of the included file already say where they are.
+                    match self.include(rest, path, &location) {
+                        Ok(lines) => output.extend(lines.into_iter().map(|line| (line, false))),
+                        Err(e) => errors.push(e),
+                    }
+                    continue;
+                }
+                Some(directive) => Err(format!("Unknown directive `{}`", directive)),
+                None => match split_global(text) {
+                    Some(names) => {
+                        globals.extend(names);
+                        Ok(vec![])
+                    }
+                    None => self.expand_line(text, 0),
+                },
+            };
+            match result {
+                Ok(lines) => output.extend(lines.into_iter().map(|text| {
+                    let location = location.clone();
+                    (Line { text, location }, true)
+                })),
+                Err(e) => errors.push(format!("{}: {}", location, e)),
+            }
+        }
+
+        if let Some((name, _, location)) = definition {
+            errors.push(format!(
+                "{}: Macro `{}` is missing `%endmacro`",
+                location, name
+            ));
+        }
+
+        if root {
+            self.globals = globals;
+        } else {
+            let defined: HashSet<&str> = output
+                .iter()
+                .filter(|(_, own)| *own)
+                .filter_map(|(line, _)| split_label(&line.text).map(|(label, _)| label))
+                .collect();
+            for global in globals.iter() {
+                if !defined.contains(global.as_str()) {
+                    errors.push(format!(
+                        "{}: Label `{}` is declared global but never defined",
+                        file.as_deref().unwrap_or_default(),
+                        global
+                    ));
+                }
+            }
+            let private: HashSet<String> = defined
+                .into_iter()
+                .filter(|label| !globals.iter().any(|g| g == label))
+                .map(|label| label.to_string())
+                .collect();
+
+            self.files += 1;
+            let stem = path
+                .and_then(|p| p.file_stem())
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let stem: String = stem
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            let suffix = format!("__{}_{}", stem, self.files);
+            for (line, _) in output.iter_mut().filter(|(_, own)| *own) {
+                line.text = scope_labels(&line.text, &private, &suffix);
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        Ok(output.into_iter().map(|(line, _)| line).collect())
+    }
+
+    fn include(
+        &mut self,
+        rest: &str,
+        current: Option<&Path>,
+        location: &Location,
+    ) -> Result<Vec<Line>, String> {
+        let name = rest.trim().trim_matches(|c| c == '"' || c == '\'');
+        if name.is_empty() {
+            return Err(format!("{}: `%include` needs a file name", location));
+        }
+        // look next to the including file first, then in the include paths.
+        let dir = current
+            .and_then(|p| p.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let resolved = std::iter::once(dir)
+            .chain(self.include_paths.iter().cloned())
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                format!(
+                    "{}: Can't find `{}` to include (include paths: {:?})",
+                    location, name, self.include_paths
+                )
+            })?;
+        let display = resolved.display().to_string();
+        let canonical = resolved
+            .canonicalize()
+            .map_err(|e| format!("{}: {}: {}", location, display, e))?;
+
+        if let Some(index) = self.including.iter().position(|(p, _)| *p == canonical) {
+            let cycle: Vec<&str> = self.including[index..]
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .chain(std::iter::once(display.as_str()))
+                .collect();
+            return Err(format!(
+                "{}: Include cycle: {}",
+                location,
+                cycle.join(" -> ")
+            ));
+        }
+
+        let input = std::fs::read_to_string(&resolved)
+            .map_err(|e| format!("{}: {}: {}", location, display, e))?;
+        self.including.push((canonical, display));
+        let result = self.run_source(&input, Some(&resolved), false);
+        self.including.pop();
+        result
+    }
+
+    fn define(&mut self, rest: &str) -> Result<(), String> {
+        let (name, value) = split_word(rest);
+        if !is_identifier(name) {
+            return Err(format!("Invalid name for `%define`: `{}`", name));
+        }
+        let value = self.substitute_defines(value.trim());
+        self.defines.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn start_macro(&self, rest: &str) -> Result<(String, Macro), String> {
+        let (name, params) = split_word(rest);
+        if !is_identifier(name) {
+            return Err(format!("Invalid name for `%macro`: `{}`", name));
+        }
+        if self.macros.contains_key(name) {
+            return Err(format!("Macro `{}` is already defined", name));
+        }
+        let params = split_arguments(params);
+        if let Some(param) = params.iter().find(|p| !is_identifier(p)) {
+            return Err(format!(
+                "Invalid parameter `{}` for macro `{}`",
+                param, name
+            ));
+        }
+        Ok((
+            name.to_string(),
+            Macro {
+                params,
+                body: vec![],
+            },
+        ))
+    }
+
+    fn expand_line(&mut self, line: &str, depth: usize) -> Result<Vec<String>, String> {
+        let line = self.substitute_defines(line);
+        let (code, comment) = split_comment(&line);
+
+        // an invocation may be preceded by a label, e.g. `start: prologue`.
+        let (label, invocation) = match split_label(code) {
+            Some((label, rest)) => (Some(label), rest),
+            None => (None, code),
+        };
+        let (name, arguments) = split_word(invocation.trim_start());
+        let mac = match self.macros.get(name) {
+            Some(mac) => mac.clone(),
+            None => return Ok(vec![line.to_string()]),
+        };
+
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(format!(
+                "Macro `{}` is nested too deeply, does it invoke itself?",
+                name
+            ));
+        }
+        let arguments = split_arguments(arguments);
+        if arguments.len() != mac.params.len() {
+            return Err(format!(
+                "Macro `{}` takes {} argument(s), but {} were given",
+                name,
+                mac.params.len(),
+                arguments.len()
+            ));
+        }
+
+        self.expansions += 1;
+        let suffix = format!("__{}_{}", name, self.expansions);
+        let bindings: HashMap<&str, &str> = mac
+            .params
+            .iter()
+            .map(|p| p.as_str())
+            .zip(arguments.iter().map(|a| a.as_str()))
+            .collect();
+
+        let mut lines = vec![];
+        if let Some(label) = label {
+            lines.push(format!("{}:", label));
+        }
+        for body_line in mac.body.iter() {
+            let mut unbound = None;
+            let body_line = replace_words(body_line, |word| {
+                if let Some(local) = word.strip_prefix("%%") {
+                    return Some(format!("{}{}", local, suffix));
+                }
+                // `%1` is the first argument, `%2` the second and so on.
+                if let Some(index) = word.strip_prefix('%') {
+                    let argument = index
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|index| arguments.get(index.checked_sub(1)?));
+                    if argument.is_none() {
+                        unbound.get_or_insert_with(|| word.to_string());
+                    }
+                    return argument.cloned();
+                }
+                bindings.get(word).map(|arg| arg.to_string())
+            });
+            if let Some(word) = unbound {
+                return Err(format!(
+                    "Macro `{}` uses `{}`, but takes {} argument(s)",
+                    name,
+                    word,
+                    arguments.len()
+                ));
+            }
+            lines.extend(self.expand_line(&body_line, depth + 1)?);
+        }
+        if !comment.is_empty() {
+            lines.push(comment.to_string());
+        }
+        Ok(lines)
+    }
+
+    fn substitute_defines(&self, line: &str) -> String {
+        if self.defines.is_empty() {
+            return line.to_string();
+        }
+        replace_words(line, |word| self.defines.get(word).cloned())
+    }
+}
+
+// returns the names of a `global a, b` line.
+fn split_global(line: &str) -> Option<Vec<String>> {
+    let (code, _) = split_comment(line);
+    let (keyword, names) = split_word(code.trim());
+    if keyword != "global" {
+        return None;
+    }
+    Some(split_arguments(names))
+}
+
+// returns the label a line starts with, and the rest of the line.
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    let label = label.trim();
+    if !is_identifier(label) {
+        return None;
+    }
+    Some((label, rest))
+}
+
+// renames the private labels where they're defined and where they're used as operands.
+fn scope_labels(line: &str, private: &HashSet<String>, suffix: &str) -> String {
+    if private.is_empty() {
+        return line.to_string();
+    }
+    let rename = |word: &str| {
+        if private.contains(word) {
+            Some(format!("{}{}", word, suffix))
+        } else {
+            None
+        }
+    };
+    let (code, comment) = split_comment(line);
+    let mut output = String::with_capacity(line.len());
+    let rest = match split_label(code) {
+        Some((label, rest)) => {
+            output.push_str(&rename(label).unwrap_or_else(|| label.to_string()));
+            output.push(':');
+            rest
+        }
+        None => code,
+    };
+    // the opcode is never a label, and neither is a literal.
+    let indent = rest.len() - rest.trim_start().len();
+    let (opcode, operands) = split_word(&rest[indent..]);
+    if opcode.starts_with(['\'', '"']) {
+        output.push_str(rest);
+    } else {
+        output.push_str(&rest[..indent]);
+        output.push_str(opcode);
+        output.push_str(&replace_words(operands, rename));
+    }
+    output.push_str(comment);
+    output
+}
+
+// returns the `%directive` a line starts with, and the rest of the line.
+fn split_directive(line: &str) -> (Option<&str>, &str) {
+    let (code, _) = split_comment(line);
+    let code = code.trim();
+    if !code.starts_with('%') || code.starts_with("%%") {
+        return (None, code);
+    }
+    let (directive, rest) = split_word(code);
+    (Some(directive), rest.trim())
+}
+
+fn split_word(i: &str) -> (&str, &str) {
+    match i.find(char::is_whitespace) {
+        Some(index) => (&i[..index], &i[index..]),
+        None => (i, ""),
+    }
+}
+
+fn split_arguments(i: &str) -> Vec<String> {
+    if i.trim().is_empty() {
+        return vec![];
+    }
+    i.split(',').map(|arg| arg.trim().to_string()).collect()
+}
+
+// splits a line at the first `;` that isn't inside of a string.
+fn split_comment(line: &str) -> (&str, &str) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, ';') => return (&line[..index], &line[index..]),
+            _ => (),
+        }
+    }
+    (line, "")
+}
+
+fn is_identifier(i: &str) -> bool {
+    !i.is_empty() && i.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// `%%local` labels and `%1` parameters are words of their own.
+fn is_percent_word(code: &str) -> bool {
+    code.starts_with("%%")
+        || code
+            .strip_prefix('%')
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+// replaces whole words (including a leading `%%` or `%`) outside of strings and comments.
+fn replace_words(line: &str, mut replace: impl FnMut(&str) -> Option<String>) -> String {
+    let (code, comment) = split_comment(line);
+    let mut output = String::with_capacity(line.len());
+    let mut quote = None;
+    let mut escaped = false;
+    let mut chars = code.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                output.push(c);
+            }
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                output.push(c);
+            }
+            None if c.is_alphanumeric() || c == '_' || is_percent_word(&code[start..]) => {
+                let mut end = start + c.len_utf8();
+                if code[start..].starts_with("%%") {
+                    chars.next();
+                    end += 1;
+                }
+                while let Some(&(index, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+                let word = &code[start..end];
+                match replace(word) {
+                    Some(replacement) => output.push_str(&replacement),
+                    None => output.push_str(word),
+                }
+            }
+            None => output.push(c),
+        }
+    }
+    output.push_str(comment);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{preprocess, Preprocessor};
+
+    fn lines(input: &str) -> Vec<String> {
+        preprocess(input)
+            .unwrap()
+            .iter()
+            .map(|l| l.text.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn can_substitute_defines() {
+        assert_eq!(
+            lines("%define SIZE 16\n%define DOUBLE SIZE\nmov ra, SIZE ; SIZE\nmov rb, DOUBLE\nmsg: 'SIZE'"),
+            vec!["mov ra, 16 ; SIZE", "mov rb, 16", "msg: 'SIZE'"]
+        );
+    }
+
+    #[test]
+    fn can_expand_macros() {
+        assert_eq!(
+            lines(
+                r"
+                %macro swap a, b
+                    push a
+                    mov a, b
+                    pop b
+                %endmacro
+                start: swap ra, rb
+                swap rc, rd ; comment
+                "
+            ),
+            vec![
+                "start:",
+                "push ra",
+                "mov ra, rb",
+                "pop rb",
+                "push rc",
+                "mov rc, rd",
+                "pop rd",
+                "; comment"
+            ]
+        );
+        // parameters can also be used by position.
+        assert_eq!(
+            lines("%macro swap a, b\nxchg %1, %2\n%endmacro\nswap ra, rb"),
+            vec!["xchg ra, rb"]
+        );
+    }
+
+    #[test]
+    fn can_make_local_labels_unique() {
+        assert_eq!(
+            lines(
+                r"
+                %macro countdown reg
+                %%loop: dec reg
+                    jnz %%loop
+                %endmacro
+                %macro twice reg
+                    countdown reg
+                    countdown reg
+                %endmacro
+                twice ra
+                "
+            ),
+            vec![
+                "loop__countdown_2: dec ra",
+                "jnz loop__countdown_2",
+                "loop__countdown_3: dec ra",
+                "jnz loop__countdown_3",
+            ]
+        );
+    }
+
+    #[test]
+    fn can_report_macro_errors() {
+        assert_eq!(
+            preprocess("%macro one a\nnop\n%endmacro\none ra, rb").map(|_| ()),
+            Err("Line 3: Macro `one` takes 1 argument(s), but 2 were given".to_string())
+        );
+        assert_eq!(
+            preprocess("%macro one\nnop").map(|_| ()),
+            Err("Line 0: Macro `one` is missing `%endmacro`".to_string())
+        );
+        assert_eq!(
+            preprocess("%endmacro").map(|_| ()),
+            Err("Line 0: `%endmacro` without `%macro`".to_string())
+        );
+        assert_eq!(
+            preprocess("%macro loop\nloop\n%endmacro\nloop").map(|_| ()),
+            Err("Line 3: Macro `loop` is nested too deeply, does it invoke itself?".to_string())
+        );
+        assert_eq!(
+            preprocess("%macro two a, b\nmov %1, %3\n%endmacro\nnop\ntwo ra, rb").map(|_| ()),
+            Err("Line 4: Macro `two` uses `%3`, but takes 2 argument(s)".to_string())
+        );
+    }
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!("opvm2_{}_{}", name, std::process::id()));
+            for (path, content) in files {
+                let path = dir.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, content).unwrap();
+            }
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn run_file(dir: &TempDir, include_paths: &[&str]) -> Result<Vec<String>, String> {
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.include_paths = include_paths.iter().map(|p| dir.0.join(p)).collect();
+        Ok(preprocessor
+            .run_file(&dir.0.join("main.o2"))?
+            .into_iter()
+            .map(|l| l.text.trim().to_string())
+            .collect())
+    }
+
+    #[test]
+    fn can_include_files() -> Result<(), String> {
+        let dir = TempDir::new(
+            "include",
+            &[
+                (
+                    "main.o2",
+                    "%include \"local.o2\"\n%include \"strings.o2\"\nCOUNT",
+                ),
+                ("local.o2", "%define COUNT print 3"),
+                ("lib/strings.o2", "%include 'nested.o2'\nputs ra"),
+                ("lib/nested.o2", "putc 10"),
+            ],
+        );
+        assert_eq!(
+            run_file(&dir, &["lib"])?,
+            vec!["putc 10", "puts ra", "print 3"]
+        );
+        assert!(run_file(&dir, &[])
+            .unwrap_err()
+            .contains("Line 1: Can't find `strings.o2` to include"));
+        Ok(())
+    }
+
+    #[test]
+    fn can_scope_labels_to_files() -> Result<(), String> {
+        let dir = TempDir::new(
+            "labels",
+            &[
+                (
+                    "main.o2",
+                    "%include \"a.o2\"\n%include \"b.o2\"\nloop: call print_line\njmp loop",
+                ),
+                (
+                    "a.o2",
+                    "global print_line\nprint_line: puts msg\nloop: jmp loop ; loop\nmsg: 'loop msg'",
+                ),
+                ("b.o2", "loop: jmp print_line"),
+            ],
+        );
+        assert_eq!(
+            run_file(&dir, &[])?,
+            vec![
+                "print_line: puts msg__a_1",
+                "loop__a_1: jmp loop__a_1 ; loop",
+                "msg__a_1: 'loop msg'",
+                "loop__b_2: jmp print_line",
+                "loop: call print_line",
+                "jmp loop"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_detect_include_cycles() {
+        let dir = TempDir::new(
+            "cycle",
+            &[
+                ("main.o2", "%include \"a.o2\""),
+                ("a.o2", "%include \"b.o2\""),
+                ("b.o2", "%include \"a.o2\""),
+            ],
+        );
+        let error = run_file(&dir, &[])
+            .unwrap_err()
+            .replace(&format!("{}/", dir.0.display()), "");
+        assert_eq!(error, "b.o2: Line 0: Include cycle: a.o2 -> b.o2 -> a.o2");
+    }
+
+    #[test]
+    fn can_report_errors_per_file() {
+        let dir = TempDir::new(
+            "errors",
+            &[
+                ("main.o2", "nop\n%include \"lib.o2\"\n%oops"),
+                ("lib.o2", "global missing\n\n%endmacro"),
+            ],
+        );
+        let error = run_file(&dir, &[])
+            .unwrap_err()
+            .replace(&format!("{}/", dir.0.display()), "");
+        assert_eq!(
+            error,
+            "lib.o2: Line 2: `%endmacro` without `%macro`\n\
+             lib.o2: Label `missing` is declared global but never defined\n\
+             main.o2: Line 2: Unknown directive `%oops`"
+        );
+    }
+}
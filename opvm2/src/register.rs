@@ -1,71 +1,127 @@
+use std::fmt::Display;
+
 use extism_pdk::{FromBytes, Json, ToBytes};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, FromBytes, ToBytes)]
+/// How many registers there are, 32 or, with the `registers-64` feature, 64.
+#[cfg(not(feature = "registers-64"))]
+pub const COUNT: usize = 32;
+#[cfg(feature = "registers-64")]
+pub const COUNT: usize = 64;
+
+// the names of the registers with a fixed place in the file, by index. the rest are general
+// purpose registers, named `r10` and up.
+const NAMES: [&str; 26] = [
+    "ra", "rb", "rc", "rd", "re", "rf", "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9",
+    "sp", "bp", "f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7",
+];
+
+// `r10` is right after `f7`.
+const NUMBERED: usize = NAMES.len() - 10;
+
+/// Other names registers can be written as: `fp` (frame pointer) for `bp`, and `lr` (link
+/// register) for `rf`, the register the calling convention sets aside for return addresses.
+pub const ALIASES: [(&str, Register); 2] = [("fp", Register::Bp), ("lr", Register::Rf)];
+
+/// A register, by its index in the register file, which is also how it is encoded.
+///
+/// It is serialized as its name.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, FromBytes, ToBytes)]
+#[serde(into = "String", try_from = "String")]
 #[encoding(Json)]
-pub enum Register {
-    Ra,
-    Rb,
-    Rc,
-    Rd,
-    Re,
-    Rf,
-    R0,
-    R1,
-    R2,
-    R3,
-    R4,
-    R5,
-    R6,
-    R7,
-    R8,
-    R9,
+pub struct Register(u8);
+
+#[allow(non_upper_case_globals)]
+impl Register {
+    pub const Ra: Register = Register(0);
+    pub const Rb: Register = Register(1);
+    pub const Rc: Register = Register(2);
+    pub const Rd: Register = Register(3);
+    pub const Re: Register = Register(4);
+    pub const Rf: Register = Register(5);
+    pub const R0: Register = Register(6);
+    pub const R1: Register = Register(7);
+    pub const R2: Register = Register(8);
+    pub const R3: Register = Register(9);
+    pub const R4: Register = Register(10);
+    pub const R5: Register = Register(11);
+    pub const R6: Register = Register(12);
+    pub const R7: Register = Register(13);
+    pub const R8: Register = Register(14);
+    pub const R9: Register = Register(15);
+    /// The stack pointer, the lowest address of the memory stack in use.
+    pub const Sp: Register = Register(16);
+    /// The base pointer, the start of the current stack frame.
+    pub const Bp: Register = Register(17);
+    /* Floating point */
+    pub const F0: Register = Register(18);
+    pub const F1: Register = Register(19);
+    pub const F2: Register = Register(20);
+    pub const F3: Register = Register(21);
+    pub const F4: Register = Register(22);
+    pub const F5: Register = Register(23);
+    pub const F6: Register = Register(24);
+    pub const F7: Register = Register(25);
 }
+
 impl Register {
-    pub fn encode(&self) -> u32 {
-        match self {
-            Self::Ra => 0,
-            Self::Rb => 1,
-            Self::Rc => 2,
-            Self::Rd => 3,
-            Self::Re => 4,
-            Self::Rf => 5,
-            Self::R0 => 6,
-            Self::R1 => 7,
-            Self::R2 => 8,
-            Self::R3 => 9,
-            Self::R4 => 10,
-            Self::R5 => 11,
-            Self::R6 => 12,
-            Self::R7 => 13,
-            Self::R8 => 14,
-            Self::R9 => 15,
+    /// Every register, in the order of the register file.
+    pub fn all() -> impl Iterator<Item = Register> {
+        (0..COUNT).map(|index| Register(index as u8))
+    }
+
+    /// The general purpose registers, which `pushall` and `popall` save and restore.
+    pub fn general() -> impl Iterator<Item = Register> {
+        Self::all().filter(|register| !register.is_float() && !register.is_stack())
+    }
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn is_float(&self) -> bool {
+        (Self::F0.0..=Self::F7.0).contains(&self.0)
+    }
+
+    /// Whether the register is `sp` or `bp`.
+    pub fn is_stack(&self) -> bool {
+        *self == Self::Sp || *self == Self::Bp
+    }
+
+    pub fn name(&self) -> String {
+        match NAMES.get(self.index()) {
+            Some(name) => name.to_string(),
+            None => format!("r{}", self.index() - NUMBERED),
         }
     }
 
-    pub fn decode(value: u32) -> Register {
-        match value {
-            0 => Self::Ra,
-            1 => Self::Rb,
-            2 => Self::Rc,
-            3 => Self::Rd,
-            4 => Self::Re,
-            5 => Self::Rf,
-            6 => Self::R0,
-            7 => Self::R1,
-            8 => Self::R2,
-            9 => Self::R3,
-            10 => Self::R4,
-            11 => Self::R5,
-            12 => Self::R6,
-            13 => Self::R7,
-            14 => Self::R8,
-            15 => Self::R9,
-            _ => panic!("Bad register format! {:X}", value),
+    pub fn encode(&self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn decode(value: u32) -> Result<Register, String> {
+        match (value as usize) < COUNT {
+            true => Ok(Register(value as u8)),
+            false => Err(format!(
+                "Bad register {:#X}, there are {} registers",
+                value, COUNT
+            )),
         }
     }
 }
 
+impl Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl From<Register> for String {
+    fn from(value: Register) -> Self {
+        value.name()
+    }
+}
+
 macro_rules! flag_register {
     ($e:expr,bool) => {
         paste::item! {
@@ -96,22 +152,9 @@ macro_rules! flag_register {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ToBytes, FromBytes)]
 #[encoding(Json)]
 pub struct Registers {
-    pub ra: usize,
-    pub rb: usize,
-    pub rc: usize,
-    pub rd: usize,
-    pub re: usize,
-    pub rf: usize,
-    pub r0: usize,
-    pub r1: usize,
-    pub r2: usize,
-    pub r3: usize,
-    pub r4: usize,
-    pub r5: usize,
-    pub r6: usize,
-    pub r7: usize,
-    pub r8: usize,
-    pub r9: usize,
+    // by index. the float registers hold the bits of an f64, so that they can be moved around
+    // like the other registers.
+    values: Vec<usize>,
     equals_flag: bool,
     greater_than_flag: bool,
     less_than_flag: bool,
@@ -124,22 +167,7 @@ pub struct Registers {
 impl Registers {
     pub fn new() -> Registers {
         Registers {
-            ra: 0,
-            rb: 0,
-            rc: 0,
-            rd: 0,
-            re: 0,
-            rf: 0,
-            r0: 0,
-            r1: 0,
-            r2: 0,
-            r3: 0,
-            r4: 0,
-            r5: 0,
-            r6: 0,
-            r7: 0,
-            r8: 0,
-            r9: 0,
+            values: vec![0; COUNT],
             equals_flag: false,
             greater_than_flag: false,
             less_than_flag: false,
@@ -151,45 +179,16 @@ impl Registers {
     }
 
     pub fn get(&self, register: &Register) -> usize {
-        match register {
-            Register::Ra => self.ra,
-            Register::Rb => self.rb,
-            Register::Rc => self.rc,
-            Register::Rd => self.rd,
-            Register::Re => self.re,
-            Register::Rf => self.rf,
-            Register::R0 => self.r0,
-            Register::R1 => self.r1,
-            Register::R2 => self.r2,
-            Register::R3 => self.r3,
-            Register::R4 => self.r4,
-            Register::R5 => self.r5,
-            Register::R6 => self.r6,
-            Register::R7 => self.r7,
-            Register::R8 => self.r8,
-            Register::R9 => self.r9,
-        }
+        self.values[register.index()]
     }
 
     pub fn set(&mut self, register: &Register, value: usize) {
-        match register {
-            Register::Ra => self.ra = value,
-            Register::Rb => self.rb = value,
-            Register::Rc => self.rc = value,
-            Register::Rd => self.rd = value,
-            Register::Re => self.re = value,
-            Register::Rf => self.rf = value,
-            Register::R0 => self.r0 = value,
-            Register::R1 => self.r1 = value,
-            Register::R2 => self.r2 = value,
-            Register::R3 => self.r3 = value,
-            Register::R4 => self.r4 = value,
-            Register::R5 => self.r5 = value,
-            Register::R6 => self.r6 = value,
-            Register::R7 => self.r7 = value,
-            Register::R8 => self.r8 = value,
-            Register::R9 => self.r9 = value,
-        }
+        self.values[register.index()] = value;
+    }
+
+    /// Every register with its value, in the order of the register file.
+    pub fn iter(&self) -> impl Iterator<Item = (Register, usize)> + '_ {
+        Register::all().map(|register| (register, self.get(&register)))
     }
 
     flag_register!(equals_flag, bool);
@@ -200,8 +199,17 @@ impl Registers {
     flag_register!(call_stack_len, usize);
     flag_register!(pc, usize);
 
-    pub fn increment_pc(&mut self) {
-        self.pc += 16; // increment size of instruction
+    /// The value of a float register.
+    pub fn get_float(&self, register: &Register) -> f64 {
+        f64::from_bits(self.get(register) as u64)
+    }
+
+    pub fn set_float(&mut self, register: &Register, value: f64) {
+        self.set(register, value.to_bits() as usize);
+    }
+
+    pub fn increment_pc(&mut self, length: usize) {
+        self.pc += length; // increment size of instruction
     }
 
     pub fn reset_flags(&mut self) {
@@ -216,24 +224,63 @@ impl TryFrom<String> for Register {
     type Error = String;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        match value.as_str() {
-            "ra" => Ok(Self::Ra),
-            "rb" => Ok(Self::Rb),
-            "rc" => Ok(Self::Rc),
-            "rd" => Ok(Self::Rd),
-            "re" => Ok(Self::Re),
-            "rf" => Ok(Self::Rf),
-            "r0" => Ok(Self::R0),
-            "r1" => Ok(Self::R1),
-            "r2" => Ok(Self::R2),
-            "r3" => Ok(Self::R3),
-            "r4" => Ok(Self::R4),
-            "r5" => Ok(Self::R5),
-            "r6" => Ok(Self::R6),
-            "r7" => Ok(Self::R7),
-            "r8" => Ok(Self::R8),
-            "r9" => Ok(Self::R9),
-            &_ => Err(format!("{:?} is not a valid register", value)),
+        if let Some(index) = NAMES.iter().position(|name| *name == value) {
+            return Ok(Register(index as u8));
         }
+        if let Some((_, register)) = ALIASES.iter().find(|(alias, _)| *alias == value) {
+            return Ok(*register);
+        }
+        // `r10` and up, without leading zeroes.
+        let number = value
+            .strip_prefix('r')
+            .filter(|number| !number.starts_with('0'))
+            .and_then(|number| number.parse::<usize>().ok());
+        match number {
+            Some(number) if number >= 10 && number + NUMBERED < COUNT => {
+                Ok(Register((number + NUMBERED) as u8))
+            }
+            _ => Err(format!("{:?} is not a valid register", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use extism_pdk::ToBytes;
+
+    use super::{Register, Registers, COUNT};
+
+    #[test]
+    fn can_name_registers() {
+        assert_eq!(Register::all().count(), COUNT);
+        for register in Register::all() {
+            assert_eq!(Register::try_from(register.name()), Ok(register));
+        }
+        assert_eq!(Register::R9.name(), "r9");
+        assert_eq!(Register::F7.name(), "f7");
+        assert_eq!(
+            Register::decode(26).map(|r| r.name()),
+            Ok("r10".to_string())
+        );
+        assert!(Register::decode(COUNT as u32).is_err());
+        assert_eq!(Register::try_from("fp".to_string()), Ok(Register::Bp));
+        assert_eq!(Register::try_from("lr".to_string()), Ok(Register::Rf));
+        assert!(Register::try_from("r010".to_string()).is_err());
+        assert!(Register::try_from(format!("r{}", COUNT - 16)).is_err());
+        assert_eq!(Register::general().count(), COUNT - 10);
+    }
+
+    #[test]
+    fn can_serialize_registers_by_name() {
+        let mut registers = Registers::new();
+        registers.set(&Register::Rb, 7);
+        assert_eq!(
+            registers.iter().find(|(_, value)| *value == 7),
+            Some((Register::Rb, 7))
+        );
+        assert_eq!(
+            Register::Bp.to_bytes().unwrap().as_ref() as &[u8],
+            b"\"bp\""
+        );
     }
 }
@@ -31,6 +31,22 @@ impl<T> Stack<T> {
         self.0.last()
     }
 
+    /// The value `depth` values below the top, 0 being the top.
+    pub fn pick(&self, depth: usize) -> Option<&T> {
+        self.0
+            .len()
+            .checked_sub(depth + 1)
+            .map(|index| &self.0[index])
+    }
+
+    /// Moves the value `depth` values below the top to the top.
+    pub fn roll(&mut self, depth: usize) -> Option<()> {
+        let index = self.0.len().checked_sub(depth + 1)?;
+        let value = self.0.remove(index);
+        self.0.push(value);
+        Some(())
+    }
+
     pub fn to_vec(&self) -> &Vec<T> {
         &self.0
     }
@@ -1,27 +1,108 @@
-use std::{fs::File, io::Write, path::Path, time::Instant};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use extism::{FromBytes, ToBytes};
 use lz4::{Decoder, EncoderBuilder};
 use opvm2::parser::program::Program;
-use opvm2_vm::{vm::Vm, CompiledProgram};
+use opvm2_vm::{
+    device::{BlockDevice, Clock, Console, Random},
+    object::{self, Archive, Object},
+    registry::PluginRegistry,
+    vm::{Engine, Vm},
+    CompiledProgram,
+};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long)]
     debug: bool,
     #[arg(short, long)]
     compile: bool,
     #[arg(short, long, required = true)]
-    file: String,
+    file: Option<String>,
     #[arg(short, long)]
     interpret: bool,
+    /// Directories to look for `%include`d files in, after the directory of the including file
+    #[arg(short = 'I', long)]
+    include_path: Vec<PathBuf>,
     #[arg(short, long)]
     plugin: Vec<String>,
+    /// Reference a plugin by name instead of embedding it, it is looked up in the plugin path when run
+    #[arg(long)]
+    plugin_name: Vec<String>,
+    /// Directories to look for plugins in, in addition to `OPVM2_PLUGIN_PATH`
+    #[arg(long)]
+    plugin_path: Vec<PathBuf>,
+    /// Run with the threaded engine, programs that load plugins are still interpreted
+    #[arg(long)]
+    threaded: bool,
+    /// Fail when a call returns without preserving the callee-saved registers
+    #[arg(long)]
+    check_calls: bool,
+    /// Map a device past the end of memory, as `NAME@ADDRESS`: `console`, `clock`, `random` or `block:FILE`
+    #[arg(long)]
+    device: Vec<String>,
     #[arg(short, long)]
     verbose: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect the plugins of a compiled program
+    Plugins {
+        #[command(subcommand)]
+        command: PluginsCommand,
+    },
+    /// Compile source files separately and link them into a program
+    Build {
+        /// Only compile, writing a relocatable object (`.o2o`) next to each file
+        #[arg(short)]
+        c: bool,
+        /// The program to write, named after the first file by default, or with `-c` the
+        /// object of the only file
+        #[arg(short, long)]
+        output: Option<String>,
+        #[arg(short = 'I', long)]
+        include_path: Vec<PathBuf>,
+        #[arg(short, long)]
+        plugin: Vec<String>,
+        #[arg(long)]
+        plugin_name: Vec<String>,
+        #[arg(short, long)]
+        verbose: bool,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Link objects and static libraries (`.o2a`) into a program
+    Link {
+        #[arg(short, long, default_value = "a.o2c")]
+        output: String,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Bundle objects into a static library, whose objects are only linked when they're used
+    Archive {
+        #[arg(short, long)]
+        output: String,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PluginsCommand {
+    /// List the plugins a compiled program will load
+    List { file: String },
+}
+
 fn compress(input: Vec<u8>, output_file: String) -> Result<(), String> {
     let output_file = File::create(output_file).map_err(|e| e.to_string())?;
     let mut encoder = EncoderBuilder::new().level(4).build(output_file).unwrap();
@@ -30,14 +111,51 @@ fn compress(input: Vec<u8>, output_file: String) -> Result<(), String> {
     Ok(())
 }
 
-fn run_interpreter(vm: &mut Vm, path: String, plugins: Vec<Vec<u8>>) -> Result<(), String> {
-    let file_content = std::fs::read_to_string(path).unwrap();
-    let mut program = Program::from(file_content.as_str());
+fn run_interpreter(
+    vm: &mut Vm,
+    path: String,
+    include_paths: &[PathBuf],
+    plugins: Vec<Vec<u8>>,
+) -> Result<(), String> {
+    let mut program = Program::parse_file(Path::new(&path), include_paths)?;
     program.plugins = plugins;
     vm.run_program(program)?;
     Ok(())
 }
 
+fn map_devices(vm: &mut Vm, devices: &[String]) -> Result<(), String> {
+    for spec in devices {
+        let Some((name, address)) = spec.rsplit_once('@') else {
+            return Err(format!("Device `{}` has to be given as NAME@ADDRESS", spec));
+        };
+        let address = match address.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => address.parse(),
+        }
+        .map_err(|_| format!("`{}` is not a valid device address", address))?;
+        match name {
+            "console" => {
+                let context = vm.context.get().map_err(|e| e.to_string())?;
+                let io = context.lock().unwrap().io.clone();
+                vm.map_device(address, Console::new(io))?
+            }
+            "clock" => vm.map_device(address, Clock::new())?,
+            "random" => {
+                let seed = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_err(|e| e.to_string())?
+                    .as_nanos() as u64;
+                vm.map_device(address, Random::new(seed))?
+            }
+            _ => match name.strip_prefix("block:") {
+                Some(path) => vm.map_device(address, BlockDevice::open(path)?)?,
+                None => return Err(format!("Unknown device `{}`", name)),
+            },
+        }
+    }
+    Ok(())
+}
+
 fn load_plugins(plugins: Vec<String>) -> Result<Vec<Vec<u8>>, String> {
     let mut loaded = Vec::new();
     for plugin in plugins {
@@ -47,21 +165,64 @@ fn load_plugins(plugins: Vec<String>) -> Result<Vec<Vec<u8>>, String> {
     Ok(loaded)
 }
 
-fn run_compiled_program(vm: &mut Vm, path: String) -> Result<(), String> {
-    let input_file = File::open(&path).map_err(|e| e.to_string())?;
+fn decompress(path: &str) -> Result<Vec<u8>, String> {
+    let input_file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
     let mut decoder = Decoder::new(input_file).map_err(|e| e.to_string())?;
     let mut buffer: Vec<u8> = Vec::new();
     std::io::copy(&mut decoder, &mut buffer).map_err(|e| e.to_string())?;
-    let compiled = CompiledProgram::from(buffer);
+    Ok(buffer)
+}
+
+fn read_compiled_program(path: &str) -> Result<CompiledProgram, String> {
+    Ok(CompiledProgram::from(decompress(path)?))
+}
+
+fn read_object(path: &str) -> Result<Object, String> {
+    Object::from_bytes(&decompress(path)?).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn run_compiled_program(vm: &mut Vm, path: String) -> Result<(), String> {
+    let compiled = read_compiled_program(&path)?;
     vm.run(compiled).unwrap();
     Ok(())
 }
 
-fn compile(path: String, plugins: Vec<Vec<u8>>, verbose: bool) -> Result<(), String> {
-    let file_content = std::fs::read_to_string(&path).unwrap();
-    let mut program = Program::from(file_content.as_str());
+fn list_plugins(path: String) -> Result<(), String> {
+    let compiled = read_compiled_program(&path)?;
+    if compiled.plugins.is_empty() {
+        println!("No plugins.");
+    }
+    for plugin in compiled.plugins {
+        println!(
+            "{} {} ({})",
+            plugin.info.name,
+            plugin.info.version,
+            if plugin.is_embedded() {
+                "embedded"
+            } else {
+                "referenced"
+            }
+        );
+        println!("  hash:    {}", plugin.info.hash);
+        println!("  opcodes: {}", plugin.info.opcodes.join(", "));
+    }
+    Ok(())
+}
+
+fn compile(
+    path: String,
+    include_paths: &[PathBuf],
+    plugins: Vec<Vec<u8>>,
+    references: Vec<String>,
+    registry: &PluginRegistry,
+    verbose: bool,
+) -> Result<(), String> {
+    let mut program = Program::parse_file(Path::new(&path), include_paths)?;
     program.plugins = plugins;
     let mut to_compile = CompiledProgram::new_e();
+    for name in references {
+        to_compile.reference_plugin(registry, &name)?;
+    }
 
     let compiled = to_compile.compile(program, verbose)?;
 
@@ -75,9 +236,121 @@ fn compile(path: String, plugins: Vec<Vec<u8>>, verbose: bool) -> Result<(), Str
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+fn build(
+    files: Vec<String>,
+    compile_only: bool,
+    output: Option<String>,
+    include_paths: &[PathBuf],
+    plugins: Vec<Vec<u8>>,
+    references: Vec<String>,
+    registry: &PluginRegistry,
+    verbose: bool,
+) -> Result<(), String> {
+    if compile_only && output.is_some() && files.len() > 1 {
+        return Err("`-o` can't be used with `-c` and more than one file".to_string());
+    }
+    let mut objects = vec![];
+    for path in files.iter() {
+        let mut program = Program::parse_file(Path::new(path), include_paths)?;
+        program.plugins = plugins.clone();
+        let mut to_compile = CompiledProgram::new_e();
+        for name in references.iter() {
+            to_compile.reference_plugin(registry, name)?;
+        }
+        let object = to_compile.compile_object(program, path, verbose)?;
+        if compile_only {
+            // next to the source, so that files of the same name don't overwrite each other.
+            let output = output.clone().unwrap_or_else(|| {
+                Path::new(path)
+                    .with_extension("o2o")
+                    .to_string_lossy()
+                    .to_string()
+            });
+            compress(object.to_bytes().map_err(|e| e.to_string())?, output)?;
+        }
+        objects.push(object);
+    }
+    if compile_only {
+        return Ok(());
+    }
+
+    let output = output.unwrap_or_else(|| {
+        let first = Path::new(&files[0]).file_name().unwrap().to_str().unwrap();
+        format!("{}c", first)
+    });
+    let linked = object::link(objects, vec![])?;
+    compress(linked.to_bytes().map_err(|e| e.to_string())?, output)
+}
+
+fn link(files: Vec<String>, output: String) -> Result<(), String> {
+    let mut objects = vec![];
+    let mut archives = vec![];
+    for path in files.iter() {
+        if path.ends_with(".o2a") {
+            let archive =
+                Archive::from_bytes(&decompress(path)?).map_err(|e| format!("{}: {}", path, e))?;
+            archives.push(archive);
+        } else {
+            objects.push(read_object(path)?);
+        }
+    }
+    let linked = object::link(objects, archives)?;
+    compress(linked.to_bytes().map_err(|e| e.to_string())?, output)
+}
+
+fn archive(files: Vec<String>, output: String) -> Result<(), String> {
+    let objects = files
+        .iter()
+        .map(|path| read_object(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let archive = Archive { objects };
+    compress(archive.to_bytes().map_err(|e| e.to_string())?, output)
+}
+
 fn main() -> Result<(), String> {
     let mut vm = Vm::new_e();
     let args = Args::parse();
+    let mut registry = PluginRegistry::from_env();
+    registry.search_paths.extend(args.plugin_path);
+
+    match args.command {
+        Some(Command::Plugins {
+            command: PluginsCommand::List { file },
+        }) => return list_plugins(file),
+        Some(Command::Build {
+            c,
+            output,
+            include_path,
+            plugin,
+            plugin_name,
+            verbose,
+            files,
+        }) => {
+            let plugins = load_plugins(plugin)?;
+            return build(
+                files,
+                c,
+                output,
+                &include_path,
+                plugins,
+                plugin_name,
+                &registry,
+                verbose,
+            );
+        }
+        Some(Command::Link { output, files }) => return link(files, output),
+        Some(Command::Archive { output, files }) => return archive(files, output),
+        None => {}
+    }
+
+    let file = args.file.unwrap();
+    vm.registry = registry.clone();
+    if args.threaded {
+        vm.engine = Engine::Threaded;
+    }
+    vm.check_calling_convention(args.check_calls);
+    map_devices(&mut vm, &args.device)?;
 
     if args.debug {
         vm.plugin
@@ -88,16 +361,27 @@ fn main() -> Result<(), String> {
             .unwrap();
     }
 
-    let plugins = load_plugins(args.plugin)?;
+    let mut plugins = load_plugins(args.plugin)?;
 
     if args.interpret {
-        run_interpreter(&mut vm, args.file, plugins)?;
+        // there is no compiled program to record references in, so just load them.
+        for name in args.plugin_name {
+            plugins.push(registry.find(&name)?.1);
+        }
+        run_interpreter(&mut vm, file, &args.include_path, plugins)?;
         return Ok(());
     }
 
     if args.compile {
         let now = Instant::now();
-        compile(args.file, plugins, args.verbose)?;
+        compile(
+            file,
+            &args.include_path,
+            plugins,
+            args.plugin_name,
+            &registry,
+            args.verbose,
+        )?;
         let end = now.elapsed();
 
         if args.verbose {
@@ -106,7 +390,7 @@ fn main() -> Result<(), String> {
         return Ok(());
     }
 
-    run_compiled_program(&mut vm, args.file)?;
+    run_compiled_program(&mut vm, file)?;
 
     Ok(())
 }
@@ -0,0 +1,135 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use opvm2::{parser::program::Program, plugin_interface::OnInstructionValue};
+use opvm2_vm::{
+    io::BufferIo,
+    machine_context::MachineContext,
+    native::OpvmPlugin,
+    vm::{Engine, Vm},
+    CompiledProgram,
+};
+
+const COUNT: &str = r"
+      mov ra, 0
+loop: inc ra
+      test ra, 10000
+      jl loop
+";
+
+const CALLS: &str = r"
+      mov ra, 0
+loop: call bump
+      test ra, 5000
+      jl loop
+      jmp end
+bump: inc ra
+      ret
+end:
+";
+
+const FIZZBUZZ: &str = r"
+fizz: 'Fizz'
+buzz: 'Buzz'
+       mov ra, 1
+start: call calc_fizz
+       call calc_buzz
+       call print_number
+       putc 10
+       inc ra
+       test ra, 100
+       jle start
+       jmp end
+print_number:
+       pop rc
+       test rc, rc
+       jnz skip
+       pop rc
+       test rc, rc
+       jnz skip
+       print ra
+skip:  ret
+calc_fizz:
+       mov rb, ra
+       mod rb, 3
+       test rb, rb
+       jnz skip_fizz
+       puts fizz
+       push 1
+       ret
+skip_fizz:
+       push 0
+       ret
+calc_buzz:
+       mov rb, ra
+       mod rb, 5
+       test rb, rb
+       jnz skip_buzz
+       puts buzz
+       push 1
+       ret
+skip_buzz:
+       push 0
+       ret
+end:
+";
+
+// a hook that does nothing, to measure what dispatching to plugins costs.
+struct Hook;
+
+impl OpvmPlugin for Hook {
+    fn name(&self) -> String {
+        "Hook".to_string()
+    }
+
+    fn opcodes(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn has_hook(&self) -> bool {
+        true
+    }
+
+    fn handle_opcode(
+        &mut self,
+        _opcode: &str,
+        _context: &mut MachineContext,
+        _ins: &OnInstructionValue,
+    ) -> Result<Option<u64>, String> {
+        Ok(None)
+    }
+}
+
+fn compile(source: &str) -> CompiledProgram {
+    let mut compiled = CompiledProgram::new_e();
+    compiled.compile(Program::from(source), false).unwrap();
+    compiled
+}
+
+fn run(program: &CompiledProgram, engine: Engine, hook: bool) {
+    let mut vm = Vm::new_e();
+    vm.engine = engine;
+    vm.set_io(BufferIo::new(""));
+    if hook {
+        vm.plugin.load_native(Hook).unwrap();
+    }
+    vm.run(program.clone()).unwrap();
+}
+
+fn interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interpreter");
+    for (name, source) in [("count", COUNT), ("calls", CALLS), ("fizzbuzz", FIZZBUZZ)] {
+        let program = compile(source);
+        group.bench_function(name, |b| {
+            b.iter(|| run(&program, Engine::Interpreter, false))
+        });
+        group.bench_function(format!("{}_with_hook", name), |b| {
+            b.iter(|| run(&program, Engine::Interpreter, true))
+        });
+        group.bench_function(format!("{}_threaded", name), |b| {
+            b.iter(|| run(&program, Engine::Threaded, false))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
@@ -0,0 +1,158 @@
+//! The standard calling convention for opvm2 code.
+//!
+//! - The first six arguments go in `r0` to `r5`. Any further arguments are written to the
+//!   memory stack by the caller, the first one at `[sp]`, and the callee drops them with
+//!   `ret n`, where `n` is their size in bytes. After `enter`, they start at `[bp + 8]`.
+//! - The return value goes in `ra`.
+//! - `ra` to `rf` and `r0` to `r5` may be changed by the callee (caller-saved).
+//! - `r6` to `r9`, `bp` and `sp` have to hold the same values after the call returns as when
+//!   it was made (callee-saved), with `sp` moved up past the arguments `ret n` drops.
+//!
+//! Return addresses live on the call stack rather than the memory stack, so a call can only go
+//! [`MAX_CALL_DEPTH`] calls deep. Code that passes a return address in a register instead, to
+//! get back with `jmp`, puts it in [`LINK`] (`lr`, another name for `rf`), so it is
+//! caller-saved.
+//!
+//! The VM doesn't enforce the convention unless the [`Checker`] is turned on, with
+//! `Vm::check_calling_convention`.
+
+use opvm2::register::{Register, Registers};
+
+/// The registers that hold the first arguments of a call, in order.
+pub const ARGUMENTS: [Register; 6] = [
+    Register::R0,
+    Register::R1,
+    Register::R2,
+    Register::R3,
+    Register::R4,
+    Register::R5,
+];
+
+/// The register that holds the return value of a call.
+pub const RETURN_VALUE: Register = Register::Ra;
+
+/// The link register, `lr`, for return addresses kept in a register.
+pub const LINK: Register = Register::Rf;
+
+/// The registers a callee has to preserve.
+pub const CALLEE_SAVED: [Register; 6] = [
+    Register::R6,
+    Register::R7,
+    Register::R8,
+    Register::R9,
+    Register::Bp,
+    Register::Sp,
+];
+
+/// How many calls can be nested before the call stack overflows.
+pub const MAX_CALL_DEPTH: usize = 1 << 16;
+
+/// Checks that every call returns with the callee-saved registers it was made with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checker {
+    // the pc of each call in progress, with its callee-saved registers.
+    calls: Vec<(usize, [usize; CALLEE_SAVED.len()])>,
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker::default()
+    }
+
+    /// Records the callee-saved registers as a call is made.
+    pub fn call(&mut self, registers: &Registers) {
+        let saved = CALLEE_SAVED.map(|register| registers.get(&register));
+        self.calls.push((*registers.check_pc(), saved));
+    }
+
+    /// Checks the callee-saved registers once a call has returned, after dropping `arguments`
+    /// bytes of arguments.
+    pub fn ret(&mut self, registers: &Registers, arguments: usize) -> Result<(), String> {
+        let Some((call, saved)) = self.calls.pop() else {
+            return Ok(());
+        };
+        for (register, saved) in CALLEE_SAVED.iter().zip(saved) {
+            let expected = match *register {
+                Register::Sp => saved.wrapping_add(arguments),
+                _ => saved,
+            };
+            let value = registers.get(register);
+            if value != expected {
+                return Err(format!(
+                    "The call at {:#02X} returned with `{}` changed from {:#X} to {:#X} at ins {:#02X}.",
+                    call,
+                    register,
+                    expected,
+                    value,
+                    registers.check_pc()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        io::BufferIo,
+        vm::{Engine, Vm},
+        CompiledProgram,
+    };
+
+    fn run_checked(program: &str) -> Result<String, String> {
+        let program = CompiledProgram::from(program);
+        let mut results = vec![];
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let io = BufferIo::new("");
+            let mut vm = Vm::new_e();
+            vm.engine = engine;
+            vm.set_io(io.clone());
+            vm.check_calling_convention(true);
+            results.push(vm.run(program.clone()).map(|_| io.output()));
+        }
+        assert_eq!(results[0], results[1]);
+        results.remove(0)
+    }
+
+    #[test]
+    fn can_check_calling_convention() {
+        // `sum` saves the r6 it uses and drops its stack argument.
+        assert_eq!(
+            run_checked(
+                r"
+                mov r0, 1
+                mov r1, 2
+                sub sp, 8
+                mov [sp], 3
+                call sum
+                print ra
+                jmp end
+            sum:
+                enter 8
+                mov [bp - 8], r6
+                mov r6, r0
+                add r6, r1
+                add r6, [bp + 8]
+                mov ra, r6
+                mov r6, [bp - 8]
+                leave
+                ret 8
+            end:
+            "
+            ),
+            Ok("6\n".to_string())
+        );
+
+        let start_address =
+            CompiledProgram::from("call clobber\nclobber: mov r7, 1\nret").start_address;
+        assert_eq!(
+            run_checked("call clobber\nclobber: mov r7, 1\nret"),
+            Err(format!(
+                "The call at {:#02X} returned with `r7` changed from 0x0 to 0x1 at ins {:#02X}.",
+                start_address,
+                start_address + 7
+            ))
+        );
+    }
+}
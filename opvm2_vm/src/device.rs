@@ -0,0 +1,432 @@
+//! Devices mapped into memory, so that programs drive them by reading and writing addresses.
+//!
+//! Devices are mapped above the end of memory, where the stack starts, and take up
+//! [`Device::size`] bytes from their address. `mov [reg], value` writes to a device and
+//! `mov reg, [reg]` reads from it, like any other address.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::io::IoHandle;
+
+/// A device a program reads from and writes to through memory.
+pub trait Device: Send {
+    /// How many bytes of addresses the device takes up.
+    fn size(&self) -> usize;
+    /// Fills `data` with what the device holds from `offset`, an offset into its addresses.
+    fn read(&mut self, offset: usize, data: &mut [u8]) -> Result<(), String>;
+    /// Writes `data` to the device at `offset`.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), String>;
+}
+
+struct Mapping {
+    address: usize,
+    device: Box<dyn Device>,
+}
+
+impl Mapping {
+    // whether `address..address + length` is within the device.
+    fn contains(&self, address: usize, length: usize) -> bool {
+        address >= self.address
+            && address
+                .checked_add(length)
+                .is_some_and(|end| end <= self.address + self.device.size())
+    }
+}
+
+/// The devices mapped into a memory, shared by every clone of it.
+#[derive(Clone, Default)]
+pub struct Bus(Arc<Mutex<Vec<Mapping>>>);
+
+impl Bus {
+    /// Maps `device` at `address`, which can't overlap another device.
+    pub fn map(&self, address: usize, device: impl Device + 'static) -> Result<(), String> {
+        let end = address
+            .checked_add(device.size())
+            .ok_or_else(|| format!("Device at {:#02X} is out of bounds.", address))?;
+        let mut mappings = self.0.lock().unwrap();
+        if let Some(mapping) = mappings.iter().find(|mapping| {
+            address < mapping.address + mapping.device.size() && mapping.address < end
+        }) {
+            return Err(format!(
+                "Device at {:#02X} overlaps the device at {:#02X}.",
+                address, mapping.address
+            ));
+        }
+        mappings.push(Mapping {
+            address,
+            device: Box::new(device),
+        });
+        Ok(())
+    }
+
+    pub fn read(&self, address: usize, length: usize) -> Result<Vec<u8>, String> {
+        let mut mappings = self.0.lock().unwrap();
+        let Some(mapping) = mappings
+            .iter_mut()
+            .find(|mapping| mapping.contains(address, length))
+        else {
+            return Err(format!(
+                "Read from {:#02X} is out of memory bounds.",
+                address
+            ));
+        };
+        let mut data = vec![0; length];
+        mapping.device.read(address - mapping.address, &mut data)?;
+        Ok(data)
+    }
+
+    pub fn write(&self, address: usize, data: &[u8]) -> Result<(), String> {
+        let mut mappings = self.0.lock().unwrap();
+        let Some(mapping) = mappings
+            .iter_mut()
+            .find(|mapping| mapping.contains(address, data.len()))
+        else {
+            return Err(format!(
+                "Write to {:#02X} is out of memory bounds.",
+                address.saturating_add(data.len())
+            ));
+        };
+        mapping.device.write(address - mapping.address, data)
+    }
+}
+
+impl std::fmt::Debug for Bus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bus")
+    }
+}
+
+// devices aren't part of what memory holds, so they're left out when comparing it.
+impl PartialEq for Bus {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Bus {}
+
+impl PartialOrd for Bus {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Bus {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+/// Writing a byte prints it, reading one reads it from the input, 0 at the end of it.
+///
+/// Only the lowest byte of a word is used, so `mov [reg], 'A'` prints `A`.
+pub struct Console {
+    io: IoHandle,
+}
+
+impl Console {
+    pub fn new(io: IoHandle) -> Console {
+        Console { io }
+    }
+}
+
+impl Device for Console {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn read(&mut self, _: usize, data: &mut [u8]) -> Result<(), String> {
+        if let Some(byte) = data.first_mut() {
+            *byte = self.io.read_byte()?.unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, _: usize, data: &[u8]) -> Result<(), String> {
+        match data.first() {
+            Some(byte) => self.io.write(&[*byte]),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads as the number of milliseconds since it was created, writing to it starts it over.
+pub struct Clock {
+    start: Instant,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Clock {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn read(&mut self, offset: usize, data: &mut [u8]) -> Result<(), String> {
+        let elapsed = (self.start.elapsed().as_millis() as u64).to_le_bytes();
+        data.copy_from_slice(&elapsed[offset..offset + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, _: usize, _: &[u8]) -> Result<(), String> {
+        self.start = Instant::now();
+        Ok(())
+    }
+}
+
+/// Pixels, a byte each, row by row, which the host can read back from any clone of it.
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: Arc::new(Mutex::new(vec![0; width * height])),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> Vec<u8> {
+        self.pixels.lock().unwrap().clone()
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> usize {
+        self.width * self.height
+    }
+
+    fn read(&mut self, offset: usize, data: &mut [u8]) -> Result<(), String> {
+        data.copy_from_slice(&self.pixels.lock().unwrap()[offset..offset + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), String> {
+        self.pixels.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Reads as a new pseudo-random word every time. The numbers only depend on the seed, which
+/// writing to it sets.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        let mut random = Random { state: 0 };
+        random.seed(seed);
+        random
+    }
+
+    // xorshift can't get out of 0.
+    fn seed(&mut self, seed: u64) {
+        self.state = seed.max(1);
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl Device for Random {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn read(&mut self, _: usize, data: &mut [u8]) -> Result<(), String> {
+        let value = self.next().to_le_bytes();
+        data.copy_from_slice(&value[..data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, _: usize, data: &[u8]) -> Result<(), String> {
+        let mut seed = [0u8; 8];
+        seed[..data.len()].copy_from_slice(data);
+        self.seed(u64::from_le_bytes(seed));
+        Ok(())
+    }
+}
+
+/// A file, read and written in place. It takes up as many addresses as the file is long.
+pub struct BlockDevice {
+    file: File,
+    size: usize,
+}
+
+impl BlockDevice {
+    /// Opens an existing file for reading and writing.
+    pub fn open(path: impl AsRef<Path>) -> Result<BlockDevice, String> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        let size = file.metadata().map_err(|e| e.to_string())?.len() as usize;
+        Ok(BlockDevice { file, size })
+    }
+}
+
+impl Device for BlockDevice {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read(&mut self, offset: usize, data: &mut [u8]) -> Result<(), String> {
+        self.file
+            .seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| self.file.read_exact(data))
+            .map_err(|e| e.to_string())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), String> {
+        self.file
+            .seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| self.file.write_all(data))
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BlockDevice, Bus, Console, Framebuffer, Random};
+    use crate::{
+        io::{BufferIo, IoHandle},
+        register::Register,
+        vm::{Engine, Vm},
+        CompiledProgram,
+    };
+
+    // memory ends at 0x100000.
+    const PROGRAM: &str = r"
+            CONSOLE     equ 0x100000
+            RANDOM      equ 0x100008
+            BLOCK       equ 0x100010
+            FRAMEBUFFER equ 0x100100
+                mov rc, CONSOLE
+                mov [rc], 'h'
+                mov [rc], 'i'
+                mov ra, [rc]
+                mov rc, RANDOM
+                mov rb, [rc]
+                mov rd, [rc]
+                mov rc, FRAMEBUFFER
+                mov [rc + 8], 0x0807060504030201
+                mov rc, BLOCK
+                mov re, [rc]
+                mov [rc + 8], re
+        ";
+
+    #[test]
+    fn can_map_devices() -> Result<(), String> {
+        let program = CompiledProgram::from(PROGRAM);
+        let mut results = vec![];
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let path = std::env::temp_dir().join(format!(
+                "opvm2_block_{:?}_{}",
+                engine,
+                std::process::id()
+            ));
+            std::fs::write(&path, b"opvm2!\0\0\0\0\0\0\0\0\0\0").map_err(|e| e.to_string())?;
+            let io = BufferIo::new("x");
+            let framebuffer = Framebuffer::new(4, 4);
+            let mut vm = Vm::new_e();
+            vm.engine = engine;
+            vm.set_io(io.clone());
+            let console = {
+                let context = vm.context.get().map_err(|e| e.to_string())?;
+                let io = context.lock().unwrap().io.clone();
+                Console::new(io)
+            };
+            vm.map_device(0x100000, console)?;
+            vm.map_device(0x100008, Random::new(7))?;
+            vm.map_device(0x100010, BlockDevice::open(&path)?)?;
+            vm.map_device(0x100100, framebuffer.clone())?;
+            vm.run(program.clone())?;
+
+            let block = std::fs::read(&path).map_err(|e| e.to_string())?;
+            std::fs::remove_file(&path).map_err(|e| e.to_string())?;
+            let context = vm.context.get().map_err(|e| e.to_string())?;
+            let registers = context.lock().unwrap().registers.clone();
+            results.push((io.output(), framebuffer.pixels(), block, registers));
+        }
+        assert_eq!(results[0], results[1]);
+        let (output, pixels, block, registers) = results.remove(0);
+        assert_eq!(output, "hi\n");
+        assert_eq!(registers.get(&Register::Ra), 'x' as usize);
+        let mut random = Random::new(7);
+        assert_eq!(registers.get(&Register::Rb), random.next() as usize);
+        assert_eq!(registers.get(&Register::Rd), random.next() as usize);
+        assert_eq!(pixels[8..], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(block, b"opvm2!\0\0opvm2!\0\0");
+        Ok(())
+    }
+
+    #[test]
+    fn can_report_device_errors() -> Result<(), String> {
+        let mut vm = Vm::new_e();
+        assert_eq!(
+            vm.map_device(0x1000, Random::new(1)),
+            Err("Device at 0x1000 overlaps memory, which ends at 0x100000.".to_string())
+        );
+        vm.map_device(0x100000, Random::new(1))?;
+        assert_eq!(
+            vm.map_device(0x100004, Framebuffer::new(2, 2)),
+            Err("Device at 0x100004 overlaps the device at 0x100000.".to_string())
+        );
+        // a word at the last byte of the device runs past it.
+        assert_eq!(
+            vm.run(CompiledProgram::from("mov rc, 0x100007\nmov ra, [rc]")),
+            Err("Read from 0x100007 is out of memory bounds.".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_access_no_bytes() -> Result<(), String> {
+        // the console only reads or writes a byte when asked for at least one.
+        let io = BufferIo::new("x");
+        let bus = Bus::default();
+        bus.map(0x100, Console::new(IoHandle::new(io.clone())))?;
+        assert_eq!(bus.read(0x100, 0), Ok(vec![]));
+        bus.write(0x100, &[])?;
+        assert_eq!(bus.read(0x100, 1), Ok(vec![b'x']));
+        assert_eq!(io.output(), "");
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use opvm2::register::Registers;
+
+/// How many interrupt vectors there are.
+pub const VECTORS: usize = 256;
+
+/// The vector the timer raises.
+pub const TIMER_VECTOR: u8 = 0;
+
+/// Raises interrupts from outside of the program, e.g. from another thread while it runs.
+///
+/// Every clone raises interrupts on the same machine.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptLine(Arc<Line>);
+
+#[derive(Debug, Default)]
+struct Line {
+    // set whenever `pending` isn't empty, so that checking for interrupts doesn't lock.
+    raised: AtomicBool,
+    pending: Mutex<VecDeque<u8>>,
+}
+
+impl InterruptLine {
+    /// Queues `vector`, to be handled once interrupts are enabled.
+    pub fn raise(&self, vector: u8) {
+        self.0.pending.lock().unwrap().push_back(vector);
+        self.0.raised.store(true, Ordering::Release);
+    }
+
+    fn take(&self) -> Option<u8> {
+        if !self.0.raised.load(Ordering::Acquire) {
+            return None;
+        }
+        let mut pending = self.0.pending.lock().unwrap();
+        let vector = pending.pop_front();
+        self.0.raised.store(!pending.is_empty(), Ordering::Release);
+        vector
+    }
+}
+
+impl PartialEq for InterruptLine {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Raises [`TIMER_VECTOR`] every `period` instructions.
+#[derive(Debug, Clone, PartialEq)]
+struct Timer {
+    period: usize,
+    remaining: usize,
+    // the timer went off while interrupts were disabled. it only goes off once until handled.
+    fired: bool,
+}
+
+// what an interrupt saves, so that `iret` can carry on where it left off.
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    return_address: usize,
+    enabled: bool,
+    flags: [bool; 4],
+}
+
+/// What each thread has of its own: whether it has interrupts enabled and the handlers it is
+/// in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HandlerState {
+    enabled: bool,
+    frames: Vec<Frame>,
+}
+
+impl HandlerState {
+    /// The state a thread started by this one begins with: outside of any handler, with
+    /// interrupts enabled if they are outside of the handlers this one is in.
+    pub fn spawned(&self) -> HandlerState {
+        HandlerState {
+            enabled: self
+                .frames
+                .first()
+                .map_or(self.enabled, |frame| frame.enabled),
+            frames: vec![],
+        }
+    }
+}
+
+/// The interrupt controller: the vector table, the timer and the handler state of the thread
+/// running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interrupts {
+    vectors: Vec<Option<usize>>,
+    timer: Option<Timer>,
+    pub state: HandlerState,
+    pub line: InterruptLine,
+}
+
+impl Interrupts {
+    pub fn new() -> Interrupts {
+        Interrupts {
+            vectors: vec![None; VECTORS],
+            timer: None,
+            state: HandlerState::default(),
+            line: InterruptLine::default(),
+        }
+    }
+
+    /// Clears the vector table and the timer, and disables interrupts. Interrupts raised on the
+    /// line stay pending until they are enabled again.
+    pub fn reset(&mut self) {
+        *self = Interrupts {
+            line: self.line.clone(),
+            ..Interrupts::new()
+        };
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.state.enabled = enabled;
+    }
+
+    /// Points `vector` at the handler at `address`.
+    pub fn set_vector(&mut self, vector: usize, address: usize) -> Result<(), String> {
+        match self.vectors.get_mut(vector) {
+            Some(entry) => {
+                *entry = Some(address);
+                Ok(())
+            }
+            None => Err(out_of_range(vector)),
+        }
+    }
+
+    /// Raises the timer interrupt every `period` instructions, or stops the timer if it's 0.
+    pub fn set_timer(&mut self, period: usize) {
+        self.timer = (period > 0).then_some(Timer {
+            period,
+            remaining: period,
+            fired: false,
+        });
+    }
+
+    /// Counts the instruction at `pc` towards the timer and, if an interrupt is due, enters
+    /// its handler instead, returning the address to run.
+    pub fn poll(&mut self, registers: &mut Registers, pc: usize) -> Result<usize, String> {
+        if let Some(timer) = &mut self.timer {
+            timer.remaining -= 1;
+            if timer.remaining == 0 {
+                timer.remaining = timer.period;
+                timer.fired = true;
+            }
+        }
+        if !self.state.enabled {
+            return Ok(pc);
+        }
+        let vector = match &mut self.timer {
+            Some(timer) if timer.fired => {
+                timer.fired = false;
+                TIMER_VECTOR
+            }
+            _ => match self.line.take() {
+                Some(vector) => vector,
+                None => return Ok(pc),
+            },
+        };
+        self.enter(registers, vector as usize, pc)
+    }
+
+    /// Enters the handler of `vector`, which returns to `return_address`, with interrupts
+    /// disabled until it does.
+    pub fn enter(
+        &mut self,
+        registers: &mut Registers,
+        vector: usize,
+        return_address: usize,
+    ) -> Result<usize, String> {
+        let Some(handler) = self
+            .vectors
+            .get(vector)
+            .ok_or_else(|| out_of_range(vector))?
+        else {
+            return Err(format!(
+                "Interrupt {} has no handler at ins {:#02X}.",
+                vector,
+                registers.check_pc()
+            ));
+        };
+        let handler = *handler;
+        self.state.frames.push(Frame {
+            return_address,
+            enabled: self.state.enabled,
+            flags: [
+                registers.check_equals_flag(),
+                registers.check_greater_than_flag(),
+                registers.check_less_than_flag(),
+                registers.check_zero_flag(),
+            ],
+        });
+        self.state.enabled = false;
+        Ok(handler)
+    }
+
+    /// Leaves the handler being run, restoring the flags and returning the address to go back
+    /// to.
+    pub fn ret(&mut self, registers: &mut Registers) -> Result<usize, String> {
+        let Some(frame) = self.state.frames.pop() else {
+            return Err(format!(
+                "iret outside of an interrupt handler at ins {:#02X}.",
+                registers.check_pc()
+            ));
+        };
+        let [equals, greater_than, less_than, zero] = frame.flags;
+        registers.set_equals_flag(equals);
+        registers.set_greater_than_flag(greater_than);
+        registers.set_less_than_flag(less_than);
+        registers.set_zero_flag(zero);
+        self.state.enabled = frame.enabled;
+        Ok(frame.return_address)
+    }
+}
+
+impl Default for Interrupts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn out_of_range(vector: usize) -> String {
+    format!(
+        "Interrupt {} is out of range, there are {} vectors.",
+        vector, VECTORS
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::InterruptLine;
+    use crate::{
+        io::BufferIo,
+        vm::{Engine, Vm},
+        CompiledProgram,
+    };
+
+    #[test]
+    fn can_raise_interrupts_from_the_host() -> Result<(), String> {
+        // raised before the program runs, handled in order once it enables interrupts.
+        let program = CompiledProgram::from(
+            r"
+                ivt 1, first
+                ivt 2, second
+                sti
+                nop
+                nop
+                jmp end
+            first:
+                print 1
+                iret
+            second:
+                print 2
+                iret
+            end:
+            ",
+        );
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let io = BufferIo::new("");
+            let mut vm = Vm::new_e();
+            vm.engine = engine;
+            vm.set_io(io.clone());
+            vm.raise_interrupt(1);
+            // clones are the same line, raising interrupts on the same machine.
+            assert_eq!(vm.interrupts.clone(), vm.interrupts);
+            assert_ne!(InterruptLine::default(), vm.interrupts);
+            vm.interrupts.clone().raise(2);
+            vm.run(program.clone())?;
+            assert_eq!(io.output(), "12\n");
+        }
+        Ok(())
+    }
+}
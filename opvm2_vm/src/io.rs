@@ -0,0 +1,267 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Write},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// The devices a program reads from and writes to.
+pub trait Io: Send {
+    fn stdout(&mut self) -> &mut dyn Write;
+    fn stderr(&mut self) -> &mut dyn Write;
+    fn stdin(&mut self) -> &mut dyn BufRead;
+}
+
+/// Uses the process' stdout, stderr and stdin.
+pub struct StdIo {
+    stdout: std::io::Stdout,
+    stderr: std::io::Stderr,
+    stdin: BufReader<std::io::Stdin>,
+}
+
+impl StdIo {
+    pub fn new() -> Self {
+        Self {
+            stdout: std::io::stdout(),
+            stderr: std::io::stderr(),
+            stdin: BufReader::new(std::io::stdin()),
+        }
+    }
+}
+
+impl Default for StdIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Io for StdIo {
+    fn stdout(&mut self) -> &mut dyn Write {
+        &mut self.stdout
+    }
+
+    fn stderr(&mut self) -> &mut dyn Write {
+        &mut self.stderr
+    }
+
+    fn stdin(&mut self) -> &mut dyn BufRead {
+        &mut self.stdin
+    }
+}
+
+/// A byte buffer that can be written to by the vm and read back by its owner.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps everything in memory: input is given up front, output can be read back
+/// from any clone of the device, e.g. after the program has run.
+#[derive(Debug, Clone, Default)]
+pub struct BufferIo {
+    stdout: SharedBuffer,
+    stderr: SharedBuffer,
+    stdin: Cursor<Vec<u8>>,
+}
+
+impl BufferIo {
+    pub fn new(input: &str) -> Self {
+        Self {
+            stdout: SharedBuffer::default(),
+            stderr: SharedBuffer::default(),
+            stdin: Cursor::new(input.as_bytes().to_vec()),
+        }
+    }
+
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.stdout.contents()).to_string()
+    }
+
+    pub fn error_output(&self) -> String {
+        String::from_utf8_lossy(&self.stderr.contents()).to_string()
+    }
+}
+
+impl Io for BufferIo {
+    fn stdout(&mut self) -> &mut dyn Write {
+        &mut self.stdout
+    }
+
+    fn stderr(&mut self) -> &mut dyn Write {
+        &mut self.stderr
+    }
+
+    fn stdin(&mut self) -> &mut dyn BufRead {
+        &mut self.stdin
+    }
+}
+
+/// Writes output to files and reads input from one.
+pub struct FileIo {
+    stdout: File,
+    stderr: File,
+    stdin: BufReader<File>,
+}
+
+impl FileIo {
+    /// Output files are created (or truncated), the input file must exist.
+    pub fn new(
+        stdout: impl AsRef<Path>,
+        stderr: impl AsRef<Path>,
+        stdin: impl AsRef<Path>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            stdout: File::create(stdout).map_err(|e| e.to_string())?,
+            stderr: File::create(stderr).map_err(|e| e.to_string())?,
+            stdin: BufReader::new(File::open(stdin).map_err(|e| e.to_string())?),
+        })
+    }
+}
+
+impl Io for FileIo {
+    fn stdout(&mut self) -> &mut dyn Write {
+        &mut self.stdout
+    }
+
+    fn stderr(&mut self) -> &mut dyn Write {
+        &mut self.stderr
+    }
+
+    fn stdin(&mut self) -> &mut dyn BufRead {
+        &mut self.stdin
+    }
+}
+
+/// The io device of a machine, shared by every clone of the context.
+#[derive(Clone)]
+pub struct IoHandle(Arc<Mutex<Box<dyn Io>>>);
+
+impl IoHandle {
+    pub fn new(io: impl Io + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(io))))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Box<dyn Io>> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn print(&self, data: &str) -> Result<(), String> {
+        self.write(data.as_bytes())
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<(), String> {
+        let mut io = self.lock();
+        let stdout = io.stdout();
+        stdout
+            .write_all(data)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn eprint(&self, data: &str) -> Result<(), String> {
+        let mut io = self.lock();
+        let stderr = io.stderr();
+        stderr
+            .write_all(data.as_bytes())
+            .and_then(|_| stderr.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Reads a single byte, `None` at the end of the input.
+    pub fn read_byte(&self) -> Result<Option<u8>, String> {
+        let mut byte = [0u8; 1];
+        let read = self
+            .lock()
+            .stdin()
+            .read(&mut byte)
+            .map_err(|e| e.to_string())?;
+        Ok(if read == 0 { None } else { Some(byte[0]) })
+    }
+
+    pub fn read_line(&self) -> Result<String, String> {
+        let mut input = String::new();
+        self.lock()
+            .stdin()
+            .read_line(&mut input)
+            .map_err(|e| e.to_string())?;
+        Ok(input)
+    }
+}
+
+impl Default for IoHandle {
+    fn default() -> Self {
+        Self::new(StdIo::new())
+    }
+}
+
+impl std::fmt::Debug for IoHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IoHandle")
+    }
+}
+
+impl PartialEq for IoHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opvm2::parser::program::Program;
+
+    use super::{BufferIo, FileIo};
+    use crate::vm::Vm;
+
+    #[test]
+    fn can_capture_output() -> Result<(), String> {
+        let io = BufferIo::new("");
+        let mut vm = Vm::new_e();
+        vm.set_io(io.clone());
+        vm.run_program(Program::from("mov ra, 5\nprint ra\nprint 10"))?;
+        assert_eq!(io.output(), "510\n");
+        assert_eq!(io.error_output(), "");
+        Ok(())
+    }
+
+    #[test]
+    fn can_read_input() -> Result<(), String> {
+        let mut vm = Vm::new_e();
+        vm.set_io(BufferIo::new("first\nsecond\n"));
+        let context = vm.context.get().map_err(|e| e.to_string())?;
+        let context = context.lock().unwrap();
+        assert_eq!(context.io.read_line()?, "first\n");
+        assert_eq!(context.io.read_line()?, "second\n");
+        assert_eq!(context.io.read_line()?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn can_write_output_to_files() -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("opvm2_file_io_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        std::fs::write(dir.join("stdin"), "").map_err(|e| e.to_string())?;
+        let io = FileIo::new(dir.join("stdout"), dir.join("stderr"), dir.join("stdin"))?;
+        let mut vm = Vm::new_e();
+        vm.set_io(io);
+        vm.run_program(Program::from("print 42"))?;
+        let output = std::fs::read_to_string(dir.join("stdout")).map_err(|e| e.to_string())?;
+        std::fs::remove_dir_all(&dir).map_err(|e| e.to_string())?;
+        assert_eq!(output, "42\n");
+        Ok(())
+    }
+}
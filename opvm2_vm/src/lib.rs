@@ -1,31 +1,49 @@
 //pub mod heap;
+pub mod convention;
+pub mod device;
+pub mod interrupt;
+pub mod io;
 pub mod machine_context;
 pub mod memory;
+pub mod native;
+pub mod object;
 pub mod plugin;
+pub mod registry;
+pub mod thread;
+pub mod threaded;
 pub mod vm;
 
 use std::collections::BTreeMap;
 
 use extism::{convert::Json, FromBytes, ToBytes, UserData};
-use instruction::Instruction;
 use machine_context::MachineContext;
 use memory::Memory;
-use opvm2::{opcode::Opcode, parser::program::Program, *};
-use parser::program::LabelValue;
+use object::Object;
+use opvm2::{
+    instruction::Instruction, opcode::Opcode, operand::Operand, parser::program::Program, *,
+};
+use parser::{
+    program::{LabelValue, Labels},
+    section::Section,
+};
 use plugin::PluginLoader;
+use registry::{PluginEntry, PluginRegistry};
 use serde::{Deserialize, Serialize};
 
+// sections (and the code) start on a 16 byte boundary.
+const SECTION_ALIGNMENT: usize = 16;
+
 #[derive(Debug, Deserialize, Serialize, ToBytes, FromBytes, PartialEq, Clone)]
 #[encoding(Json)]
 pub struct CompiledProgram {
     pub start_address: usize,
     pub memory_address: usize,
     pub program: Vec<u8>,
-    pub plugins: Vec<Vec<u8>>,
+    pub plugins: Vec<PluginEntry>,
 }
 
 impl CompiledProgram {
-    pub fn new(plugins: Vec<Vec<u8>>) -> Self {
+    pub fn new(plugins: Vec<PluginEntry>) -> Self {
         Self {
             start_address: 0,
             plugins,
@@ -43,13 +61,82 @@ impl CompiledProgram {
         }
     }
 
+    /// Records a plugin from the registry without embedding it; it is looked up
+    /// by name again when the program is run.
+    pub fn reference_plugin(
+        &mut self,
+        registry: &PluginRegistry,
+        name: &str,
+    ) -> Result<(), String> {
+        let (info, _) = registry.find(name)?;
+        self.plugins.push(PluginEntry { info, wasm: None });
+        Ok(())
+    }
+
     pub fn compile(&mut self, program: Program, verbose: bool) -> Result<Vec<u8>, String> {
+        self.compile_with(program, verbose, &[])
+    }
+
+    /// Compiles the program, treating `known_opcodes` as handled by plugins that are
+    /// loaded outside of the program (e.g. native plugins registered on the vm).
+    pub fn compile_with(
+        &mut self,
+        program: Program,
+        verbose: bool,
+        known_opcodes: &[String],
+    ) -> Result<Vec<u8>, String> {
+        let err_msg = self.load_plugins(&program, verbose, known_opcodes)?;
+        let (base, memory) = Self::remap(program)?;
+        self.program = memory.raw();
+        self.start_address = base;
+        self.memory_address = memory.address();
+        if !err_msg.is_empty() {
+            return Err(err_msg);
+        }
+        let bytes = (*self).to_bytes().map_err(|e| e.to_string())?;
+        Ok(bytes)
+    }
+
+    /// Compiles the program to a relocatable object, which [`object::link`] places along with
+    /// other objects. `name` is used in errors.
+    pub fn compile_object(
+        &mut self,
+        program: Program,
+        name: &str,
+        verbose: bool,
+    ) -> Result<Object, String> {
+        let err_msg = self.load_plugins(&program, verbose, &[])?;
+        if !err_msg.is_empty() {
+            return Err(err_msg);
+        }
+        Object::compile(program, name, self.plugins.clone())
+    }
+
+    // records the plugins of the program, returning the opcodes that no plugin handles.
+    fn load_plugins(
+        &mut self,
+        program: &Program,
+        verbose: bool,
+        known_opcodes: &[String],
+    ) -> Result<String, String> {
         let mut loader = PluginLoader::new(UserData::new(MachineContext::new()));
         loader.load_all(&program.plugins, verbose)?;
+        let referenced: Vec<PluginEntry> = self
+            .plugins
+            .drain(..)
+            .filter(|plugin| !plugin.is_embedded())
+            .collect();
+        let mut known_opcodes = known_opcodes.to_vec();
+        for plugin in referenced.iter() {
+            known_opcodes.extend(plugin.info.opcodes.iter().cloned());
+        }
         let mut err_msg = String::new();
         for ins in program.instructions.iter() {
             match ins.opcode {
                 Opcode::Plugin(ref name) => {
+                    if known_opcodes.contains(&name.to_string().to_lowercase()) {
+                        continue;
+                    }
                     // if there are no plugins, we can't handle the opcode
                     if loader.plugins.is_empty() {
                         err_msg = format!(
@@ -59,16 +146,7 @@ impl CompiledProgram {
                         );
                         continue;
                     }
-                    let mut found = false;
-                    for plugin in loader.plugins.iter_mut() {
-                        if plugin
-                            .function_exists(format!("handle_{}", name.to_string().to_lowercase()))
-                        {
-                            found = true;
-                            continue;
-                        }
-                    }
-                    if !found {
+                    if !loader.handles(&name.to_string()) {
                         err_msg = format!(
                             "{}No plugin found for handling opcode: `{}`. ",
                             err_msg,
@@ -79,19 +157,19 @@ impl CompiledProgram {
                 _ => {}
             }
         }
-        self.plugins = program.plugins.clone();
-        let (base, memory) = Self::remap(program);
-        self.program = memory.raw();
-        self.start_address = base;
-        self.memory_address = memory.address();
-        if !err_msg.is_empty() {
-            return Err(err_msg);
+        self.plugins = referenced;
+        for (info, wasm) in loader.metadata().iter().zip(program.plugins.iter()) {
+            self.plugins.push(PluginEntry {
+                info: info.clone(),
+                wasm: Some(wasm.clone()),
+            });
         }
-        let bytes = (*self).to_bytes().map_err(|e| e.to_string())?;
-        Ok(bytes)
+        Ok(err_msg)
     }
 
-    fn remap(program: Program) -> (usize, Memory) {
+    // memory is laid out as: literals, plugin names, .rodata, .data, .bss and finally the
+    // code, so that the program ends where the code ends.
+    fn remap(program: Program) -> Result<(usize, Memory), String> {
         let mut memory = Memory::new();
         // loop through program, get all literals/instructions, remap into existing memory space.
         let mut literal_list: BTreeMap<String, usize> = BTreeMap::new();
@@ -106,10 +184,11 @@ impl CompiledProgram {
                     let address = memory.push(value.as_bytes(), true);
                     literal_list.insert(label.clone(), address);
                 }
-                LabelValue::Address(address) => {
-                    // these addresses need to be relocated to the new memory space. start_address + (address * 128).
-                    literal_list.insert(label.clone(), *address * 16);
+                LabelValue::Constant(value) => {
+                    literal_list.insert(label.clone(), *value);
                 }
+                // placed once the sections and the code are laid out.
+                LabelValue::Data { .. } | LabelValue::Address(_) => {}
             }
         }
 
@@ -133,13 +212,113 @@ impl CompiledProgram {
             }
         }
 
-        let start_address = memory.address();
-        for instruction in program.instructions.iter() {
-            let encoded = instruction.encode(&literal_list);
-            memory.push(&Instruction::get_u8_array(encoded), false);
+        let mut section_addresses: BTreeMap<Section, usize> = BTreeMap::new();
+        for section in Section::DATA_SECTIONS {
+            memory.align(SECTION_ALIGNMENT);
+            let address = match section {
+                Section::Rodata => memory.reserve(program.sections.rodata.len())?,
+                Section::Data => memory.reserve(program.sections.data.len())?,
+                _ => memory.reserve(program.sections.bss)?,
+            };
+            section_addresses.insert(section, address);
+        }
+        memory.write(
+            section_addresses[&Section::Rodata],
+            &program.sections.rodata,
+        )?;
+        memory.write(section_addresses[&Section::Data], &program.sections.data)?;
+        for (label, value) in program.labels.list.iter() {
+            if let LabelValue::Data { section, offset } = value {
+                literal_list.insert(label.clone(), section_addresses[section] + offset);
+            }
         }
 
-        (start_address, memory)
+        let start_address = memory.align(SECTION_ALIGNMENT);
+        for encoded in Self::layout_code(&program.instructions, &program.labels, &mut literal_list)?
+        {
+            memory.push(&encoded, false);
+        }
+
+        Ok((start_address, memory))
+    }
+
+    // code labels are relative to the start of the code. instructions are variable length, and
+    // the length of an instruction can depend on where the labels end up, so the code is encoded
+    // again until the label addresses settle. lengths only ever grow (shorter instructions are
+    // padded), so this always ends.
+    fn layout_code(
+        instructions: &[Instruction],
+        labels: &Labels,
+        literal_list: &mut BTreeMap<String, usize>,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        let code_labels: Vec<&str> = labels
+            .list
+            .iter()
+            .filter(|(_, value)| matches!(value, LabelValue::Address(_)))
+            .map(|(label, _)| label.as_str())
+            .collect();
+        let mut lengths = vec![0; instructions.len()];
+        loop {
+            let mut offsets = vec![0];
+            for length in lengths.iter() {
+                offsets.push(offsets[offsets.len() - 1] + length);
+            }
+            for (label, value) in labels.list.iter() {
+                if let LabelValue::Address(index) = value {
+                    // labels can point past the end of the code.
+                    let address = offsets.get(*index).unwrap_or(&offsets[lengths.len()]);
+                    literal_list.insert(label.clone(), *address);
+                }
+            }
+
+            let code = instructions
+                .iter()
+                .zip(lengths.iter())
+                .zip(offsets.iter())
+                .map(|((instruction, length), offset)| {
+                    let next = offset + length;
+                    Self::relative(instruction, literal_list, &code_labels, next)?
+                        .encode_padded(literal_list, *length)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let settled = code
+                .iter()
+                .zip(lengths.iter())
+                .all(|(encoded, length)| encoded.len() == *length);
+            if settled {
+                return Ok(code);
+            }
+            lengths = code.iter().map(|encoded| encoded.len()).collect();
+        }
+    }
+
+    // branches, and `lea` and `ivt` of code labels, refer to them relative to `next`, the end
+    // of the instruction, so that the code can be loaded anywhere. literals and data don't move
+    // with the code, so `lea` of them keeps their absolute address, like every other operand
+    // that refers to them.
+    fn relative(
+        instruction: &Instruction,
+        literal_list: &BTreeMap<String, usize>,
+        code_labels: &[&str],
+        next: usize,
+    ) -> Result<Instruction, String> {
+        let mut instruction = instruction.clone();
+        let operand = match instruction.opcode {
+            ref opcode if opcode.takes_address() => &mut instruction.rhs,
+            ref opcode if opcode.is_branch() => &mut instruction.lhs,
+            _ => return Ok(instruction),
+        };
+        let in_code = match &*operand {
+            Operand::Label(LabelValue::Literal(name)) => code_labels.contains(&name.as_str()),
+            Operand::Expression(expr) => expr.names().iter().any(|n| code_labels.contains(n)),
+            _ => return Ok(instruction),
+        };
+        if !in_code && !instruction.opcode.is_branch() {
+            return Ok(instruction);
+        }
+        let target = operand.encode(literal_list)? as usize;
+        *operand = Operand::Relative(target.wrapping_sub(next) as isize);
+        Ok(instruction)
     }
 }
 
@@ -178,9 +357,14 @@ impl From<&str> for CompiledProgram {
 
 #[cfg(test)]
 mod test {
-    use opvm2::register::Registers;
+    use opvm2::{register::Registers, stack::Stack};
 
-    use crate::{parser::program::Program, vm::Vm};
+    use crate::{
+        io::BufferIo,
+        parser::program::Program,
+        vm::{Engine, Vm},
+        CompiledProgram,
+    };
 
     fn read_registers(vm: &Vm) -> Registers {
         let context = vm.context.get().unwrap();
@@ -188,79 +372,108 @@ mod test {
         context.registers.clone()
     }
 
+    // runs the program with both engines, which have to end up in the same state.
+    fn run_with_input(input: &str, program: &str) -> Result<(Vm, String), String> {
+        let threaded_io = BufferIo::new(input);
+        let mut threaded = super::vm::Vm::new_e();
+        threaded.engine = Engine::Threaded;
+        threaded.set_io(threaded_io.clone());
+        let threaded_result = threaded.run_program(Program::from(program));
+
+        let io = BufferIo::new(input);
+        let mut vm = super::vm::Vm::new_e();
+        vm.set_io(io.clone());
+        let result = vm.run_program(Program::from(program));
+        assert_eq!(result, threaded_result);
+        assert_eq!(io.output(), threaded_io.output());
+        assert_eq!(read_state(&vm), read_state(&threaded));
+        result?;
+        Ok((vm, io.output()))
+    }
+
+    fn read_state(vm: &Vm) -> (Registers, Stack<usize>, Stack<usize>, Vec<u8>) {
+        let context = vm.context.get().unwrap();
+        let context = context.lock().unwrap();
+        (
+            context.registers.clone(),
+            context.stack.clone(),
+            context.call_stack.clone(),
+            context.memory.raw(),
+        )
+    }
+
     #[test]
     fn can_add_two_numbers() -> Result<(), String> {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 1
             mov rb, 2
             add ra, rb
         ",
-        );
-        vm.run_program(program)?;
+        )?;
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 3);
         Ok(())
     }
 
     #[test]
     fn can_inc() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 1
             inc ra
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 2);
     }
 
     #[test]
     fn can_dec() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 1
             dec ra
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 0);
     }
 
     #[test]
     fn can_xor_two_numbers() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 3
             mov rb, 5
             xor ra, rb
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 6);
     }
 
     #[test]
     fn can_push_and_pop() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 1
             push ra
             pop rb
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rb), 1);
     }
 
     #[test]
     fn can_dup_stack() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 5
             push ra
@@ -268,25 +481,113 @@ mod test {
             pop rb
             pop rc
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rb), 5);
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 5);
     }
 
+    #[test]
+    fn can_shuffle_the_stack() -> Result<(), String> {
+        let (vm, output) = run_with_input(
+            "",
+            r"
+            push 1
+            push 2
+            push 3
+            rot         ; 2 3 1
+            swap        ; 2 1 3
+            over        ; 2 1 3 1
+            pick 3      ; 2 1 3 1 2
+            depth ra
+            drop        ; 2 1 3 1
+            print ra
+            pop rb
+            print rb
+            pop rb
+            print rb
+            pop rb
+            print rb
+            pop rb
+            print rb
+        ",
+        )?;
+        assert_eq!(output, "51312\n");
+        let context = vm.context.get().unwrap();
+        assert!(context.lock().unwrap().stack.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn can_save_registers() -> Result<(), String> {
+        let (vm, _) = run_with_input(
+            "",
+            r"
+            mov ra, 1
+            mov r9, 2
+            pushall
+            mov ra, 3
+            mov r9, 4
+            depth rb
+            popall
+        ",
+        )?;
+        let registers = read_registers(&vm);
+        assert_eq!(registers.get(&crate::register::Register::Ra), 1);
+        assert_eq!(registers.get(&crate::register::Register::R9), 2);
+        // restored to what it was before `depth`.
+        assert_eq!(registers.get(&crate::register::Register::Rb), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn can_report_stack_underflow() {
+        let start_address = CompiledProgram::from("dup").start_address;
+        let general = crate::register::Register::general().count();
+        for (program, message) in [
+            (
+                "dup",
+                "`dup` needs 1 value, but the stack holds 0.".to_string(),
+            ),
+            (
+                "pop ra",
+                "`pop` needs 1 value, but the stack holds 0.".to_string(),
+            ),
+            (
+                "swap",
+                "`swap` needs 2 values, but the stack holds 0.".to_string(),
+            ),
+            (
+                "pick 4",
+                "`pick` needs 5 values, but the stack holds 0.".to_string(),
+            ),
+            (
+                "popall",
+                format!("`popall` needs {} values, but the stack holds 0.", general),
+            ),
+        ] {
+            assert_eq!(
+                run_with_input("", program).err(),
+                Some(format!(
+                    "Stack underflow at ins {:#02X}: {}",
+                    start_address, message
+                ))
+            );
+        }
+    }
+
     #[test]
     fn can_jump() -> Result<(), String> {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
-            mov r0, 64
+            lea r0, target
             jmp r0
             mov ra, 2   ; this should be skipped
             mov rb, 3   ; this should be skipped
-            mov rc, 5
+            target: mov rc, 5
         ",
-        );
-        vm.run_program(program)?;
+        )?;
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 0);
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rb), 0);
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 5);
@@ -295,16 +596,15 @@ mod test {
 
     #[test]
     fn can_jump_with_labels() -> Result<(), String> {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             jmp _start
             mov ra, 2   ; this should be skipped
             mov rb, 3   ; this should be skipped
             _start: mov rc, 5
         ",
-        );
-        vm.run_program(program)?;
+        )?;
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 0);
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rb), 0);
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 5);
@@ -313,8 +613,8 @@ mod test {
 
     #[test]
     fn can_jump_when_less_than() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 2
             mov rb, 3
@@ -325,15 +625,15 @@ mod test {
             _less_than: mov rc, 10
             _exit:
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 10);
     }
 
     #[test]
     fn can_jump_when_less_than_or_equal() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 3
             mov rb, 3
@@ -344,15 +644,15 @@ mod test {
             _less_than: mov rc, 10
             _exit:
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 10);
     }
 
     #[test]
     fn can_jump_when_greater_than() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 4
             mov rb, 3
@@ -363,15 +663,15 @@ mod test {
             _greater_than: mov rc, 10
             _exit:
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 10);
     }
 
     #[test]
     fn can_jump_when_greater_than_or_equal() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 3
             mov rb, 3
@@ -382,15 +682,15 @@ mod test {
             _greater_than: mov rc, 10
             _exit:
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 10);
     }
 
     #[test]
     fn can_jump_when_equal() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 3
             mov rb, 3
@@ -401,15 +701,15 @@ mod test {
             _equal: mov rc, 10
             _exit:
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 10);
     }
 
     #[test]
     fn can_jump_when_not_equal() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             mov ra, 3
             mov rb, 4
@@ -420,15 +720,15 @@ mod test {
             _not_equal: mov rc, 10
             _exit:
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 10);
     }
 
     #[test]
     fn can_call_and_return() {
-        let mut vm = super::vm::Vm::new_e();
-        let program = Program::from(
+        let (vm, _) = run_with_input(
+            "",
             r"
             call add
             jmp exit
@@ -440,10 +740,605 @@ mod test {
             exit:
             mov rd, 6
         ",
-        );
-        vm.run_program(program).unwrap();
+        )
+        .unwrap();
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 7);
         assert_eq!(read_registers(&vm).get(&crate::register::Register::Rd), 6);
         assert_ne!(read_registers(&vm).get(&crate::register::Register::Rc), 5);
     }
+
+    #[test]
+    fn can_call_registers_and_drop_arguments() -> Result<(), String> {
+        let (vm, output) = run_with_input(
+            "",
+            r"
+                lea r6, double
+                sub sp, 16
+                mov [sp], 20
+                mov [sp + 8], 1
+                call r6
+                print ra
+                jmp end
+            double:
+                mov ra, [sp]
+                add ra, [sp + 8]
+                mul ra, 2
+                ret 16
+            end:
+        ",
+        )?;
+        assert_eq!(output, "42\n");
+        let context = vm.context.get().unwrap();
+        let top = context.lock().unwrap().memory.size();
+        assert_eq!(read_registers(&vm).get(&crate::register::Register::Sp), top);
+        Ok(())
+    }
+
+    #[test]
+    fn can_report_call_stack_errors() {
+        let start_address = CompiledProgram::from("ret").start_address;
+        assert_eq!(
+            run_with_input("", "ret").err(),
+            Some(format!(
+                "ret with an empty call stack at ins {:#02X}.",
+                start_address
+            ))
+        );
+        assert_eq!(
+            run_with_input("", "recurse: call recurse").err(),
+            Some(format!(
+                "Call stack overflow at ins {:#02X}, calls can only go {} deep.",
+                start_address,
+                crate::convention::MAX_CALL_DEPTH
+            ))
+        );
+    }
+
+    #[test]
+    fn can_use_stack_frames() -> Result<(), String> {
+        let (vm, output) = run_with_input(
+            "",
+            r"
+                mov ra, 5
+                sub sp, 8
+                mov [sp], ra
+                call square
+                add sp, 8
+                print ra
+                jmp end
+            square:
+                enter 16
+                mov rb, [bp + 8]
+                mov [bp - 8], rb
+                mul rb, [bp - 8]
+                mov [bp-16], rb
+                lea rc, [bp - 16]
+                mov ra, [rc]
+                leave
+                ret
+            end:
+        ",
+        )?;
+        assert_eq!(output, "25\n");
+        let registers = read_registers(&vm);
+        let context = vm.context.get().unwrap();
+        let top = context.lock().unwrap().memory.size();
+        assert_eq!(registers.get(&crate::register::Register::Sp), top);
+        assert_eq!(registers.get(&crate::register::Register::Bp), top);
+        // the local at `[bp - 16]`, below the saved bp and the local at `[bp - 8]`.
+        assert_eq!(registers.get(&crate::register::Register::Rc), top - 32);
+        Ok(())
+    }
+
+    #[test]
+    fn can_report_stack_errors() {
+        let start_address = CompiledProgram::from("leave").start_address;
+        assert_eq!(
+            run_with_input("", "leave").err(),
+            Some(format!(
+                "leave without a frame at ins {:#02X}.",
+                start_address
+            ))
+        );
+        assert_eq!(
+            run_with_input("", "enter 0x100000").err(),
+            Some(format!("Stack overflow at ins {:#02X}.", start_address))
+        );
+    }
+
+    #[test]
+    fn can_handle_interrupts() -> Result<(), String> {
+        // the handler changes the flags, which `iret` restores before `je` reads them.
+        let (_, output) = run_with_input(
+            "",
+            r"
+                ivt 3, handler
+                mov ra, 1
+                test ra, 1
+                int 3
+                je equal
+                hlt
+            equal:
+                print rb
+                jmp end
+            handler:
+                mov rb, 7
+                test rb, 0
+                iret
+            end:
+        ",
+        )?;
+        assert_eq!(output, "7\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_run_a_timer() -> Result<(), String> {
+        // the handler turns the timer off once it has gone off 4 times, which ends the loop.
+        let (vm, output) = run_with_input(
+            "",
+            r"
+                ivt 0, tick
+                timer 10
+                sti
+            spin:
+                inc rb
+                test ra, 4
+                jne spin
+                print ra
+                jmp end
+            tick:
+                inc ra
+                test ra, 4
+                jne done
+                timer 0
+            done:
+                iret
+            end:
+        ",
+        )?;
+        assert_eq!(output, "4\n");
+        assert!(read_registers(&vm).get(&crate::register::Register::Rb) > 1);
+        // interrupts stay disabled until `sti`.
+        let (_, output) = run_with_input(
+            "",
+            r"
+                ivt 0, tick
+                timer 1
+                nop
+                nop
+                jmp end
+            tick:
+                print 1
+                iret
+            end:
+        ",
+        )?;
+        assert_eq!(output, "\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_report_interrupt_errors() {
+        let start_address = CompiledProgram::from("int 5").start_address;
+        assert_eq!(
+            run_with_input("", "int 5").err(),
+            Some(format!(
+                "Interrupt 5 has no handler at ins {:#02X}.",
+                start_address
+            ))
+        );
+        assert_eq!(
+            run_with_input("", "iret").err(),
+            Some(format!(
+                "iret outside of an interrupt handler at ins {:#02X}.",
+                start_address
+            ))
+        );
+        assert_eq!(
+            run_with_input("", "ivt 256, 0").err(),
+            Some("Interrupt 256 is out of range, there are 256 vectors.".to_string())
+        );
+    }
+
+    #[test]
+    fn can_do_float_math() -> Result<(), String> {
+        let (vm, output) = run_with_input(
+            "",
+            r"
+            mov f0, 1.5
+            fadd f0, 2.5        ; 4
+            mov ra, 3
+            itof f1, ra
+            fmul f0, f1         ; 12
+            fsub f0, -0.5       ; 12.5
+            fdiv f0, 2.0        ; 6.25
+            fsqrt f2, f0        ; 2.5
+            printf f0
+            putc ' '
+            printf f2, 3
+            putc ' '
+            ftoi rb, f0
+            print rb
+            mov f3, -1.75
+            ftoi rc, f3
+            fcmp f2, 2.5
+            je equal
+            hlt
+        equal:
+            fcmp f3, f2
+            jge wrong
+            push f3
+            pop f4
+            fsqrt f4
+        wrong:
+        ",
+        )?;
+        assert_eq!(output, "6.25 2.500 6\n");
+        let registers = read_registers(&vm);
+        assert_eq!(
+            registers.get(&crate::register::Register::Rc),
+            -1isize as usize
+        );
+        assert_eq!(registers.get_float(&crate::register::Register::F0), 6.25);
+        assert!(registers.get_float(&crate::register::Register::F4).is_nan());
+        Ok(())
+    }
+
+    #[test]
+    fn can_print_characters() -> Result<(), String> {
+        let (_, output) = run_with_input(
+            "",
+            r"
+            putc 72
+            mov ra, 105
+            putc ra
+        ",
+        )?;
+        assert_eq!(output, "Hi\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_print_strings() -> Result<(), String> {
+        let (_, output) = run_with_input(
+            "",
+            r"
+            text: 'Hello, World!'
+            puts text
+        ",
+        )?;
+        assert_eq!(output, "Hello, World!\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_print_hex() -> Result<(), String> {
+        let (_, output) = run_with_input("", "printx 255\nputc 32\nprintx 4096")?;
+        assert_eq!(output, "ff 1000\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_read_characters() -> Result<(), String> {
+        let (vm, _) = run_with_input("ab", "getc ra\ngetc rb\ngetc rc")?;
+        let registers = read_registers(&vm);
+        assert_eq!(registers.get(&crate::register::Register::Ra), 97);
+        assert_eq!(registers.get(&crate::register::Register::Rb), 98);
+        assert_eq!(registers.get(&crate::register::Register::Rc), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn can_read_lines() -> Result<(), String> {
+        let (_, output) = run_with_input(
+            "hello\nworld\n",
+            r"
+            buf: '..........'
+            readln buf, 4
+            puts buf
+            putc 32
+            readln buf, 10
+            puts buf
+        ",
+        )?;
+        assert_eq!(output, "hel world\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_run_macros() -> Result<(), String> {
+        let (vm, output) = run_with_input(
+            "",
+            r"
+            %define COUNT 3
+            %macro countdown reg, from
+                mov reg, from
+            %%loop: print reg
+                dec reg
+                test reg, 0
+                jnz %%loop
+            %endmacro
+            countdown ra, COUNT
+            countdown rb, 2
+        ",
+        )?;
+        assert_eq!(output, "32121\n");
+        assert_eq!(read_registers(&vm).get(&crate::register::Register::Rb), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn can_lay_out_sections() -> Result<(), String> {
+        let (_, output) = run_with_input(
+            "typed\n",
+            r"
+            section .bss
+            buffer: resb 16
+            section .data
+            prompt: 'you '
+            section .rodata
+            said: 'said: '
+            section .text
+            puts prompt
+            puts said
+            readln buffer, 16
+            puts buffer
+        ",
+        )?;
+        assert_eq!(output, "you said: typed\n");
+
+        let compiled = super::CompiledProgram::from(
+            "section .data\nvalue: 'x'\nsection .bss\nbuffer: resb 100\nsection .text\nnop",
+        );
+        assert_eq!(compiled.start_address % 16, 0);
+        assert_eq!(compiled.start_address, 128);
+        Ok(())
+    }
+
+    #[test]
+    fn can_define_data() -> Result<(), String> {
+        let (vm, output) = run_with_input(
+            "",
+            r"
+            LENGTH equ 4
+            section .rodata
+            first: db 'a\tb', 0
+            second: db 'c', 0x41, 10, 0
+            greeting: 'hi\x21'
+            section .data
+            buffer: times LENGTH db '-'
+            terminator: db 0
+            section .text
+            puts second
+            puts first
+            puts buffer
+            puts greeting
+            mov ra, LENGTH
+        ",
+        )?;
+        assert_eq!(output, "cA\na\tb----hi!\n");
+        assert_eq!(read_registers(&vm).get(&crate::register::Register::Ra), 4);
+
+        // numeric literals and data defined in .text are placed in .data.
+        let (_, output) = run_with_input(
+            "",
+            r"
+            x: 5
+            table: dq 1, 2, 3, 5, 8
+            msg: 'a\xff'
+            buf: times 64 db 0
+            lea rb, x
+            mov ra, [rb]
+            print ra
+            lea rc, table
+            print [rc+24]
+            lea rd, buf
+            print [rd]
+            lea re, msg
+            print [re]
+        ",
+        )?;
+        // `msg` is followed by the zeroes of `buf`.
+        assert_eq!(output, format!("550{}\n", 0xff61));
+        Ok(())
+    }
+
+    #[test]
+    fn can_evaluate_constant_expressions() -> Result<(), String> {
+        let (vm, _) = run_with_input(
+            "",
+            r"
+            COUNT equ 3
+            section .rodata
+            start: db 1, 2, 3, 4, 5
+            end: db 0
+            section .text
+                  mov ra, COUNT * 4 + 1
+                  mov rb, end - start
+                  mov rc, 'A'
+                  mov rd, (1 << 4) | 0x1
+                  jmp skip + 4
+            skip: mov re, 1
+                  mov rf, ','
+        ",
+        )?;
+        let registers = read_registers(&vm);
+        assert_eq!(registers.get(&crate::register::Register::Ra), 13);
+        assert_eq!(registers.get(&crate::register::Register::Rb), 5);
+        assert_eq!(registers.get(&crate::register::Register::Rc), 65);
+        assert_eq!(registers.get(&crate::register::Register::Rd), 17);
+        assert_eq!(registers.get(&crate::register::Register::Re), 0);
+        assert_eq!(registers.get(&crate::register::Register::Rf), 44);
+
+        let mut vm = super::vm::Vm::new_e();
+        assert_eq!(
+            vm.run_program(Program::from("mov ra, missing + 1")),
+            Err("`missing` is not a constant or label".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_use_number_literals() -> Result<(), String> {
+        let (vm, _) = run_with_input(
+            "",
+            r"
+            mov ra, 0b1010
+            mov rb, 0o755
+            mov rc, 1_000_000
+            mov rd, 0xFFu8
+            mov re, 0xFFFF_FFFF
+        ",
+        )?;
+        let registers = read_registers(&vm);
+        assert_eq!(registers.get(&crate::register::Register::Ra), 10);
+        assert_eq!(registers.get(&crate::register::Register::Rb), 493);
+        assert_eq!(registers.get(&crate::register::Register::Rc), 1_000_000);
+        assert_eq!(registers.get(&crate::register::Register::Rd), 255);
+        assert_eq!(registers.get(&crate::register::Register::Re), 0xFFFF_FFFF);
+
+        assert_eq!(
+            Program::parse("mov ra, 0x1_0000_0000_0000_0000"),
+            Err("Value `0x1_0000_0000_0000_0000` doesn't fit in a 64-bit operand".to_string())
+        );
+        assert_eq!(
+            Program::parse("mov ra, 256u8"),
+            Err("Number `256u8` doesn't fit in 8 bits".to_string())
+        );
+        let mut vm = super::vm::Vm::new_e();
+        assert_eq!(
+            vm.run_program(Program::from(
+                "BIG equ 0x8000_0000_0000_0000\nmov ra, BIG * 2"
+            )),
+            Err("Value `18446744073709551616` doesn't fit in a 64-bit operand".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_use_64_bit_immediates() -> Result<(), String> {
+        let (vm, output) = run_with_input(
+            "",
+            r"
+                  mov ra, 0xFFFF_FFFF
+                  mov rb, 0x1_0000_0000
+                  call wide
+                  jmp end
+            wide: mov rc, 0xDEAD_BEEF_CAFE_F00D
+                  mov rd, -1
+                  ret
+                  mov ra, 0
+            end:  printx rb
+        ",
+        )?;
+        let registers = read_registers(&vm);
+        assert_eq!(registers.get(&crate::register::Register::Ra), 0xFFFF_FFFF);
+        assert_eq!(registers.get(&crate::register::Register::Rb), 0x1_0000_0000);
+        assert_eq!(
+            registers.get(&crate::register::Register::Rc),
+            0xDEAD_BEEF_CAFE_F00D
+        );
+        assert_eq!(registers.get(&crate::register::Register::Rd), usize::MAX);
+        assert_eq!(output, "100000000\n");
+
+        // wide operands make instructions longer, which moves the labels after them.
+        let program = r"
+                  mov ra, 0x1_0000_0000
+                  mov rb, 1
+            end:  mov rc, end
+        ";
+        let mut compiled = CompiledProgram::new_e();
+        compiled.compile(Program::from(program), false)?;
+        let (vm, _) = run_with_input("", program)?;
+        assert_eq!(read_registers(&vm).get(&crate::register::Register::Rc), 12);
+        assert_eq!(compiled.memory_address - compiled.start_address, 16);
+        Ok(())
+    }
+
+    #[test]
+    fn can_jump_over_long_code() -> Result<(), String> {
+        // `end` is past 127 bytes, so the jump to it needs a longer operand.
+        let program = format!("jmp end\n{}end: mov rb, end", "mov ra, 1\n".repeat(40));
+        let (vm, _) = run_with_input("", &program)?;
+        let registers = read_registers(&vm);
+        assert_eq!(registers.get(&crate::register::Register::Ra), 0);
+        assert_eq!(registers.get(&crate::register::Register::Rb), 4 + 40 * 4);
+        Ok(())
+    }
+
+    #[test]
+    fn can_load_addresses() -> Result<(), String> {
+        let program = CompiledProgram::from(
+            r"
+            message: 'Hello'
+                  lea ra, message
+                  lea rb, greet
+                  call rb
+                  jmp end
+            greet: puts ra
+                  ret
+            end:
+        ",
+        );
+        let start_address = program.start_address;
+        let io = BufferIo::new("");
+        let mut vm = Vm::new_e();
+        vm.set_io(io.clone());
+        vm.run(program)?;
+        let registers = read_registers(&vm);
+        assert_eq!(registers.get(&crate::register::Register::Ra), 0);
+        // the address of `greet`, not its offset from the start of the code.
+        assert!(registers.get(&crate::register::Register::Rb) > start_address);
+        assert_eq!(io.output(), "Hello\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_relocate_code() -> Result<(), String> {
+        let program = CompiledProgram::from(
+            r"
+            COUNT equ 3
+            message: 'hi'
+                  mov ra, 0
+            loop: call print
+                  inc ra
+                  test ra, COUNT
+                  jl loop
+                  lea rd, message
+                  puts rd
+                  lea rb, done
+                  jmp rb
+                  mov rc, 1
+            done: jmp end
+            print: puts message
+                  ret
+            end:
+        ",
+        );
+        // move the code 48 bytes further, without changing any of it.
+        let mut moved = program.clone();
+        let code = moved.program.split_off(program.start_address);
+        moved.program.extend(vec![0; 48]);
+        moved.program.extend(code);
+        moved.start_address += 48;
+        moved.memory_address += 48;
+
+        for (program, engine) in [
+            (program.clone(), Engine::Interpreter),
+            (moved.clone(), Engine::Interpreter),
+            (moved, Engine::Threaded),
+        ] {
+            let io = BufferIo::new("");
+            let mut vm = Vm::new_e();
+            vm.engine = engine;
+            vm.set_io(io.clone());
+            vm.run(program)?;
+            let registers = read_registers(&vm);
+            assert_eq!(registers.get(&crate::register::Register::Ra), 3);
+            assert_eq!(registers.get(&crate::register::Register::Rc), 0);
+            assert_eq!(io.output(), "hihihihi\n");
+        }
+        Ok(())
+    }
 }
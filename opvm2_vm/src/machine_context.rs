@@ -1,8 +1,18 @@
 use extism::{convert::Json, *};
-use opvm2::{register::Registers, stack::Stack};
+use opvm2::{
+    opcode::Opcode,
+    register::{Register, Registers},
+    stack::Stack,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::memory::Memory;
+use crate::{
+    convention::{Checker, MAX_CALL_DEPTH, RETURN_VALUE},
+    interrupt::Interrupts,
+    io::IoHandle,
+    memory::Memory,
+    thread::{Saved, State, Threads, MAIN, STACK_SIZE},
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToBytes, FromBytes)]
 #[encoding(Json)]
@@ -12,6 +22,16 @@ pub struct MachineContext {
     pub stack: Stack<usize>,
     pub call_stack: Stack<usize>,
     pub memory: Memory,
+    #[serde(skip)]
+    pub io: IoHandle,
+    /// Checks calls follow the calling convention, if set.
+    #[serde(skip)]
+    pub checker: Option<Checker>,
+    #[serde(skip)]
+    pub interrupts: Interrupts,
+    /// The threads other than the one running, see [`crate::thread`].
+    #[serde(skip)]
+    pub threads: Threads,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToBytes, FromBytes)]
@@ -29,6 +49,351 @@ impl MachineContext {
             call_stack: Stack::new(),
             memory: Memory::new(),
             base_address: 0,
+            io: IoHandle::default(),
+            checker: None,
+            interrupts: Interrupts::new(),
+            threads: Threads::new(),
         }
     }
+
+    /// Points `sp` and `bp` at the top of memory, where the stack grows down from.
+    pub fn reset_stack(&mut self) {
+        let top = self.memory.size();
+        self.registers.set(&Register::Sp, top);
+        self.registers.set(&Register::Bp, top);
+    }
+
+    /// Reads the word at `address`.
+    pub fn load(&self, address: usize) -> Result<usize, String> {
+        let bytes = self.memory.read(address, WORD)?;
+        Ok(usize::from_le_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    /// Writes `value` as a word at `address`.
+    pub fn store(&mut self, address: usize, value: usize) -> Result<(), String> {
+        self.memory.write(address, &value.to_le_bytes())
+    }
+
+    /// Pops the top of the stack for `opcode`.
+    pub fn pop(&mut self, opcode: &Opcode) -> Result<usize, String> {
+        self.check_stack(opcode, 1)?;
+        Ok(self.stack.pop().unwrap())
+    }
+
+    /// Runs the stack instructions that don't read or write registers.
+    pub fn stack_op(&mut self, opcode: &Opcode, operand: Option<usize>) -> Result<(), String> {
+        match opcode {
+            Opcode::Dup | Opcode::Over | Opcode::Pick => {
+                let depth = match opcode {
+                    Opcode::Dup => 0,
+                    Opcode::Over => 1,
+                    _ => operand.expect("lhs is none"),
+                };
+                self.check_stack(opcode, depth.saturating_add(1))?;
+                let value = *self.stack.pick(depth).unwrap();
+                self.stack.push(value);
+            }
+            Opcode::Swap | Opcode::Rot => {
+                let depth = match opcode {
+                    Opcode::Swap => 1,
+                    _ => 2,
+                };
+                self.check_stack(opcode, depth + 1)?;
+                self.stack.roll(depth).unwrap();
+            }
+            Opcode::Drop => {
+                self.pop(opcode)?;
+            }
+            Opcode::Pushall => {
+                for register in Register::general() {
+                    self.stack.push(self.registers.get(&register));
+                }
+            }
+            Opcode::Popall => {
+                let registers: Vec<Register> = Register::general().collect();
+                self.check_stack(opcode, registers.len())?;
+                for register in registers.iter().rev() {
+                    let value = self.stack.pop().unwrap();
+                    self.registers.set(register, value);
+                }
+            }
+            _ => panic!("{} is not a stack instruction", opcode),
+        }
+        Ok(())
+    }
+
+    // fails unless the stack holds at least `count` values.
+    fn check_stack(&self, opcode: &Opcode, count: usize) -> Result<(), String> {
+        if self.stack.len() >= count {
+            return Ok(());
+        }
+        Err(format!(
+            "Stack underflow at ins {:#02X}: `{}` needs {} value{}, but the stack holds {}.",
+            self.registers.check_pc(),
+            opcode,
+            count,
+            if count == 1 { "" } else { "s" },
+            self.stack.len()
+        ))
+    }
+
+    /// Sets the flags from comparing two floats, like `test` does for integers. Nothing is
+    /// equal to, less or greater than NaN.
+    pub fn compare_floats(&mut self, lhs: f64, rhs: f64) {
+        self.registers.reset_flags();
+        self.registers.set_equals_flag(lhs == rhs);
+        self.registers.set_less_than_flag(lhs < rhs);
+        self.registers.set_greater_than_flag(lhs > rhs);
+        self.registers.set_zero_flag(lhs == 0.0 && rhs == 0.0);
+    }
+
+    /// Pushes the address a call returns to.
+    pub fn call(&mut self, return_address: usize) -> Result<(), String> {
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            return Err(format!(
+                "Call stack overflow at ins {:#02X}, calls can only go {} deep.",
+                self.registers.check_pc(),
+                MAX_CALL_DEPTH
+            ));
+        }
+        if let Some(checker) = &mut self.checker {
+            checker.call(&self.registers);
+        }
+        self.call_stack.push(return_address);
+        Ok(())
+    }
+
+    /// Pops the address to return to, dropping `arguments` bytes of arguments off the stack.
+    pub fn ret(&mut self, arguments: usize) -> Result<usize, String> {
+        let Some(return_address) = self.call_stack.pop() else {
+            // a thread returning from where it started finishes.
+            if self.threads.current() != MAIN {
+                return self.exit_thread();
+            }
+            return Err(format!(
+                "ret with an empty call stack at ins {:#02X}.",
+                self.registers.check_pc()
+            ));
+        };
+        let sp = self.registers.get(&Register::Sp);
+        self.registers
+            .set(&Register::Sp, sp.wrapping_add(arguments));
+        if let Some(checker) = &mut self.checker {
+            checker.ret(&self.registers, arguments)?;
+        }
+        Ok(return_address)
+    }
+
+    /// Enters the handler of an interrupt that is due before the instruction at `pc` runs,
+    /// returning the address to run next.
+    pub fn poll_interrupt(&mut self, pc: usize) -> Result<usize, String> {
+        self.interrupts.poll(&mut self.registers, pc)
+    }
+
+    /// Enters the handler of `vector` for `int`, returning its address.
+    pub fn interrupt(&mut self, vector: usize, return_address: usize) -> Result<usize, String> {
+        self.interrupts
+            .enter(&mut self.registers, vector, return_address)
+    }
+
+    /// Returns from an interrupt handler, returning the address to go back to.
+    pub fn iret(&mut self) -> Result<usize, String> {
+        self.interrupts.ret(&mut self.registers)
+    }
+
+    /// Starts a thread at `entry` with a copy of the registers of the one running, so that it
+    /// can be passed arguments, and a memory stack of its own. Returns its id.
+    pub fn spawn(&mut self, entry: usize) -> Result<usize, String> {
+        let slot = self.threads.free_slot();
+        let top = self
+            .memory
+            .size()
+            .checked_sub(slot * STACK_SIZE)
+            .filter(|top| top.saturating_sub(STACK_SIZE) >= self.memory.address());
+        let Some(top) = top else {
+            return Err(format!(
+                "No room for the stack of another thread at ins {:#02X}.",
+                self.registers.check_pc()
+            ));
+        };
+        let mut registers = self.registers.clone();
+        registers.reset_flags();
+        registers.set_pc(entry);
+        registers.set(&Register::Sp, top);
+        registers.set(&Register::Bp, top);
+        let saved = Saved {
+            registers,
+            stack: Stack::new(),
+            call_stack: Stack::new(),
+            handlers: self.interrupts.state.spawned(),
+            checker: self.checker.as_ref().map(|_| Checker::new()),
+        };
+        Ok(self.threads.spawn(saved, slot))
+    }
+
+    /// Lets the next thread run, returning the address to go on from. The thread running
+    /// carries on at `resume_at` once it's its turn again.
+    pub fn yield_thread(&mut self, resume_at: usize) -> Result<usize, String> {
+        self.switch(resume_at, State::Ready)
+    }
+
+    /// Waits for thread `id` to finish and puts its return value in `ra`. Returns the address
+    /// to go on from if another thread runs in the meantime, in which case the instruction at
+    /// `pc` runs again once it's this thread's turn. A thread can only be joined once, after
+    /// which `spawn` can reuse its id.
+    pub fn join(&mut self, id: usize, pc: usize) -> Result<Option<usize>, String> {
+        if let Some(value) = self.threads.join(id) {
+            self.registers.set(&RETURN_VALUE, value);
+            return Ok(None);
+        }
+        match self.threads.state(id) {
+            Some(_) => self.switch(pc, State::Joining(id)).map(Some),
+            None => Err(format!(
+                "join of thread {}, which doesn't exist, at ins {:#02X}.",
+                id,
+                self.registers.check_pc()
+            )),
+        }
+    }
+
+    /// Queues `value` on `channel`.
+    pub fn send(&mut self, channel: usize, value: usize) {
+        self.threads.send(channel, value);
+    }
+
+    /// Takes the next value on `channel` into `register`, waiting for one like `join` does.
+    pub fn receive(
+        &mut self,
+        register: &Register,
+        channel: usize,
+        pc: usize,
+    ) -> Result<Option<usize>, String> {
+        match self.threads.receive(channel) {
+            Some(value) => {
+                self.registers.set(register, value);
+                Ok(None)
+            }
+            None => self.switch(pc, State::Receiving(channel)).map(Some),
+        }
+    }
+
+    /// Takes the mutex at `address`, a word that holds 0 while it is free and the id of the
+    /// thread holding it plus 1 otherwise, waiting for it like `join` does.
+    pub fn lock(&mut self, address: usize, pc: usize) -> Result<Option<usize>, String> {
+        let owner = self.load(address)?;
+        let id = self.threads.current();
+        if owner == 0 {
+            self.store(address, id + 1)?;
+            return Ok(None);
+        }
+        if owner == id + 1 {
+            return Err(format!(
+                "lock of a mutex thread {} already holds at ins {:#02X}.",
+                id,
+                self.registers.check_pc()
+            ));
+        }
+        self.switch(pc, State::Locking(address)).map(Some)
+    }
+
+    /// Frees the mutex at `address`, which the thread running has to hold.
+    pub fn unlock(&mut self, address: usize) -> Result<(), String> {
+        let id = self.threads.current();
+        if self.load(address)? != id + 1 {
+            return Err(format!(
+                "unlock of a mutex thread {} doesn't hold at ins {:#02X}.",
+                id,
+                self.registers.check_pc()
+            ));
+        }
+        self.store(address, 0)
+    }
+
+    /// Finishes the thread running when it runs past the end of the code, returning the address
+    /// to go on from, or `None` if it is the main thread, which ends the program.
+    pub fn end_thread(&mut self) -> Result<Option<usize>, String> {
+        match self.threads.current() {
+            MAIN => Ok(None),
+            _ => self.exit_thread().map(Some),
+        }
+    }
+
+    // finishes the thread running, with `ra` as its return value.
+    fn exit_thread(&mut self) -> Result<usize, String> {
+        let value = self.registers.get(&RETURN_VALUE);
+        let pc = *self.registers.check_pc();
+        self.switch(pc, State::Finished(value))
+    }
+
+    // puts the thread running in `state` and swaps in the next one to run, returning its pc.
+    fn switch(&mut self, resume_at: usize, state: State) -> Result<usize, String> {
+        self.registers.set_pc(resume_at);
+        self.threads.set_state(state);
+        let memory = &self.memory;
+        let next = self.threads.next(|address| {
+            memory
+                .read(address, WORD)
+                .is_ok_and(|bytes| bytes.iter().all(|byte| *byte == 0))
+        });
+        let Some(next) = next else {
+            return Err(format!(
+                "Deadlock at ins {:#02X}: every thread is waiting.",
+                resume_at
+            ));
+        };
+        let current = self.threads.current();
+        if next != current {
+            self.swap_thread(current);
+            self.swap_thread(next);
+        }
+        self.threads.resume(next);
+        Ok(*self.registers.check_pc())
+    }
+
+    // swaps the state of the thread running with what thread `id` saved.
+    fn swap_thread(&mut self, id: usize) {
+        let saved = self.threads.saved_mut(id);
+        std::mem::swap(&mut self.registers, &mut saved.registers);
+        std::mem::swap(&mut self.stack, &mut saved.stack);
+        std::mem::swap(&mut self.call_stack, &mut saved.call_stack);
+        std::mem::swap(&mut self.interrupts.state, &mut saved.handlers);
+        std::mem::swap(&mut self.checker, &mut saved.checker);
+    }
+
+    /// Pushes `bp`, points it at the new frame and reserves `size` bytes below it for locals.
+    pub fn enter(&mut self, size: usize) -> Result<(), String> {
+        let sp = self.registers.get(&Register::Sp);
+        let bottom = sp
+            .checked_sub(WORD + size)
+            .filter(|bottom| *bottom >= self.memory.address());
+        let Some(bottom) = bottom else {
+            return Err(format!(
+                "Stack overflow at ins {:#02X}.",
+                self.registers.check_pc()
+            ));
+        };
+        let bp = sp - WORD;
+        self.store(bp, self.registers.get(&Register::Bp))?;
+        self.registers.set(&Register::Bp, bp);
+        self.registers.set(&Register::Sp, bottom);
+        Ok(())
+    }
+
+    /// Drops the current frame and restores the `bp` that `enter` pushed.
+    pub fn leave(&mut self) -> Result<(), String> {
+        let bp = self.registers.get(&Register::Bp);
+        if bp >= self.memory.size() {
+            return Err(format!(
+                "leave without a frame at ins {:#02X}.",
+                self.registers.check_pc()
+            ));
+        }
+        let saved = self.load(bp)?;
+        self.registers.set(&Register::Bp, saved);
+        self.registers.set(&Register::Sp, bp + WORD);
+        Ok(())
+    }
 }
+
+// the size of the values the stack and offset operands read and write.
+pub(crate) const WORD: usize = std::mem::size_of::<usize>();
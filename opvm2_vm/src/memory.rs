@@ -1,8 +1,16 @@
+use std::{borrow::Cow, collections::BTreeMap};
+
 use extism::{convert::Json, FromBytes, ToBytes};
+use opvm2::instruction::Instruction;
 use serde::{Deserialize, Serialize};
 
+use crate::device::{Bus, Device};
+
 const MAX_MEMORY_SIZE: usize = 1024 * 1024; // 1MB of memory, can be adjustable, but tests get extremely slow with any more.
 
+// the longest encoded instruction: opcode, operand types and three 10 byte operands.
+const MAX_INSTRUCTION_LENGTH: usize = 32;
+
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToBytes, FromBytes, Clone,
 )]
@@ -10,6 +18,54 @@ const MAX_MEMORY_SIZE: usize = 1024 * 1024; // 1MB of memory, can be adjustable,
 pub struct Memory {
     data: Vec<u8>,
     pointer: usize,
+    #[serde(skip)]
+    decoded: DecodedCache,
+    #[serde(skip)]
+    devices: Bus,
+}
+
+/// Instructions decoded so far, by address, along with their length.
+///
+/// The cache only mirrors `data`, so it's left out when comparing memory.
+#[derive(Debug, Default, Clone)]
+struct DecodedCache(BTreeMap<usize, (Instruction, usize)>);
+
+impl DecodedCache {
+    // drops every instruction overlapping `start..end`.
+    fn invalidate(&mut self, start: usize, end: usize) {
+        if self.0.is_empty() {
+            return;
+        }
+        let overlapping: Vec<usize> = self
+            .0
+            .range(start.saturating_sub(MAX_INSTRUCTION_LENGTH)..end)
+            .filter(|(address, (_, length))| *address + length > start)
+            .map(|(address, _)| *address)
+            .collect();
+        for address in overlapping {
+            self.0.remove(&address);
+        }
+    }
+}
+
+impl PartialEq for DecodedCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for DecodedCache {}
+
+impl PartialOrd for DecodedCache {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DecodedCache {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
 }
 
 impl Memory {
@@ -18,11 +74,14 @@ impl Memory {
         Self {
             data: vec![0; MAX_MEMORY_SIZE],
             pointer: 0,
+            decoded: DecodedCache::default(),
+            devices: Bus::default(),
         }
     }
 
     pub fn push(&mut self, data: &[u8], spacer: bool) -> usize {
         let start = self.pointer;
+        self.decoded.invalidate(start, start + data.len());
         for (i, byte) in data.iter().enumerate() {
             self.data[self.pointer as usize + i] = *byte;
         }
@@ -33,14 +92,66 @@ impl Memory {
         start
     }
 
-    pub fn get_instruction(&mut self, pointer: usize) -> u128 {
-        let start = pointer as usize;
-        let end = pointer + 16 as usize;
-        let mut instruction = 0;
-        for i in 0..(end - start) {
-            instruction |= (self.data[start + i] as u128) << (i * 8);
+    /// Moves the pointer up to the next multiple of `alignment`.
+    pub fn align(&mut self, alignment: usize) -> usize {
+        self.pointer = self.pointer.div_ceil(alignment) * alignment;
+        self.pointer
+    }
+
+    /// Reserves `size` zeroed bytes, returning where they start.
+    pub fn reserve(&mut self, size: usize) -> Result<usize, String> {
+        let start = self.pointer;
+        if start + size > self.data.len() {
+            return Err(format!(
+                "Reserving {:#02X} bytes at {:#02X} is out of memory bounds.",
+                size, start
+            ));
+        }
+        self.pointer += size;
+        Ok(start)
+    }
+
+    /// Decodes the instruction at `pointer`, returning it with its length in bytes.
+    ///
+    /// Decoded instructions are cached until the memory they were decoded from is written to.
+    pub fn decode_instruction(&mut self, pointer: usize) -> Result<(Instruction, usize), String> {
+        if let Some(decoded) = self.decoded.0.get(&pointer) {
+            return Ok(decoded.clone());
+        }
+        let bytes = self.data.get(pointer..).unwrap_or_default();
+        let decoded =
+            Instruction::decode(bytes).map_err(|e| format!("{} at {:#02X}.", e, pointer))?;
+        self.decoded.0.insert(pointer, decoded.clone());
+        Ok(decoded)
+    }
+
+    /// Writes `data` at `pointer`, or to the device mapped there if it's past the end of memory.
+    pub fn write(&mut self, pointer: usize, data: &[u8]) -> Result<(), String> {
+        if pointer >= self.data.len() {
+            return self.devices.write(pointer, data);
+        }
+        let end = pointer + data.len();
+        if end > self.data.len() {
+            return Err(format!("Write to {:#02X} is out of memory bounds.", end));
+        }
+        self.data[pointer..end].copy_from_slice(data);
+        self.decoded.invalidate(pointer, end);
+        Ok(())
+    }
+
+    /// Reads `length` bytes from `pointer`, or from the device mapped there if it's past the
+    /// end of memory.
+    pub fn read(&self, pointer: usize, length: usize) -> Result<Cow<'_, [u8]>, String> {
+        if pointer >= self.data.len() {
+            return self.devices.read(pointer, length).map(Cow::Owned);
+        }
+        match pointer.checked_add(length) {
+            Some(end) if end <= self.data.len() => Ok(Cow::Borrowed(&self.data[pointer..end])),
+            _ => Err(format!(
+                "Read from {:#02X} is out of memory bounds.",
+                pointer
+            )),
         }
-        instruction
     }
 
     pub fn get_literal(&mut self, pointer: usize) -> &[u8] {
@@ -56,6 +167,33 @@ impl Memory {
         self.pointer
     }
 
+    /// The size of the memory, where the stack starts.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Maps `device` at `address`, which has to be past the end of memory.
+    pub fn map(&mut self, address: usize, device: impl Device + 'static) -> Result<(), String> {
+        if address < self.data.len() {
+            return Err(format!(
+                "Device at {:#02X} overlaps memory, which ends at {:#02X}.",
+                address,
+                self.data.len()
+            ));
+        }
+        self.devices.map(address, device)
+    }
+
+    /// The devices mapped into memory.
+    pub fn devices(&self) -> &Bus {
+        &self.devices
+    }
+
+    /// Replaces the devices mapped into memory, e.g. to keep them when loading a program.
+    pub fn set_devices(&mut self, devices: Bus) {
+        self.devices = devices;
+    }
+
     pub fn raw(&self) -> Vec<u8> {
         self.data.clone()
     }
@@ -64,6 +202,43 @@ impl Memory {
         Self {
             data: raw,
             pointer: base,
+            decoded: DecodedCache::default(),
+            devices: Bus::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use opvm2::{instruction::Instruction, opcode::Opcode, operand::Operand, register::Register};
+
+    use super::Memory;
+
+    fn mov(value: usize) -> Instruction {
+        Instruction::new(
+            Opcode::Mov,
+            Operand::Register(Register::Ra),
+            Operand::Number(value),
+        )
+    }
+
+    #[test]
+    fn can_invalidate_decoded_instructions() -> Result<(), String> {
+        let mut memory = Memory::new();
+        let first = memory.push(&mov(1).encode(&BTreeMap::new())?, false);
+        let second = memory.push(&mov(2).encode(&BTreeMap::new())?, false);
+        assert_eq!(memory.decode_instruction(first)?, (mov(1), 4));
+        assert_eq!(memory.decode_instruction(second)?, (mov(2), 4));
+
+        // overwriting the operand of the first instruction leaves the second one cached.
+        memory.write(first + 3, &[5])?;
+        assert_eq!(memory.decode_instruction(first)?, (mov(5), 4));
+        assert_eq!(memory.decode_instruction(second)?, (mov(2), 4));
+
+        memory.write(second, &mov(300).encode(&BTreeMap::new())?)?;
+        assert_eq!(memory.decode_instruction(second)?, (mov(300), 5));
+        Ok(())
+    }
+}
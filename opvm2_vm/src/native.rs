@@ -0,0 +1,205 @@
+use opvm2::plugin_interface::OnInstructionValue;
+
+use crate::machine_context::MachineContext;
+
+/// An in-process plugin, the native counterpart of a wasm plugin.
+///
+/// It follows the same rules as the wasm ABI: `opcodes` lists what would be the
+/// `handle_<opcode>` exports, and a handler returning `Some(address)` jumps to that
+/// address (relative to the start of the program) while `None` moves on to the next
+/// instruction. Instead of host functions, handlers get the machine context directly.
+pub trait OpvmPlugin {
+    fn name(&self) -> String;
+
+    /// The opcodes this plugin handles.
+    fn opcodes(&self) -> Vec<String>;
+
+    /// Plugins with a higher priority win when several handle the same opcode.
+    fn priority(&self) -> i64 {
+        0
+    }
+
+    /// Whether `handle_instruction` should be called before every instruction.
+    fn has_hook(&self) -> bool {
+        false
+    }
+
+    /// The instruction hook, called before every instruction when `has_hook` is `true`.
+    fn handle_instruction(
+        &mut self,
+        _context: &mut MachineContext,
+        _ins: &OnInstructionValue,
+    ) -> Result<Option<u64>, String> {
+        Ok(None)
+    }
+
+    /// Handles one of the opcodes returned by `opcodes`.
+    fn handle_opcode(
+        &mut self,
+        opcode: &str,
+        context: &mut MachineContext,
+        ins: &OnInstructionValue,
+    ) -> Result<Option<u64>, String>;
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, rc::Rc};
+
+    use opvm2::{parser::program::Program, plugin_interface::OnInstructionValue};
+
+    use super::OpvmPlugin;
+    use crate::{machine_context::MachineContext, vm::Vm};
+
+    struct Life {
+        priority: i64,
+        value: usize,
+    }
+
+    impl OpvmPlugin for Life {
+        fn name(&self) -> String {
+            format!("Life {}", self.value)
+        }
+
+        fn opcodes(&self) -> Vec<String> {
+            vec!["life".to_string(), "SKIP".to_string()]
+        }
+
+        fn priority(&self) -> i64 {
+            self.priority
+        }
+
+        fn handle_opcode(
+            &mut self,
+            opcode: &str,
+            context: &mut MachineContext,
+            ins: &OnInstructionValue,
+        ) -> Result<Option<u64>, String> {
+            match opcode {
+                "life" => {
+                    context.registers.set(&ins.lhs.get_register()?, self.value);
+                    Ok(None)
+                }
+                // jump over the next instruction.
+                _ => {
+                    let (_, length) = context.memory.decode_instruction(ins.pc)?;
+                    let (_, next) = context.memory.decode_instruction(ins.pc + length)?;
+                    Ok(Some((ins.pc - context.base_address + length + next) as u64))
+                }
+            }
+        }
+    }
+
+    struct Counter(Rc<Cell<usize>>);
+
+    impl OpvmPlugin for Counter {
+        fn name(&self) -> String {
+            "Counter".to_string()
+        }
+
+        fn opcodes(&self) -> Vec<String> {
+            vec![]
+        }
+
+        fn has_hook(&self) -> bool {
+            true
+        }
+
+        fn handle_instruction(
+            &mut self,
+            _context: &mut MachineContext,
+            _ins: &OnInstructionValue,
+        ) -> Result<Option<u64>, String> {
+            self.0.set(self.0.get() + 1);
+            Ok(None)
+        }
+
+        fn handle_opcode(
+            &mut self,
+            _opcode: &str,
+            _context: &mut MachineContext,
+            _ins: &OnInstructionValue,
+        ) -> Result<Option<u64>, String> {
+            unreachable!()
+        }
+    }
+
+    fn life(priority: i64, value: usize) -> Life {
+        Life { priority, value }
+    }
+
+    fn read_register(vm: &Vm, register: &str) -> usize {
+        let context = vm.context.get().unwrap();
+        let context = context.lock().unwrap();
+        context
+            .registers
+            .get(&register.to_string().try_into().unwrap())
+    }
+
+    #[test]
+    fn can_handle_custom_opcode() -> Result<(), String> {
+        let mut vm = Vm::new_e();
+        vm.plugin.load_native(life(0, 42))?;
+        vm.run_program(Program::from("life ra\nmov rb, ra"))?;
+        assert_eq!(read_register(&vm, "ra"), 42);
+        assert_eq!(read_register(&vm, "rb"), 42);
+        Ok(())
+    }
+
+    #[test]
+    fn can_jump_from_handler() -> Result<(), String> {
+        let mut vm = Vm::new_e();
+        vm.plugin.load_native(life(0, 42))?;
+        vm.run_program(Program::from("skip\nmov ra, 1\nmov rb, 2"))?;
+        assert_eq!(read_register(&vm, "ra"), 0);
+        assert_eq!(read_register(&vm, "rb"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn can_call_hooks() -> Result<(), String> {
+        let count = Rc::new(Cell::new(0));
+        let mut vm = Vm::new_e();
+        vm.plugin.load_native(Counter(count.clone()))?;
+        vm.run_program(Program::from("mov ra, 1\ninc ra\ninc ra"))?;
+        assert_eq!(count.get(), 3);
+        assert_eq!(read_register(&vm, "ra"), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn can_detect_conflicts() -> Result<(), String> {
+        let mut vm = Vm::new_e();
+        vm.plugin.load_native(life(0, 1))?;
+        assert_eq!(
+            vm.plugin.load_native(life(0, 2)),
+            Err(
+                "Opcode `life` is handled by both `Life 1` and `Life 2`, give one of them a higher priority"
+                    .to_string()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_resolve_conflicts_by_priority() -> Result<(), String> {
+        let mut vm = Vm::new_e();
+        vm.plugin.load_native(life(0, 1))?;
+        vm.plugin.load_native(life(2, 2))?;
+        vm.plugin.load_native(life(1, 3))?;
+        assert_eq!(vm.plugin.handler_of("life"), Some("Life 2"));
+        vm.run_program(Program::from("life ra"))?;
+        assert_eq!(read_register(&vm, "ra"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unhandled_opcodes() {
+        let mut vm = Vm::new_e();
+        let result = vm.run_program(Program::from("life ra"));
+        assert_eq!(
+            result,
+            Err("No plugins found for handling opcode: `life`. ".to_string())
+        );
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::BTreeMap;
+
+use extism::{convert::Json, FromBytes, ToBytes};
+use opvm2::{
+    instruction::Instruction,
+    opcode::{Opcode, PluginValue},
+    operand::Operand,
+    parser::{
+        program::{LabelValue, Program},
+        section::{Section, Sections},
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{memory::Memory, registry::PluginEntry, CompiledProgram, SECTION_ALIGNMENT};
+
+/// A source file compiled on its own, before its labels have addresses. [`link`] places
+/// objects next to each other and fills in their references to labels.
+#[derive(Debug, Deserialize, Serialize, ToBytes, FromBytes, PartialEq, Clone)]
+#[encoding(Json)]
+pub struct Object {
+    /// The file the object was compiled from, used in errors.
+    pub name: String,
+    pub code: Vec<u8>,
+    /// The literals and plugin names, null terminated.
+    pub literals: Vec<u8>,
+    pub sections: Sections,
+    pub symbols: BTreeMap<String, Symbol>,
+    /// The symbols declared `global`, which other objects can refer to.
+    pub exports: Vec<String>,
+    /// The symbols the object refers to without defining them.
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    pub plugins: Vec<PluginEntry>,
+}
+
+/// Where a symbol is, relative to the part of its object it is in.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub enum Symbol {
+    Code(usize),
+    Literal(usize),
+    Data { section: Section, offset: usize },
+    Constant(usize),
+}
+
+/// An operand referring to symbols whose values are only known once the objects are placed.
+///
+/// The operand is encoded as wide as any value can be, so that filling it in never changes
+/// the length of the instruction.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct Relocation {
+    /// Where the instruction starts in the code of the object.
+    pub offset: usize,
+    pub field: Field,
+    /// The operand as it was written, a label or an expression using labels. For
+    /// [`Field::Plugin`], the name of the plugin opcode.
+    pub target: Operand,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+pub enum Field {
+    Plugin,
+    Lhs,
+    Rhs,
+}
+
+/// A static library: objects that are only linked in when another object refers to one of
+/// their exports.
+#[derive(Debug, Deserialize, Serialize, ToBytes, FromBytes, PartialEq, Clone, Default)]
+#[encoding(Json)]
+pub struct Archive {
+    pub objects: Vec<Object>,
+}
+
+impl Object {
+    /// Compiles the program on its own. Operands using labels that aren't constants are left
+    /// to the linker, except for branches and `lea` to labels in the code of the object, which
+    /// are relative and so don't depend on where the object is placed.
+    pub fn compile(
+        program: Program,
+        name: &str,
+        plugins: Vec<PluginEntry>,
+    ) -> Result<Object, String> {
+        let labels = &program.labels.list;
+        let mut symbols = BTreeMap::new();
+        let mut literals = vec![];
+        for (label, value) in labels.iter() {
+            let symbol = match value {
+                LabelValue::Literal(value) => Symbol::Literal(push_literal(&mut literals, value)),
+                LabelValue::Data { section, offset } => Symbol::Data {
+                    section: *section,
+                    offset: *offset,
+                },
+                LabelValue::Constant(value) => Symbol::Constant(*value),
+                // placed once the code is laid out.
+                LabelValue::Address(_) => continue,
+            };
+            symbols.insert(label.clone(), symbol);
+        }
+        for global in program.globals.iter() {
+            if !labels.contains_key(global) {
+                return Err(format!(
+                    "{}: Label `{}` is declared global but never defined",
+                    name, global
+                ));
+            }
+        }
+
+        let is_constant = |name: &str| matches!(labels.get(name), Some(LabelValue::Constant(_)));
+        let is_code = |name: &str| matches!(labels.get(name), Some(LabelValue::Address(_)));
+        let mut instructions = vec![];
+        let mut pending = vec![];
+        for (index, instruction) in program.instructions.iter().enumerate() {
+            let mut instruction = instruction.clone();
+            if let Opcode::Plugin(PluginValue::Name(plugin)) = &instruction.opcode {
+                if !symbols.contains_key(plugin) {
+                    let offset = push_literal(&mut literals, plugin);
+                    symbols.insert(plugin.clone(), Symbol::Literal(offset));
+                }
+                let target = Operand::Label(LabelValue::Literal(plugin.clone()));
+                pending.push((index, Field::Plugin, target));
+                instruction.opcode = Opcode::Plugin(PluginValue::Address(u32::MAX));
+            }
+            for field in [Field::Lhs, Field::Rhs] {
+                let relative = match field {
+                    Field::Lhs => instruction.opcode.is_branch(),
+                    _ => instruction.opcode.takes_address(),
+                };
+                let operand = field.operand(&mut instruction);
+                let names = names(operand);
+                if names.iter().all(|name| is_constant(name))
+                    || relative && names.iter().all(|name| is_code(name) || is_constant(name))
+                {
+                    continue;
+                }
+                pending.push((index, field, operand.clone()));
+                *operand = match relative {
+                    true => Operand::Relative(isize::MIN),
+                    false => Operand::Number(usize::MAX),
+                };
+            }
+            instructions.push(instruction);
+        }
+
+        let mut literal_list: BTreeMap<String, usize> = labels
+            .iter()
+            .filter_map(|(label, value)| match value {
+                LabelValue::Constant(value) => Some((label.clone(), *value)),
+                _ => None,
+            })
+            .collect();
+        let encoded =
+            CompiledProgram::layout_code(&instructions, &program.labels, &mut literal_list)?;
+        for (label, value) in labels.iter() {
+            if let LabelValue::Address(_) = value {
+                symbols.insert(label.clone(), Symbol::Code(literal_list[label]));
+            }
+        }
+        let mut offsets = vec![0];
+        for instruction in encoded.iter() {
+            offsets.push(offsets[offsets.len() - 1] + instruction.len());
+        }
+        let relocations: Vec<Relocation> = pending
+            .into_iter()
+            .map(|(index, field, target)| Relocation {
+                offset: offsets[index],
+                field,
+                target,
+            })
+            .collect();
+        let mut imports: Vec<String> = relocations
+            .iter()
+            .flat_map(|relocation| names(&relocation.target))
+            .filter(|name| !symbols.contains_key(*name))
+            .map(|name| name.to_string())
+            .collect();
+        imports.sort();
+        imports.dedup();
+
+        Ok(Object {
+            name: name.to_string(),
+            code: encoded.concat(),
+            literals,
+            sections: program.sections,
+            symbols,
+            exports: program.globals,
+            imports,
+            relocations,
+            plugins,
+        })
+    }
+}
+
+impl Field {
+    fn operand<'a>(&self, instruction: &'a mut Instruction) -> &'a mut Operand {
+        match self {
+            Field::Rhs => &mut instruction.rhs,
+            _ => &mut instruction.lhs,
+        }
+    }
+}
+
+/// Places the objects one after the other and fills in their references to symbols.
+///
+/// Objects from the archives are linked in when the objects linked in so far refer to one of
+/// their exports. The program starts at the code of the first object and ends at the end of
+/// the code of the last one, so the first object has to jump past or `hlt` before the code
+/// of the others.
+pub fn link(objects: Vec<Object>, archives: Vec<Archive>) -> Result<CompiledProgram, String> {
+    let mut objects = objects;
+    let mut available: Vec<Object> = archives.into_iter().flat_map(|a| a.objects).collect();
+    loop {
+        let undefined: Vec<&String> = objects
+            .iter()
+            .flat_map(|object| object.imports.iter())
+            .filter(|name| !objects.iter().any(|object| object.exports.contains(name)))
+            .collect();
+        let needed = available
+            .iter()
+            .position(|object| object.exports.iter().any(|name| undefined.contains(&name)));
+        match needed {
+            Some(index) => objects.push(available.remove(index)),
+            None => break,
+        }
+    }
+
+    let mut exports: BTreeMap<&str, usize> = BTreeMap::new();
+    for (index, object) in objects.iter().enumerate() {
+        for name in object.exports.iter() {
+            if let Some(other) = exports.insert(name, index) {
+                return Err(format!(
+                    "Symbol `{}` is exported by both {} and {}",
+                    name, objects[other].name, object.name
+                ));
+            }
+        }
+    }
+
+    // laid out like a single program: literals, .rodata, .data, .bss and the code.
+    let mut memory = Memory::new();
+    let literal_bases: Vec<usize> = objects
+        .iter()
+        .map(|object| memory.push(&object.literals, false))
+        .collect();
+    let mut section_bases: Vec<BTreeMap<Section, usize>> = vec![BTreeMap::new(); objects.len()];
+    for section in Section::DATA_SECTIONS {
+        memory.align(SECTION_ALIGNMENT);
+        for (index, object) in objects.iter().enumerate() {
+            let address = memory.reserve(object.sections.size(section))?;
+            match section {
+                Section::Rodata => memory.write(address, &object.sections.rodata)?,
+                Section::Data => memory.write(address, &object.sections.data)?,
+                _ => {}
+            }
+            section_bases[index].insert(section, address);
+        }
+    }
+    let start_address = memory.align(SECTION_ALIGNMENT);
+    let mut code_bases = vec![];
+    let mut code = vec![];
+    for object in objects.iter() {
+        code_bases.push(code.len());
+        code.extend_from_slice(&object.code);
+    }
+
+    // code symbols are offsets from the start of the code, like labels of a single program.
+    let value = |index: usize, symbol: &Symbol| match symbol {
+        Symbol::Code(offset) => code_bases[index] + offset,
+        Symbol::Literal(offset) => literal_bases[index] + offset,
+        Symbol::Data { section, offset } => section_bases[index][section] + offset,
+        Symbol::Constant(value) => *value,
+    };
+    for (index, object) in objects.iter().enumerate() {
+        let mut literal_list = BTreeMap::new();
+        let mut code_labels = vec![];
+        for name in object.relocations.iter().flat_map(|r| names(&r.target)) {
+            let (owner, symbol) = match object.symbols.get(name) {
+                Some(symbol) => (index, symbol),
+                None => {
+                    let owner = *exports
+                        .get(name)
+                        .ok_or_else(|| format!("{}: Undefined symbol `{}`", object.name, name))?;
+                    (owner, &objects[owner].symbols[name])
+                }
+            };
+            literal_list.insert(name.to_string(), value(owner, symbol));
+            if let Symbol::Code(_) = symbol {
+                code_labels.push(name);
+            }
+        }
+
+        for relocation in object.relocations.iter() {
+            let position = code_bases[index] + relocation.offset;
+            let (mut instruction, length) = Instruction::decode(&code[position..])?;
+            match (relocation.field, &relocation.target) {
+                (Field::Plugin, Operand::Label(LabelValue::Literal(plugin))) => {
+                    instruction.opcode = Opcode::Plugin(PluginValue::Name(plugin.clone()))
+                }
+                (field, target) => *field.operand(&mut instruction) = target.clone(),
+            }
+            let next = position + length;
+            let encoded =
+                CompiledProgram::relative(&instruction, &literal_list, &code_labels, next)?
+                    .encode_padded(&literal_list, length)?;
+            if encoded.len() != length {
+                return Err(format!(
+                    "{}: Relocation at {:#02X} doesn't fit in its instruction",
+                    object.name, relocation.offset
+                ));
+            }
+            code[position..next].copy_from_slice(&encoded);
+        }
+    }
+    memory.push(&code, false);
+
+    let mut plugins: Vec<PluginEntry> = vec![];
+    for plugin in objects.into_iter().flat_map(|object| object.plugins) {
+        if !plugins.iter().any(|p| p.info.hash == plugin.info.hash) {
+            plugins.push(plugin);
+        }
+    }
+    Ok(CompiledProgram {
+        start_address,
+        memory_address: memory.address(),
+        program: memory.raw(),
+        plugins,
+    })
+}
+
+// the names of the labels an operand uses.
+fn names(operand: &Operand) -> Vec<&str> {
+    match operand {
+        Operand::Label(LabelValue::Literal(name)) => vec![name.as_str()],
+        Operand::Expression(expr) => expr.names(),
+        _ => vec![],
+    }
+}
+
+fn push_literal(literals: &mut Vec<u8>, value: &str) -> usize {
+    let offset = literals.len();
+    literals.extend_from_slice(value.as_bytes());
+    literals.push(0);
+    offset
+}
+
+#[cfg(test)]
+mod test {
+    use opvm2::parser::program::Program;
+
+    use super::{link, Archive, Object};
+    use crate::{io::BufferIo, vm::Vm, CompiledProgram};
+
+    const MAIN: &str = r"
+        COUNT equ 2
+              mov rc, COUNT
+        loop: call print_line
+              dec rc
+              test rc, 0
+              jne loop
+              lea rb, table
+              puts rb
+              hlt
+    ";
+
+    const LIB: &str = r"
+        global print_line, table
+        message: 'Hello'
+        section .data
+        table: db 'Bye', 0
+        section .text
+        print_line: puts message
+              call newline
+              ret
+        newline: putc 10
+              ret
+    ";
+
+    fn object(name: &str, source: &str) -> Object {
+        CompiledProgram::new_e()
+            .compile_object(Program::from(source), name, false)
+            .unwrap()
+    }
+
+    fn run(program: CompiledProgram) -> Result<String, String> {
+        let io = BufferIo::new("");
+        let mut vm = Vm::new_e();
+        vm.set_io(io.clone());
+        vm.run(program)?;
+        Ok(io.output())
+    }
+
+    #[test]
+    fn can_compile_objects() {
+        let lib = object("lib.o2", LIB);
+        assert_eq!(lib.exports, vec!["print_line", "table"]);
+        assert!(lib.imports.is_empty());
+        // `puts message` is the only reference to a label outside of the code.
+        assert_eq!(lib.relocations.len(), 1);
+
+        let main = object("main.o2", MAIN);
+        assert!(main.exports.is_empty());
+        assert_eq!(main.imports, vec!["print_line", "table"]);
+    }
+
+    #[test]
+    fn can_link_objects() -> Result<(), String> {
+        let program = link(vec![object("main.o2", MAIN), object("lib.o2", LIB)], vec![])?;
+        assert_eq!(run(program)?, "Hello\nHello\nBye");
+        Ok(())
+    }
+
+    #[test]
+    fn can_link_archives() -> Result<(), String> {
+        let unused = object("unused.o2", "global unused\nunused: 'unused'\n");
+        let archive = Archive {
+            objects: vec![unused, object("lib.o2", LIB)],
+        };
+        let linked = link(vec![object("main.o2", MAIN)], vec![archive])?;
+        // only objects that are referred to are linked in.
+        let expected = link(vec![object("main.o2", MAIN), object("lib.o2", LIB)], vec![])?;
+        assert_eq!(linked, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn can_report_link_errors() {
+        assert_eq!(
+            link(vec![object("main.o2", MAIN)], vec![]),
+            Err("main.o2: Undefined symbol `print_line`".to_string())
+        );
+        assert_eq!(
+            link(vec![object("a.o2", LIB), object("b.o2", LIB)], vec![]),
+            Err("Symbol `print_line` is exported by both a.o2 and b.o2".to_string())
+        );
+        // labels that aren't declared global stay private.
+        let main = object("main.o2", "call newline");
+        assert_eq!(
+            link(vec![main, object("lib.o2", LIB)], vec![]),
+            Err("main.o2: Undefined symbol `newline`".to_string())
+        );
+        assert_eq!(
+            CompiledProgram::new_e().compile_object(Program::from("global x"), "x.o2", false),
+            Err("x.o2: Label `x` is declared global but never defined".to_string())
+        );
+    }
+}
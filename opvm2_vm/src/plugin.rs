@@ -1,21 +1,65 @@
-use std::{collections::BTreeMap, ffi::CString, io::Write};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+};
 
 use extism::*;
 use opvm2::{
     instruction::Instruction,
     opcode::Opcode,
-    parser::program::LabelValue,
-    plugin_interface::{Label, Labels, OnInstructionValue},
+    operand::Operand,
+    plugin_interface::{Label, Labels, OnInstructionValue, NAME_SECTION},
+};
+use wasmparser::{ExternalKind, Parser, Payload};
+
+use crate::{
+    machine_context::MachineContext,
+    native::OpvmPlugin,
+    register::Register,
+    registry::{self, PluginInfo},
 };
 
-use crate::{machine_context::MachineContext, register::Register};
+// exports starting with this prefix are opcode handlers, e.g. `handle_life` handles `life`.
+const HANDLER_PREFIX: &str = "handle_";
+// hooks are called for every instruction and are never routed as opcodes.
+const HOOKS: [&str; 1] = ["handle_instruction"];
 
-#[derive(Debug)]
 pub struct PluginLoader {
     pub plugins: Vec<Plugin>,
+    pub native: Vec<Box<dyn OpvmPlugin>>,
+    // metadata of each wasm plugin, in the same order as `plugins`.
+    metadata: Vec<PluginInfo>,
+    names: HashMap<PluginRef, String>,
+    // opcode name -> the plugin that handles it, resolved once at load time.
+    routes: HashMap<String, Route>,
+    hooks: Vec<PluginRef>,
     context: UserData<MachineContext>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum PluginRef {
+    Wasm(usize),
+    Native(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Route {
+    plugin: PluginRef,
+    priority: i64,
+}
+
+impl std::fmt::Debug for PluginLoader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PluginLoader")
+            .field("plugins", &self.plugins)
+            .field("metadata", &self.metadata)
+            .field("names", &self.names)
+            .field("routes", &self.routes)
+            .field("hooks", &self.hooks)
+            .finish()
+    }
+}
+
 // extern "C" fn log(data: *const std::ffi::c_char, _size: Size) {
 //     unsafe {
 //         let line = CStr::from_ptr(data);
@@ -37,59 +81,147 @@ impl PluginLoader {
         }
         Self {
             plugins: vec![],
+            native: vec![],
+            metadata: vec![],
+            names: HashMap::new(),
+            routes: HashMap::new(),
+            hooks: vec![],
             context,
         }
     }
 
-    pub fn execute_plugin_fn(
+    /// Returns `true` if a loaded plugin handles the given opcode.
+    pub fn handles(&self, opcode: &str) -> bool {
+        self.routes.contains_key(&opcode.to_lowercase())
+    }
+
+    /// Returns the name of the plugin that handles the given opcode, if any.
+    pub fn handler_of(&self, opcode: &str) -> Option<&str> {
+        self.routes
+            .get(&opcode.to_lowercase())
+            .map(|route| self.names[&route.plugin].as_str())
+    }
+
+    /// Returns the metadata of the loaded wasm plugins, in load order.
+    pub fn metadata(&self) -> &[PluginInfo] {
+        &self.metadata
+    }
+
+    /// Returns `true` if any plugin has to see the instructions that run, either through a hook
+    /// or as the handler of an opcode.
+    pub fn is_active(&self) -> bool {
+        !self.hooks.is_empty() || !self.routes.is_empty()
+    }
+
+    /// Returns every opcode handled by the loaded plugins.
+    pub fn opcodes(&self) -> Vec<String> {
+        self.routes.keys().cloned().collect()
+    }
+
+    /// Calls every plugin exporting an instruction hook, in load order.
+    pub fn execute_hooks(
         &mut self,
-        name: String,
-        ins: OnInstructionValue,
-        is_hook: bool,
+        ins: &OnInstructionValue,
         base_address: usize,
-    ) -> Result<usize, String> {
-        let mut executed_count = 0;
-        for plugin in self.plugins.iter_mut() {
-            if !plugin.function_exists(&name) {
-                continue;
-            }
+    ) -> Result<(), String> {
+        for index in 0..self.hooks.len() {
+            let plugin = self.hooks[index];
+            self.call_handler(plugin, None, ins, base_address)?;
+        }
+        Ok(())
+    }
 
-            let addr = plugin
-                .call::<&OnInstructionValue, Option<u64>>(&name, &ins)
-                .map_err(|e| e.to_string())?;
+    /// Dispatches the opcode to the plugin registered for it.
+    /// Returns `false` if no plugin handles the opcode.
+    pub fn execute_opcode(
+        &mut self,
+        opcode: &str,
+        ins: &OnInstructionValue,
+        base_address: usize,
+    ) -> Result<bool, String> {
+        let route = match self.routes.get(opcode) {
+            Some(route) => *route,
+            None => return Ok(false),
+        };
+        self.call_handler(route.plugin, Some(opcode), ins, base_address)?;
+        Ok(true)
+    }
 
-            let context = self.context.get().map_err(|e| e.to_string()).unwrap();
-            let mut context = context.lock().unwrap();
-            match addr {
-                Some(addr) => context
-                    .registers
-                    .set_pc(base_address as usize + addr as usize),
-                None => {
-                    if !is_hook {
-                        context.registers.increment_pc()
-                    }
+    // calls the opcode handler, or the instruction hook if `opcode` is `None`.
+    fn call_handler(
+        &mut self,
+        plugin: PluginRef,
+        opcode: Option<&str>,
+        ins: &OnInstructionValue,
+        base_address: usize,
+    ) -> Result<(), String> {
+        let context = self.context.get().map_err(|e| e.to_string())?;
+        let addr = match plugin {
+            PluginRef::Wasm(index) => {
+                let name = match opcode {
+                    Some(opcode) => format!("{}{}", HANDLER_PREFIX, opcode),
+                    None => HOOKS[0].to_string(),
+                };
+                // the context must stay unlocked here, host functions lock it themselves.
+                self.plugins[index]
+                    .call::<&OnInstructionValue, Option<u64>>(&name, ins)
+                    .map_err(|e| e.to_string())?
+            }
+            PluginRef::Native(index) => {
+                let mut context = context.lock().unwrap();
+                match opcode {
+                    Some(opcode) => self.native[index].handle_opcode(opcode, &mut context, ins)?,
+                    None => self.native[index].handle_instruction(&mut context, ins)?,
+                }
+            }
+        };
+
+        let mut context = context.lock().unwrap();
+        match addr {
+            Some(addr) => context.registers.set_pc(base_address + addr as usize),
+            None => {
+                if opcode.is_some() {
+                    let pc = *context.registers.check_pc();
+                    let (_, length) = context.memory.decode_instruction(pc)?;
+                    context.registers.increment_pc(length)
                 }
             }
-            executed_count += 1;
         }
-        Ok(executed_count)
+        Ok(())
     }
 
     pub fn load_all(&mut self, plugins: &Vec<Vec<u8>>, verbose: bool) -> Result<(), String> {
         for plugin in plugins {
-            let manifest = Manifest::new([Wasm::data(plugin.clone())]);
-            self.load(manifest, verbose);
+            self.load(plugin.clone(), verbose)?;
         }
         Ok(())
     }
 
     pub fn load_from_path(&mut self, path: &str, verbose: bool) -> Result<(), String> {
-        let manifest = Manifest::new([Wasm::file(path)]);
-        self.load(manifest, verbose);
+        let wasm = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.load(wasm, verbose)
+    }
+
+    /// Registers an in-process plugin, following the same routing rules as wasm plugins.
+    pub fn load_native(&mut self, plugin: impl OpvmPlugin + 'static) -> Result<(), String> {
+        let index = PluginRef::Native(self.native.len());
+        let opcodes = plugin
+            .opcodes()
+            .iter()
+            .map(|opcode| opcode.to_lowercase())
+            .collect();
+        self.register(index, plugin.name(), opcodes, plugin.priority())?;
+        if plugin.has_hook() {
+            self.hooks.push(index);
+        }
+        self.native.push(Box::new(plugin));
         Ok(())
     }
 
-    pub fn load(&mut self, manifest: Manifest, verbose: bool) {
+    pub fn load(&mut self, wasm: Vec<u8>, verbose: bool) -> Result<(), String> {
+        let opcodes = Self::exported_opcodes(&wasm)?;
+        let hash = registry::hash(&wasm);
+        let manifest = Manifest::new([Wasm::data(wasm)]);
         let mut plugin = PluginBuilder::new(manifest)
             .with_wasi(true)
             .with_function(
@@ -128,15 +260,117 @@ impl PluginLoader {
             .with_function("print", [PTR], [], self.context.clone(), print)
             .with_function("execute", [PTR], [], self.context.clone(), execute)
             .build()
-            .unwrap();
+            .map_err(|e| e.to_string())?;
         if !plugin.function_exists("name") {
-            panic!("Plugin does not have a `name` function");
+            return Err("Plugin does not have a `name` function".to_string());
         }
-        let name = plugin.call::<(), String>("name", ()).unwrap();
+        let name = plugin
+            .call::<(), String>("name", ())
+            .map_err(|e| e.to_string())?;
+        // plugins may export a `priority` to win over others handling the same opcode.
+        let priority = if plugin.function_exists("priority") {
+            plugin
+                .call::<(), i64>("priority", ())
+                .map_err(|e| e.to_string())?
+        } else {
+            0
+        };
+        let version = if plugin.function_exists("version") {
+            plugin
+                .call::<(), String>("version", ())
+                .map_err(|e| e.to_string())?
+        } else {
+            "unknown".to_string()
+        };
+
+        let index = PluginRef::Wasm(self.plugins.len());
+        self.register(index, name.clone(), opcodes.clone(), priority)?;
         if verbose {
             println!("Loaded plugin: {}", name);
         }
+        if HOOKS.iter().any(|hook| plugin.function_exists(hook)) {
+            self.hooks.push(index);
+        }
         self.plugins.push(plugin);
+        self.metadata.push(PluginInfo {
+            name,
+            version,
+            hash,
+            opcodes,
+        });
+        Ok(())
+    }
+
+    // adds the plugin's opcodes to the routing table, leaving it untouched on conflict.
+    fn register(
+        &mut self,
+        plugin: PluginRef,
+        name: String,
+        opcodes: Vec<String>,
+        priority: i64,
+    ) -> Result<(), String> {
+        let mut routes = self.routes.clone();
+        for opcode in opcodes {
+            match routes.get(&opcode) {
+                Some(existing) if existing.priority == priority => {
+                    return Err(format!(
+                        "Opcode `{}` is handled by both `{}` and `{}`, give one of them a higher priority",
+                        opcode, self.names[&existing.plugin], name
+                    ));
+                }
+                Some(existing) if existing.priority > priority => {}
+                _ => {
+                    routes.insert(opcode, Route { plugin, priority });
+                }
+            }
+        }
+        self.routes = routes;
+        self.names.insert(plugin, name);
+        Ok(())
+    }
+
+    // reads the export section of the wasm module and collects every `handle_<opcode>` function.
+    fn exported_opcodes(wasm: &[u8]) -> Result<Vec<String>, String> {
+        let mut opcodes = vec![];
+        for payload in Parser::new(0).parse_all(wasm) {
+            if let Payload::ExportSection(exports) = payload.map_err(|e| e.to_string())? {
+                for export in exports {
+                    let export = export.map_err(|e| e.to_string())?;
+                    if export.kind != ExternalKind::Func || HOOKS.contains(&export.name) {
+                        continue;
+                    }
+                    if let Some(opcode) = export.name.strip_prefix(HANDLER_PREFIX) {
+                        opcodes.push(opcode.to_lowercase());
+                    }
+                }
+            }
+        }
+        Ok(opcodes)
+    }
+
+    /// Reads the name a plugin keeps in its [`NAME_SECTION`] without loading it. Fails for
+    /// modules that aren't plugins, i.e. don't export a `name` function.
+    pub(crate) fn declared_name(wasm: &[u8]) -> Result<Option<String>, String> {
+        let mut exports_name = false;
+        let mut name = None;
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.map_err(|e| e.to_string())? {
+                Payload::ExportSection(exports) => {
+                    for export in exports {
+                        let export = export.map_err(|e| e.to_string())?;
+                        exports_name |= export.kind == ExternalKind::Func && export.name == "name";
+                    }
+                }
+                Payload::CustomSection(section) if section.name() == NAME_SECTION => {
+                    name = Some(String::from_utf8_lossy(section.data()).into_owned());
+                }
+                _ => {}
+            }
+        }
+        if !exports_name {
+            return Err("Plugin must export a `name` function".to_string());
+        }
+        Ok(name)
     }
 }
 
@@ -173,9 +407,10 @@ host_fn!(pub pop_stack(user_data: MachineContext;) -> Result<u64, String> {
 });
 
 host_fn!(pub get_input(user_data: MachineContext;) -> Result<String, String> {
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
-    Ok(input)
+    let context = user_data.get()?;
+    let io = context.lock().unwrap().io.clone();
+    // don't hold the context while waiting for input.
+    io.read_line().map_err(extism::Error::msg)
 });
 
 host_fn!(pub jmp_to_label(user_data: MachineContext; label: String) -> Result<(), String> {
@@ -218,25 +453,32 @@ host_fn!(pub quit(user_data: MachineContext;) -> Result<(), String> {
 });
 
 host_fn!(pub print(user_data: MachineContext; data: String) -> Result<(), String> {
-   print!("{}", data);
-   std::io::stdout().flush()?;
-   Ok(())
+    let context = user_data.get()?;
+    let context = context.lock().unwrap();
+    context.io.print(&data).map_err(extism::Error::msg)?;
+    Ok(())
 });
 
 host_fn!(pub execute(user_data: MachineContext; data: Instruction) -> Result<(), String> {
     let context = user_data.get()?;
     let mut context = context.lock().unwrap();
     let empty_map: BTreeMap<String, usize> = BTreeMap::new();
-    let base = context.base_address;
     let current_end = context.memory.address();
     let current_pc = *context.registers.check_pc();
 
     // insert a jump at the beginning and at the end
     // the beginning ensures we never hit this routine again.
     // the end ensures we return to the original location.
-    let jmp_address = context.memory.push(&Instruction::get_u8_array(Instruction::new_l(Opcode::Jmp, opvm2::operand::Operand::Label(LabelValue::Address((current_end - base) + 48))).encode(&empty_map)), false);
-    context.memory.push(&Instruction::get_u8_array(data.encode(&empty_map)), false);
-    context.memory.push(&Instruction::get_u8_array(Instruction::new_l(Opcode::Jmp, opvm2::operand::Operand::Label(LabelValue::Address(current_pc - base))).encode(&empty_map)), false);
+    // both are relative, so this works wherever the code was loaded.
+    let data = data.encode(&empty_map).map_err(extism::Error::msg)?;
+    // the jump back is padded to a fixed length, so that its own length doesn't change where it jumps to.
+    let back_length = 12;
+    let skip = Instruction::new_l(Opcode::Jmp, Operand::Relative((data.len() + back_length) as isize)).encode(&empty_map).map_err(extism::Error::msg)?;
+    let back_end = current_end + skip.len() + data.len() + back_length;
+    let back = Instruction::new_l(Opcode::Jmp, Operand::Relative(current_pc as isize - back_end as isize)).encode_padded(&empty_map, back_length).map_err(extism::Error::msg)?;
+    let jmp_address = context.memory.push(&skip, false);
+    context.memory.push(&data, false);
+    context.memory.push(&back, false);
     context.registers.set_pc(jmp_address);
     Ok(())
 });
@@ -244,14 +486,10 @@ host_fn!(pub execute(user_data: MachineContext; data: Instruction) -> Result<(),
 #[cfg(test)]
 mod test {
     use extism::convert::Json;
-    use opvm2::{
-        instruction::Instruction,
-        parser::program::{LabelValue, Program},
-        register::Registers,
-    };
+    use opvm2::{parser::program::Program, register::Registers};
     use serde::{Deserialize, Serialize};
 
-    use crate::{plugin::Labels, register::Register, vm::Vm};
+    use crate::{io::BufferIo, plugin::Labels, register::Register, vm::Vm};
 
     fn load_plugins(plugins: Vec<String>) -> Result<Vec<Vec<u8>>, String> {
         let mut loaded = Vec::new();
@@ -323,8 +561,8 @@ mod test {
             }),
         )?;
         vm.run_program(Program::from("mov rb, ra")).unwrap();
-        assert_eq!(read_registers(&vm).ra, 5);
-        assert_eq!(read_registers(&vm).rb, 5);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 5);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 5);
         Ok(())
     }
 
@@ -342,9 +580,9 @@ mod test {
     fn can_get_all_registers() -> Result<(), extism::Error> {
         let mut vm = run_program(Program::from("mov ra, 10\nmov rb, 3")).unwrap();
         let registers = vm.plugin.plugins[0].call::<(), Registers>("get_all_registers_test", ())?;
-        assert_eq!(registers.ra, 10);
-        assert_eq!(registers.rb, 3);
-        assert_eq!(*registers.check_pc(), 32);
+        assert_eq!(registers.get(&Register::Ra), 10);
+        assert_eq!(registers.get(&Register::Rb), 3);
+        assert_eq!(*registers.check_pc(), 8);
         Ok(())
     }
 
@@ -362,13 +600,16 @@ mod test {
             let context = vm.context.get()?;
             let mut context = context.lock().unwrap();
             // try to pull the first instruction from memory, and decode the label?
-            let ins = context.memory.get_instruction(0);
-            let ins_decoded = Instruction::decode(ins);
+            let (ins_decoded, _) = context
+                .memory
+                .decode_instruction(0)
+                .map_err(extism::Error::msg)?;
             match ins_decoded.lhs {
-                opvm2::operand::Operand::Label(LabelValue::Address(address)) => {
-                    assert_eq!(address, 32);
+                opvm2::operand::Operand::Relative(offset) => {
+                    // the jump is relative to its end, and `mov ra, 10` takes 4 bytes.
+                    assert_eq!(offset, 4);
                 }
-                _ => panic!("Expected label address!"),
+                _ => panic!("Expected relative label address!"),
             }
         }
         Ok(())
@@ -396,10 +637,51 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn can_capture_plugin_output() -> Result<(), String> {
+        let io = BufferIo::new("");
+        let mut vm = load_vm();
+        vm.set_io(io.clone());
+        let mut program = Program::from("print_ascii 65\nprint_ascii 66");
+        program.plugins = load_plugins(vec![
+            "../target/wasm32-unknown-unknown/debug/plugin_test.wasm".to_string(),
+        ])?;
+        vm.run_program(program)?;
+        assert_eq!(io.output(), "AB\n");
+        Ok(())
+    }
+
+    #[test]
+    fn can_route_opcodes_to_plugins() -> Result<(), String> {
+        let vm = run_program(Program::from(""))?;
+        assert!(vm.plugin.handles("life"));
+        assert!(vm.plugin.handles("PRINT_ASCII"));
+        assert!(!vm.plugin.handles("instruction"));
+        assert!(!vm.plugin.handles("mov"));
+        assert_eq!(vm.plugin.handler_of("len"), Some("Test Plugin"));
+        Ok(())
+    }
+
+    #[test]
+    fn can_detect_conflicting_plugins() -> Result<(), String> {
+        let mut vm = load_vm();
+        let mut plugins = load_plugins(vec![
+            "../target/wasm32-unknown-unknown/debug/plugin_test.wasm".to_string(),
+        ])?;
+        plugins.push(plugins[0].clone());
+        let result = vm.plugin.load_all(&plugins, false);
+        assert!(result
+            .unwrap_err()
+            .contains("is handled by both `Test Plugin` and `Test Plugin`"));
+        // the conflicting plugin must not have been registered.
+        assert_eq!(vm.plugin.plugins.len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn can_handle_custom_opcode() {
         let vm = run_program(Program::from("life ra")).unwrap();
         let registers = read_registers(&vm);
-        assert_eq!(registers.ra, 42);
+        assert_eq!(registers.get(&Register::Ra), 42);
     }
 }
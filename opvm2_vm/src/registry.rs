@@ -0,0 +1,211 @@
+use std::path::PathBuf;
+
+use extism::UserData;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{machine_context::MachineContext, plugin::PluginLoader};
+
+/// Describes a wasm plugin: what it calls itself and which opcodes it handles.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct PluginInfo {
+    pub name: String,
+    pub version: String,
+    /// sha256 of the wasm module, as hex.
+    pub hash: String,
+    pub opcodes: Vec<String>,
+}
+
+impl PluginInfo {
+    /// Loads the wasm module on its own to read its metadata.
+    pub fn read(wasm: &[u8]) -> Result<PluginInfo, String> {
+        let mut loader = PluginLoader::new(UserData::new(MachineContext::new()));
+        loader.load(wasm.to_vec(), false)?;
+        Ok(loader.metadata()[0].clone())
+    }
+}
+
+/// A plugin as recorded in a compiled program. Plugins without `wasm` are looked up
+/// by name in a [`PluginRegistry`] when the program is run.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct PluginEntry {
+    pub info: PluginInfo,
+    pub wasm: Option<Vec<u8>>,
+}
+
+impl PluginEntry {
+    pub fn is_embedded(&self) -> bool {
+        self.wasm.is_some()
+    }
+}
+
+/// Finds plugins by name in a list of directories.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PluginRegistry {
+    pub search_paths: Vec<PathBuf>,
+}
+
+impl PluginRegistry {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        Self { search_paths }
+    }
+
+    /// Builds a registry from the `OPVM2_PLUGIN_PATH` environment variable.
+    pub fn from_env() -> Self {
+        let search_paths = match std::env::var_os("OPVM2_PLUGIN_PATH") {
+            Some(paths) => std::env::split_paths(&paths).collect(),
+            None => vec![],
+        };
+        Self { search_paths }
+    }
+
+    /// Finds a plugin either by its file name (without `.wasm`) or by the name it exports.
+    pub fn find(&self, name: &str) -> Result<(PluginInfo, Vec<u8>), String> {
+        // only the plugin that is returned gets loaded.
+        self.candidates(name)?
+            .into_iter()
+            .find_map(|wasm| PluginInfo::read(&wasm).ok().map(|info| (info, wasm)))
+            .ok_or_else(|| format!("Plugin `{}` not found in {:?}", name, self.search_paths))
+    }
+
+    /// Returns the wasm for the entry, looking it up by name and hash if it isn't embedded.
+    pub fn resolve(&self, entry: &PluginEntry) -> Result<Vec<u8>, String> {
+        if let Some(wasm) = &entry.wasm {
+            return Ok(wasm.clone());
+        }
+        let candidates = self.candidates(&entry.info.name)?;
+        if candidates.is_empty() {
+            return Err(format!(
+                "Plugin `{}` not found in {:?}",
+                entry.info.name, self.search_paths
+            ));
+        }
+        candidates
+            .into_iter()
+            .find(|wasm| hash(wasm) == entry.info.hash)
+            .ok_or_else(|| {
+                format!(
+                    "Plugin `{}` has changed since the program was compiled (expected hash {})",
+                    entry.info.name, entry.info.hash
+                )
+            })
+    }
+
+    // the plugins in the search paths called `name`, without loading them unless they don't
+    // declare their name with `plugin_name!`.
+    fn candidates(&self, name: &str) -> Result<Vec<Vec<u8>>, String> {
+        let mut found = vec![];
+        for dir in self.search_paths.iter() {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let mut paths: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+                .collect();
+            paths.sort();
+            for path in paths {
+                let wasm = std::fs::read(&path).map_err(|e| e.to_string())?;
+                // skip anything that isn't a plugin.
+                let declared = match PluginLoader::declared_name(&wasm) {
+                    Ok(declared) => declared,
+                    Err(_) => continue,
+                };
+                let stem = path.file_stem().and_then(|s| s.to_str());
+                let matches = stem == Some(name)
+                    || match declared {
+                        Some(declared) => declared == name,
+                        None => PluginInfo::read(&wasm).is_ok_and(|info| info.name == name),
+                    };
+                if matches {
+                    found.push(wasm);
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+pub fn hash(wasm: &[u8]) -> String {
+    Sha256::digest(wasm)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use opvm2::parser::program::Program;
+
+    use super::{PluginEntry, PluginRegistry};
+    use crate::{plugin::PluginLoader, vm::Vm, CompiledProgram};
+
+    fn registry() -> PluginRegistry {
+        PluginRegistry::new(vec![PathBuf::from(
+            "../target/wasm32-unknown-unknown/debug",
+        )])
+    }
+
+    #[test]
+    fn can_find_plugins_by_name() -> Result<(), String> {
+        let (info, wasm) = registry().find("Test Plugin")?;
+        assert_eq!(info.name, "Test Plugin");
+        assert_eq!(info.version, "0.1.0");
+        assert_eq!(info.hash, super::hash(&wasm));
+        assert!(info.opcodes.contains(&"life".to_string()));
+        assert_eq!(registry().find("plugin_test")?.0, info);
+        assert!(registry().find("missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn can_read_names_without_loading() -> Result<(), String> {
+        let dir = PathBuf::from("../target/wasm32-unknown-unknown/debug");
+        let wasm = std::fs::read(dir.join("plugin_test.wasm")).map_err(|e| e.to_string())?;
+        assert_eq!(
+            PluginLoader::declared_name(&wasm)?,
+            Some("Test Plugin".to_string())
+        );
+        let wasm = std::fs::read(dir.join("debugger.wasm")).map_err(|e| e.to_string())?;
+        assert_eq!(
+            PluginLoader::declared_name(&wasm)?,
+            Some("debugger".to_string())
+        );
+        // a module without a `name` function isn't a plugin.
+        assert!(PluginLoader::declared_name(b"\0asm\x01\0\0\0").is_err());
+        assert!(registry().find("debugger").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn can_run_program_with_referenced_plugin() -> Result<(), String> {
+        let mut compiled = CompiledProgram::new_e();
+        compiled.reference_plugin(&registry(), "Test Plugin")?;
+        compiled.compile(Program::from("life ra"), false)?;
+        assert_eq!(compiled.plugins.len(), 1);
+        assert!(!compiled.plugins[0].is_embedded());
+
+        let mut vm = Vm::new_e();
+        vm.registry = registry();
+        vm.run(compiled)?;
+        let context = vm.context.get().map_err(|e| e.to_string())?;
+        let context = context.lock().unwrap();
+        assert_eq!(context.registers.get(&crate::register::Register::Ra), 42);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_changed_plugins() -> Result<(), String> {
+        let (mut info, _) = registry().find("Test Plugin")?;
+        info.hash = "0".repeat(64);
+        let entry = PluginEntry { info, wasm: None };
+        assert!(registry()
+            .resolve(&entry)
+            .unwrap_err()
+            .contains("has changed since the program was compiled"));
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+//! Green threads: several threads of execution sharing the memory of one machine.
+//!
+//! Each thread has its own registers, stacks and interrupt handler state, which are swapped
+//! into the `MachineContext` while it runs. Threads are scheduled round-robin and only switch
+//! when the one running yields, waits or finishes, so a program runs the same way every time.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use opvm2::{register::Registers, stack::Stack};
+
+use crate::{convention::Checker, interrupt::HandlerState};
+
+/// The id of the thread a program starts in.
+pub const MAIN: usize = 0;
+
+/// The size of the memory stack of each thread `spawn` starts. They sit below the stack of the
+/// main thread, at the top of memory.
+pub const STACK_SIZE: usize = 0x4000;
+
+/// What a thread is doing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Ready,
+    /// Waiting for the thread with this id to finish.
+    Joining(usize),
+    /// Waiting for a value on this channel.
+    Receiving(usize),
+    /// Waiting for the mutex at this address to be unlocked.
+    Locking(usize),
+    /// Finished, with this return value.
+    Finished(usize),
+}
+
+/// What a thread keeps while another one runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Saved {
+    pub registers: Registers,
+    pub stack: Stack<usize>,
+    pub call_stack: Stack<usize>,
+    pub handlers: HandlerState,
+    pub checker: Option<Checker>,
+}
+
+impl Saved {
+    pub fn new() -> Saved {
+        Saved {
+            registers: Registers::new(),
+            stack: Stack::new(),
+            call_stack: Stack::new(),
+            handlers: HandlerState::default(),
+            checker: None,
+        }
+    }
+}
+
+impl Default for Saved {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Thread {
+    state: State,
+    // which of the stacks below the main thread's it uses, 0 being the main thread's own.
+    slot: usize,
+    // empty while the thread runs.
+    saved: Saved,
+}
+
+/// The threads of a machine, by id, and the channels they send values over.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threads {
+    // `None` once a thread has been joined, until `spawn` reuses its id.
+    threads: Vec<Option<Thread>>,
+    // the ids of joined threads.
+    free: Vec<usize>,
+    current: usize,
+    channels: BTreeMap<usize, VecDeque<usize>>,
+}
+
+impl Threads {
+    /// Just the main thread, running.
+    pub fn new() -> Threads {
+        Threads {
+            threads: vec![Some(Thread {
+                state: State::Ready,
+                slot: 0,
+                saved: Saved::new(),
+            })],
+            free: vec![],
+            current: MAIN,
+            channels: BTreeMap::new(),
+        }
+    }
+
+    /// The id of the thread running.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn state(&self, id: usize) -> Option<State> {
+        self.thread(id).map(|thread| thread.state)
+    }
+
+    pub fn set_state(&mut self, state: State) {
+        self.thread_mut(self.current).state = state;
+    }
+
+    /// The lowest stack slot no unfinished thread uses.
+    pub fn free_slot(&self) -> usize {
+        (1..)
+            .find(|slot| {
+                !self.threads.iter().flatten().any(|thread| {
+                    thread.slot == *slot && !matches!(thread.state, State::Finished(_))
+                })
+            })
+            .unwrap()
+    }
+
+    /// Adds a thread that starts from `saved` once it is scheduled, returning its id. The ids
+    /// of joined threads are reused.
+    pub fn spawn(&mut self, saved: Saved, slot: usize) -> usize {
+        let thread = Some(Thread {
+            state: State::Ready,
+            slot,
+            saved,
+        });
+        match self.free.pop() {
+            Some(id) => {
+                self.threads[id] = thread;
+                id
+            }
+            None => {
+                self.threads.push(thread);
+                self.threads.len() - 1
+            }
+        }
+    }
+
+    /// Takes the return value of thread `id` if it has finished, dropping the rest of it so that
+    /// its id can be reused.
+    pub fn join(&mut self, id: usize) -> Option<usize> {
+        let State::Finished(value) = self.state(id)? else {
+            return None;
+        };
+        self.threads[id] = None;
+        // other threads joining it wake up to find it gone, so the id can't be reused for them
+        // to join instead.
+        let waiting = self
+            .threads
+            .iter()
+            .flatten()
+            .any(|thread| thread.state == State::Joining(id));
+        if !waiting {
+            self.free.push(id);
+        }
+        Some(value)
+    }
+
+    pub fn saved_mut(&mut self, id: usize) -> &mut Saved {
+        &mut self.thread_mut(id).saved
+    }
+
+    pub fn send(&mut self, channel: usize, value: usize) {
+        self.channels.entry(channel).or_default().push_back(value);
+    }
+
+    pub fn receive(&mut self, channel: usize) -> Option<usize> {
+        self.channels.get_mut(&channel)?.pop_front()
+    }
+
+    /// The thread to run next: the first one after the current one that is ready or whose wait
+    /// is over, coming back around to the current one last. `is_unlocked` says whether the
+    /// mutex at an address is free.
+    pub fn next(&self, is_unlocked: impl Fn(usize) -> bool) -> Option<usize> {
+        let count = self.threads.len();
+        (1..=count)
+            .map(|offset| (self.current + offset) % count)
+            .find(|id| match self.state(*id) {
+                None => false,
+                Some(State::Ready) => true,
+                // a thread that's been joined already is gone, which `join` then reports.
+                Some(State::Joining(thread)) => {
+                    matches!(self.state(thread), Some(State::Finished(_)) | None)
+                }
+                Some(State::Receiving(channel)) => self
+                    .channels
+                    .get(&channel)
+                    .is_some_and(|values| !values.is_empty()),
+                Some(State::Locking(address)) => is_unlocked(address),
+                Some(State::Finished(_)) => false,
+            })
+    }
+
+    /// Makes `id` the thread running, once its state has been swapped in.
+    pub fn resume(&mut self, id: usize) {
+        self.current = id;
+        self.thread_mut(id).state = State::Ready;
+    }
+
+    fn thread(&self, id: usize) -> Option<&Thread> {
+        self.threads.get(id)?.as_ref()
+    }
+
+    // only called for threads that haven't been joined.
+    fn thread_mut(&mut self, id: usize) -> &mut Thread {
+        self.threads[id].as_mut().expect("thread has been joined")
+    }
+}
+
+impl Default for Threads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        io::BufferIo,
+        vm::{Engine, Vm},
+        CompiledProgram,
+    };
+
+    // runs the program with both engines, which have to schedule the threads the same way.
+    fn run_threads(program: &str) -> Result<String, String> {
+        let program = CompiledProgram::from(program);
+        let mut results = vec![];
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let io = BufferIo::new("");
+            let mut vm = Vm::new_e();
+            vm.engine = engine;
+            vm.set_io(io.clone());
+            results.push(vm.run(program.clone()).map(|_| io.output()));
+        }
+        assert_eq!(results[0], results[1]);
+        results.remove(0)
+    }
+
+    #[test]
+    fn can_spawn_and_join_threads() {
+        // threads take turns at `yield`, in the order they were started.
+        assert_eq!(
+            run_threads(
+                r"
+                    mov r0, 1
+                    spawn count
+                    mov r6, ra
+                    mov r0, 2
+                    spawn count
+                    mov r7, ra
+                    join r6
+                    mov r8, ra
+                    join r7
+                    add ra, r8
+                    print ra
+                    jmp end
+                count:
+                    mov rb, 3
+                again:
+                    print r0
+                    yield
+                    dec rb
+                    test rb, 0
+                    jne again
+                    mov ra, r0
+                    mul ra, 10
+                    ret
+                end:
+                "
+            ),
+            Ok("12121230\n".to_string())
+        );
+    }
+
+    #[test]
+    fn can_reuse_joined_threads() {
+        // every thread is joined before the next one starts, so they all get id 1.
+        assert_eq!(
+            run_threads(
+                r"
+                    mov rb, 0
+                    mov rc, 0
+                again:
+                    mov r0, rb
+                    spawn double
+                    mov r6, ra
+                    join r6
+                    add rc, ra
+                    inc rb
+                    test rb, 100
+                    jne again
+                    print r6
+                    print rc
+                    jmp end
+                double:
+                    mov ra, r0
+                    mul ra, 2
+                    ret
+                end:
+                "
+            ),
+            Ok("19900\n".to_string())
+        );
+        assert!(
+            run_threads("spawn done\nmov r6, ra\njoin r6\njoin r6\ndone: ret")
+                .unwrap_err()
+                .starts_with("join of thread 1, which doesn't exist")
+        );
+        // the second thread waiting for thread 1 finds it joined by the first one.
+        assert!(run_threads(
+            r"
+                spawn work
+                mov r0, ra
+                spawn wait
+                mov r6, ra
+                spawn wait
+                mov r7, ra
+                join r6
+                join r7
+                jmp end
+            work:
+                yield
+                ret
+            wait:
+                join r0
+                ret
+            end:
+            "
+        )
+        .unwrap_err()
+        .starts_with("join of thread 1, which doesn't exist"));
+    }
+
+    #[test]
+    fn can_send_values_between_threads() {
+        // the consumer waits on the channel until the producer has sent something.
+        assert_eq!(
+            run_threads(
+                r"
+                    spawn consumer
+                    mov r6, ra
+                    spawn producer
+                    join r6
+                    print ra
+                    jmp end
+                producer:
+                    mov rb, 1
+                more:
+                    send 1, rb
+                    inc rb
+                    test rb, 5
+                    jne more
+                    send 1, 0
+                    ret
+                consumer:
+                    mov ra, 0
+                next:
+                    recv rb, 1
+                    add ra, rb
+                    test rb, 0
+                    jne next
+                    ret
+                end:
+                "
+            ),
+            Ok("10\n".to_string())
+        );
+    }
+
+    #[test]
+    fn can_lock_mutexes() {
+        // both threads yield inside the critical section, so without the mutex an increment
+        // would get lost.
+        assert_eq!(
+            run_threads(
+                r"
+                section .data
+                mutex:   dq 0
+                counter: dq 0
+                section .text
+                    spawn work
+                    mov r6, ra
+                    spawn work
+                    mov r7, ra
+                    join r6
+                    join r7
+                    lea rc, counter
+                    print [rc]
+                    jmp end
+                work:
+                    mov rd, 3
+                    lea rc, counter
+                loop:
+                    lock mutex
+                    mov rb, [rc]
+                    yield
+                    inc rb
+                    mov [rc], rb
+                    unlock mutex
+                    dec rd
+                    test rd, 0
+                    jne loop
+                    ret
+                end:
+                "
+            ),
+            Ok("6\n".to_string())
+        );
+    }
+
+    #[test]
+    fn can_report_thread_errors() {
+        let start_address = CompiledProgram::from("recv ra, 1").start_address;
+        assert_eq!(
+            run_threads("recv ra, 1"),
+            Err(format!(
+                "Deadlock at ins {:#02X}: every thread is waiting.",
+                start_address
+            ))
+        );
+        assert_eq!(
+            run_threads("join 1"),
+            Err(format!(
+                "join of thread 1, which doesn't exist, at ins {:#02X}.",
+                start_address
+            ))
+        );
+        assert_eq!(
+            run_threads("unlock 0x100"),
+            Err(format!(
+                "unlock of a mutex thread 0 doesn't hold at ins {:#02X}.",
+                start_address
+            ))
+        );
+        // the main thread's stack is the last one.
+        assert!(run_threads("again: spawn again\njmp again")
+            .unwrap_err()
+            .starts_with("No room for the stack of another thread"));
+    }
+}
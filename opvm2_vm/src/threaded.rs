@@ -0,0 +1,646 @@
+use std::collections::HashMap;
+
+use opvm2::{instruction::Instruction, parser::program::LabelValue};
+
+use crate::{
+    convention::RETURN_VALUE,
+    machine_context::{MachineContext, WORD},
+    memory::Memory,
+    opcode::Opcode,
+    operand::Operand,
+    register::Register,
+    vm::format_float,
+};
+
+/// The code of a program translated into closures, one per instruction, with the operands
+/// and the targets of jumps resolved ahead of time.
+///
+/// It runs programs the same way the interpreter in `Vm::run` does, without plugins: they can
+/// change the code and the pc at any point, so programs using plugins are always interpreted.
+pub struct Threaded {
+    ops: Vec<Op>,
+    // address -> index of the op of the instruction at that address.
+    indexes: HashMap<usize, usize>,
+    start_address: usize,
+    end_address: usize,
+}
+
+/// How a translated program stopped.
+#[derive(Debug, PartialEq)]
+pub enum Exit {
+    /// The pc went past the end of the code.
+    End,
+    /// The program ran `halt`.
+    Halt,
+}
+
+type Run = Box<dyn Fn(&mut MachineContext) -> Result<Flow, String>>;
+type Jump = Box<dyn Fn(&MachineContext) -> Result<Flow, String>>;
+
+struct Op {
+    pc: usize,
+    length: usize,
+    run: Run,
+}
+
+enum Flow {
+    Next,
+    // jumps to the op at this index.
+    Jump(usize),
+    // jumps to an address that is only known when the program runs.
+    JumpTo(usize),
+    Halt,
+    // the op wrote to the code, which has to be translated again.
+    CodeWritten,
+}
+
+// an operand, resolved to what it reads when the op runs.
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    None,
+    Register(Register),
+    Constant(usize),
+    // the word at a register plus a displacement.
+    Memory(Register, isize),
+}
+
+impl Value {
+    // `next` is the end of the instruction, which relative operands are relative to.
+    fn of(operand: &Operand, next: usize) -> Result<Value, String> {
+        if let Some(address) = operand.get_relative(next) {
+            return Ok(Value::Constant(address));
+        }
+        Ok(match operand {
+            Operand::Number(n) => Value::Constant(*n),
+            Operand::Float(f) => Value::Constant(f.to_bits() as usize),
+            Operand::Register(r) => Value::Register(*r),
+            Operand::Label(LabelValue::Address(n)) => Value::Constant(*n),
+            Operand::Label(l) => return Err(format!("Label '{:?}' is not an address", l)),
+            Operand::Offset(offset) => Value::Memory(offset.register, offset.displacement),
+            _ => Value::None,
+        })
+    }
+
+    fn get(&self, context: &MachineContext) -> Result<Option<usize>, String> {
+        Ok(match self {
+            Value::None => None,
+            Value::Register(r) => Some(context.registers.get(r)),
+            Value::Constant(n) => Some(*n),
+            Value::Memory(..) => Some(context.load(self.address(context))?),
+        })
+    }
+
+    // the address a memory operand refers to.
+    fn address(&self, context: &MachineContext) -> usize {
+        match self {
+            Value::Memory(register, displacement) => context
+                .registers
+                .get(register)
+                .wrapping_add_signed(*displacement),
+            _ => panic!("Value is not in memory"),
+        }
+    }
+}
+
+impl Threaded {
+    /// Translates the code from `start_address` up to the end of the memory in use.
+    pub fn translate(memory: &mut Memory, start_address: usize) -> Threaded {
+        let end_address = memory.address();
+        let mut decoded = vec![];
+        let mut pc = start_address;
+        while pc < end_address {
+            match memory.decode_instruction(pc) {
+                Ok((instruction, length)) => {
+                    decoded.push((pc, length, Ok(instruction)));
+                    pc += length;
+                }
+                // only an error if the program gets here.
+                Err(e) => {
+                    decoded.push((pc, 0, Err(e)));
+                    break;
+                }
+            }
+        }
+
+        let mut threaded = Threaded {
+            ops: vec![],
+            indexes: decoded
+                .iter()
+                .enumerate()
+                .map(|(index, (pc, _, _))| (*pc, index))
+                .collect(),
+            start_address,
+            end_address,
+        };
+        for (pc, length, instruction) in decoded {
+            let run = instruction
+                .and_then(|instruction| threaded.op(&instruction, pc + length))
+                .unwrap_or_else(|e| Box::new(move |_| Err(e.clone())));
+            threaded.ops.push(Op { pc, length, run });
+        }
+        threaded
+    }
+
+    /// Runs the program from the op at the pc until it ends or halts.
+    pub fn run(&mut self, context: &mut MachineContext) -> Result<Exit, String> {
+        let mut index = match self.resolve(*context.registers.check_pc())? {
+            Some(index) => index,
+            None => return Ok(Exit::End),
+        };
+        loop {
+            let pc = self.ops[index].pc;
+            context.registers.set_pc(pc);
+            let handler = context.poll_interrupt(pc)?;
+            if handler != pc {
+                match self.resolve(handler)? {
+                    Some(target) => index = target,
+                    None => {
+                        context.registers.set_pc(handler);
+                        return Ok(Exit::End);
+                    }
+                }
+                context.registers.set_pc(handler);
+            }
+            let op = &self.ops[index];
+            let next = op.pc + op.length;
+            let target = match (op.run)(context)? {
+                Flow::Next => next,
+                Flow::Jump(target) => {
+                    index = target;
+                    continue;
+                }
+                Flow::JumpTo(target) => target,
+                Flow::Halt => return Ok(Exit::Halt),
+                Flow::CodeWritten => {
+                    *self = Threaded::translate(&mut context.memory, self.start_address);
+                    next
+                }
+            };
+            index = match self.next(context, target)? {
+                Some(index) => index,
+                None => return Ok(Exit::End),
+            };
+        }
+    }
+
+    // the index of the op to run at `target`. past the end of the code, threads other than the
+    // main one finish and the next one runs, while the main one ends the program like in the
+    // interpreter, with the pc past the end of the code.
+    fn next(&self, context: &mut MachineContext, target: usize) -> Result<Option<usize>, String> {
+        let mut target = target;
+        loop {
+            if let Some(index) = self.resolve(target)? {
+                return Ok(Some(index));
+            }
+            context.registers.set_pc(target);
+            match context.end_thread()? {
+                Some(next) => target = next,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // the index of the op at `address`, or `None` if the address is past the end of the code.
+    fn resolve(&self, address: usize) -> Result<Option<usize>, String> {
+        if address >= self.end_address {
+            return Ok(None);
+        }
+        match self.indexes.get(&address) {
+            Some(index) => Ok(Some(*index)),
+            None => Err(format!(
+                "Jump to {:#02X}, which is not the start of an instruction.",
+                address
+            )),
+        }
+    }
+
+    // jumps to the address of a relative operand or in a register or memory, or to
+    // `start_address + n` for a number. constant targets are resolved now.
+    fn jump(&self, operand: &Operand, next: usize) -> Result<Jump, String> {
+        let start_address = self.start_address;
+        let value = Value::of(operand, next)?;
+        let target = match (operand, value) {
+            (_, Value::Register(_) | Value::Memory(..)) => {
+                return Ok(Box::new(move |context| {
+                    Ok(Flow::JumpTo(value.get(context)?.expect("lhs is none")))
+                }))
+            }
+            (Operand::Relative(_), Value::Constant(address)) => address,
+            (_, Value::Constant(offset)) => start_address + offset,
+            (_, Value::None) => {
+                return Ok(Box::new(move |context| {
+                    Ok(Flow::JumpTo(
+                        start_address + value.get(context)?.expect("lhs is none"),
+                    ))
+                }))
+            }
+        };
+        Ok(match self.indexes.get(&target) {
+            Some(index) => {
+                let index = *index;
+                Box::new(move |_| Ok(Flow::Jump(index)))
+            }
+            None => Box::new(move |_| Ok(Flow::JumpTo(target))),
+        })
+    }
+
+    fn op(&self, instruction: &Instruction, next: usize) -> Result<Run, String> {
+        let (lhs, rhs) = (
+            Value::of(&instruction.lhs, next)?,
+            Value::of(&instruction.rhs, next)?,
+        );
+        let lhs_register = instruction.lhs.get_register();
+        Ok(match &instruction.opcode {
+            Opcode::Mov if matches!(lhs, Value::Memory(..)) => {
+                let (start_address, end_address) = (self.start_address, self.end_address);
+                Box::new(move |context| {
+                    let value = rhs.get(context)?.expect("rhs is None");
+                    let address = lhs.address(context);
+                    context.store(address, value)?;
+                    if address < end_address && address + WORD > start_address {
+                        return Ok(Flow::CodeWritten);
+                    }
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Mov => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    let value = rhs.get(context)?.expect("rhs is None");
+                    context.registers.set(&register, value);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod | Opcode::Xor => {
+                let operator: fn(usize, usize) -> usize = match instruction.opcode {
+                    Opcode::Add => |a, b| a + b,
+                    Opcode::Sub => |a, b| a - b,
+                    Opcode::Mul => |a, b| a * b,
+                    Opcode::Div => |a, b| a / b,
+                    Opcode::Mod => |a, b| a % b,
+                    _ => |a, b| a ^ b,
+                };
+                Box::new(move |context| {
+                    let lhs_value = lhs.get(context)?.expect("lhs is none");
+                    let rhs_value = rhs.get(context)?.expect("rhs is none");
+                    let value = operator(lhs_value, rhs_value);
+                    context.registers.set(&lhs_register.clone()?, value);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Inc | Opcode::Dec => {
+                let register = lhs_register?;
+                let step: fn(usize) -> usize = match instruction.opcode {
+                    Opcode::Inc => |value| value + 1,
+                    _ => |value| value - 1,
+                };
+                Box::new(move |context| {
+                    let value = step(lhs.get(context)?.expect("lhs is none"));
+                    context.registers.set(&register, value);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Print => Box::new(move |context| {
+                let value = lhs.get(context)?.expect("lhs is none");
+                context.io.print(&value.to_string())?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Printx => Box::new(move |context| {
+                let value = lhs.get(context)?.expect("lhs is none");
+                context.io.print(&format!("{:x}", value))?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Putc => Box::new(move |context| {
+                let value = lhs.get(context)?.expect("lhs is none");
+                context.io.write(&[value as u8])?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Puts => Box::new(move |context| {
+                let address = lhs.get(context)?.expect("lhs is none");
+                let text = context.memory.get_literal(address).to_vec();
+                context.io.write(&text)?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Getc => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    // the end of the input reads as 0.
+                    let byte = context.io.read_byte()?.unwrap_or(0);
+                    context.registers.set(&register, byte as usize);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Readln => {
+                let (start_address, end_address) = (self.start_address, self.end_address);
+                Box::new(move |context| {
+                    let address = lhs.get(context)?.expect("lhs is none");
+                    let max = rhs.get(context)?.expect("rhs is none");
+                    if max == 0 {
+                        return Err(format!(
+                            "readln needs room for at least the terminator at ins {:#02X}.",
+                            context.registers.check_pc()
+                        ));
+                    }
+                    let line = context.io.read_line()?;
+                    let line = line.trim_end_matches(['\n', '\r']).as_bytes();
+                    // keep the last byte for the null terminator.
+                    let mut data = line[..line.len().min(max - 1)].to_vec();
+                    data.push(0);
+                    context.memory.write(address, &data)?;
+                    if address < end_address && address + data.len() > start_address {
+                        return Ok(Flow::CodeWritten);
+                    }
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Push => Box::new(move |context| {
+                let value = lhs.get(context)?.expect("lhs is none");
+                context.stack.push(value);
+                Ok(Flow::Next)
+            }),
+            Opcode::Pop => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    let value = context.pop(&Opcode::Pop)?;
+                    context.registers.set(&register, value);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Depth => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    let depth = context.stack.len();
+                    context.registers.set(&register, depth);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Dup
+            | Opcode::Over
+            | Opcode::Pick
+            | Opcode::Swap
+            | Opcode::Rot
+            | Opcode::Drop
+            | Opcode::Pushall
+            | Opcode::Popall => {
+                let opcode = instruction.opcode.clone();
+                Box::new(move |context| {
+                    let operand = lhs.get(context)?;
+                    context.stack_op(&opcode, operand)?;
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Test => Box::new(move |context| {
+                let (lhs, rhs) = (lhs.get(context)?, rhs.get(context)?);
+                test(context, lhs, rhs);
+                Ok(Flow::Next)
+            }),
+            Opcode::Jmp => {
+                let jump = self.jump(&instruction.lhs, next)?;
+                Box::new(move |context| jump(context))
+            }
+            Opcode::Je
+            | Opcode::Jne
+            | Opcode::Jle
+            | Opcode::Jge
+            | Opcode::Jl
+            | Opcode::Jg
+            | Opcode::Jz
+            | Opcode::Jnz => {
+                let condition: fn(&MachineContext) -> bool = match instruction.opcode {
+                    Opcode::Je => |c| c.registers.check_equals_flag(),
+                    Opcode::Jne => |c| !c.registers.check_equals_flag(),
+                    Opcode::Jle => {
+                        |c| c.registers.check_equals_flag() || c.registers.check_less_than_flag()
+                    }
+                    Opcode::Jge => {
+                        |c| c.registers.check_equals_flag() || c.registers.check_greater_than_flag()
+                    }
+                    Opcode::Jl => |c| c.registers.check_less_than_flag(),
+                    Opcode::Jg => |c| c.registers.check_greater_than_flag(),
+                    Opcode::Jz => |c| c.registers.check_zero_flag(),
+                    _ => |c| !c.registers.check_zero_flag(),
+                };
+                let jump = self.jump(&instruction.lhs, next)?;
+                Box::new(move |context| match condition(context) {
+                    true => jump(context),
+                    false => Ok(Flow::Next),
+                })
+            }
+            Opcode::Call => {
+                let jump = self.jump(&instruction.lhs, next)?;
+                Box::new(move |context| {
+                    context.call(next)?;
+                    jump(context)
+                })
+            }
+            Opcode::Lea => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    let address = match rhs {
+                        Value::Memory(..) => rhs.address(context),
+                        _ => rhs.get(context)?.expect("rhs is none"),
+                    };
+                    context.registers.set(&register, address);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Int => Box::new(move |context| {
+                let vector = lhs.get(context)?.expect("lhs is none");
+                Ok(Flow::JumpTo(context.interrupt(vector, next)?))
+            }),
+            Opcode::Iret => Box::new(|context| Ok(Flow::JumpTo(context.iret()?))),
+            Opcode::Cli | Opcode::Sti => {
+                let enabled = instruction.opcode == Opcode::Sti;
+                Box::new(move |context| {
+                    context.interrupts.set_enabled(enabled);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Ivt => Box::new(move |context| {
+                let vector = lhs.get(context)?.expect("lhs is none");
+                let handler = rhs.get(context)?.expect("rhs is none");
+                context.interrupts.set_vector(vector, handler)?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Timer => Box::new(move |context| {
+                let period = lhs.get(context)?.expect("lhs is none");
+                context.interrupts.set_timer(period);
+                Ok(Flow::Next)
+            }),
+            Opcode::Spawn => {
+                // like a jump, numbers are offsets from the start of the code.
+                let base = match (&instruction.lhs, lhs) {
+                    (Operand::Relative(_), _) | (_, Value::Register(_) | Value::Memory(..)) => 0,
+                    _ => self.start_address,
+                };
+                Box::new(move |context| {
+                    let entry = base + lhs.get(context)?.expect("lhs is none");
+                    let id = context.spawn(entry)?;
+                    context.registers.set(&RETURN_VALUE, id);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Yield => Box::new(move |context| Ok(Flow::JumpTo(context.yield_thread(next)?))),
+            Opcode::Join => Box::new(move |context| {
+                let pc = *context.registers.check_pc();
+                let id = lhs.get(context)?.expect("lhs is none");
+                Ok(context.join(id, pc)?.map_or(Flow::Next, Flow::JumpTo))
+            }),
+            Opcode::Send => Box::new(move |context| {
+                let channel = lhs.get(context)?.expect("lhs is none");
+                let value = rhs.get(context)?.expect("rhs is none");
+                context.send(channel, value);
+                Ok(Flow::Next)
+            }),
+            Opcode::Recv => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    let pc = *context.registers.check_pc();
+                    let channel = rhs.get(context)?.expect("rhs is none");
+                    let next = context.receive(&register, channel, pc)?;
+                    Ok(next.map_or(Flow::Next, Flow::JumpTo))
+                })
+            }
+            Opcode::Lock => Box::new(move |context| {
+                let pc = *context.registers.check_pc();
+                let address = lhs.get(context)?.expect("lhs is none");
+                Ok(context.lock(address, pc)?.map_or(Flow::Next, Flow::JumpTo))
+            }),
+            Opcode::Unlock => Box::new(move |context| {
+                context.unlock(lhs.get(context)?.expect("lhs is none"))?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Enter => Box::new(move |context| {
+                context.enter(lhs.get(context)?.unwrap_or(0))?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Leave => Box::new(|context| {
+                context.leave()?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Return => Box::new(move |context| {
+                let return_address = context.ret(lhs.get(context)?.unwrap_or(0))?;
+                Ok(Flow::JumpTo(return_address))
+            }),
+            Opcode::Fadd | Opcode::Fsub | Opcode::Fmul | Opcode::Fdiv | Opcode::Fsqrt => {
+                let register = lhs_register?;
+                let operator: fn(f64, Option<f64>) -> f64 = match instruction.opcode {
+                    Opcode::Fadd => |a, b| a + b.expect("rhs is none"),
+                    Opcode::Fsub => |a, b| a - b.expect("rhs is none"),
+                    Opcode::Fmul => |a, b| a * b.expect("rhs is none"),
+                    Opcode::Fdiv => |a, b| a / b.expect("rhs is none"),
+                    _ => |a, b| b.unwrap_or(a).sqrt(),
+                };
+                Box::new(move |context| {
+                    let lhs_value = float(lhs.get(context)?.expect("lhs is none"));
+                    let rhs_value = rhs.get(context)?.map(float);
+                    let value = operator(lhs_value, rhs_value);
+                    context.registers.set_float(&register, value);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Fcmp => Box::new(move |context| {
+                let lhs_value = float(lhs.get(context)?.expect("lhs is none"));
+                let rhs_value = float(rhs.get(context)?.expect("rhs is none"));
+                context.compare_floats(lhs_value, rhs_value);
+                Ok(Flow::Next)
+            }),
+            Opcode::Itof => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    let value = rhs.get(context)?.expect("rhs is none") as i64 as f64;
+                    context.registers.set_float(&register, value);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Ftoi => {
+                let register = lhs_register?;
+                Box::new(move |context| {
+                    let value = float(rhs.get(context)?.expect("rhs is none")) as i64;
+                    context.registers.set(&register, value as usize);
+                    Ok(Flow::Next)
+                })
+            }
+            Opcode::Printf => Box::new(move |context| {
+                let value = float(lhs.get(context)?.expect("lhs is none"));
+                let text = format_float(value, rhs.get(context)?);
+                context.io.print(&text)?;
+                Ok(Flow::Next)
+            }),
+            Opcode::Assert => Box::new(move |context| {
+                let (lhs, rhs) = (lhs.get(context)?, rhs.get(context)?);
+                test(context, lhs, rhs);
+                if !context.registers.check_equals_flag() {
+                    return Err(format!(
+                        "Assertion failed at ins {:#02X}.",
+                        context.registers.check_pc()
+                    ));
+                }
+                context.registers.reset_flags();
+                Ok(Flow::Next)
+            }),
+            Opcode::Sleep => Box::new(move |context| {
+                let millis = lhs.get(context)?.expect("lhs is none") as u64;
+                std::thread::sleep(std::time::Duration::from_millis(millis));
+                Ok(Flow::Next)
+            }),
+            Opcode::Nop => Box::new(|_| Ok(Flow::Next)),
+            Opcode::Halt => Box::new(|_| Ok(Flow::Halt)),
+            Opcode::Plugin(s) => {
+                let message = format!("Plugin for '{}' not found", s);
+                Box::new(move |_| Err(message.clone()))
+            }
+        })
+    }
+}
+
+// the float a value holds the bits of.
+fn float(value: usize) -> f64 {
+    f64::from_bits(value as u64)
+}
+
+// sets the flags the same way `test` does in the interpreter.
+fn test(context: &mut MachineContext, lhs: Option<usize>, rhs: Option<usize>) {
+    context.registers.reset_flags();
+    if lhs == rhs {
+        context.registers.set_equals_flag(true);
+    }
+    if lhs < rhs {
+        context.registers.set_less_than_flag(true);
+    }
+    if lhs > rhs {
+        context.registers.set_greater_than_flag(true);
+    }
+    if lhs == Some(0) && rhs == Some(0) {
+        context.registers.set_zero_flag(true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        io::BufferIo,
+        parser::program::Program,
+        vm::{Engine, Vm},
+        CompiledProgram,
+    };
+
+    #[test]
+    fn can_only_jump_to_instructions() {
+        let mut compiled = CompiledProgram::new_e();
+        compiled
+            .compile(Program::from("mov r0, 1\njmp r0"), false)
+            .unwrap();
+        let start_address = compiled.start_address;
+        let mut vm = Vm::new_e();
+        vm.engine = Engine::Threaded;
+        vm.set_io(BufferIo::new(""));
+        assert_eq!(
+            vm.run(compiled),
+            Err(format!(
+                "Jump to {:#02X}, which is not the start of an instruction.",
+                start_address + 1
+            ))
+        );
+    }
+}
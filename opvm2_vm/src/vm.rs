@@ -8,33 +8,100 @@ use opvm2::{
 };
 
 use crate::{
-    machine_context::MachineContext, memory::Memory, opcode::Opcode, operand::Operand,
-    plugin::PluginLoader, CompiledProgram,
+    convention::{Checker, RETURN_VALUE},
+    device::Device,
+    interrupt::InterruptLine,
+    io::{Io, IoHandle},
+    machine_context::MachineContext,
+    memory::Memory,
+    opcode::Opcode,
+    operand::Operand,
+    plugin::PluginLoader,
+    registry::PluginRegistry,
+    thread::Threads,
+    threaded::{Exit, Threaded},
+    CompiledProgram,
 };
 
+/// Formats a float for `printf`, with `precision` digits after the point if it's given.
+pub(crate) fn format_float(value: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) => format!("{:.*}", precision, value),
+        None => value.to_string(),
+    }
+}
+
+/// How the `Vm` executes programs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Decodes and dispatches one instruction at a time.
+    #[default]
+    Interpreter,
+    /// Translates the program into closures before running it. Programs that load plugins
+    /// are still interpreted, since plugins can change the code and the pc while it runs.
+    Threaded,
+}
+
 #[derive(Debug)]
 pub struct Vm {
     pub context: UserData<MachineContext>,
     pub plugin: PluginLoader,
+    pub registry: PluginRegistry,
+    pub engine: Engine,
+    /// Raises interrupts on the machine, also while a program runs, see [`Vm::raise_interrupt`].
+    pub interrupts: InterruptLine,
 }
 
 impl Vm {
     pub fn new(context: MachineContext) -> Vm {
+        let interrupts = context.interrupts.line.clone();
         let context = UserData::new(context);
 
         Vm {
             context: context.clone(),
             plugin: PluginLoader::new(context),
+            registry: PluginRegistry::default(),
+            engine: Engine::default(),
+            interrupts,
         }
     }
 
     pub fn new_e() -> Vm {
-        let context = UserData::new(MachineContext::new());
+        Vm::new(MachineContext::new())
+    }
 
-        Vm {
-            context: context.clone(),
-            plugin: PluginLoader::new(context),
-        }
+    /// Raises interrupt `vector`, which runs its handler once the program enables interrupts.
+    ///
+    /// This doesn't lock the context, so it can be called from another thread while the
+    /// program runs, through a clone of [`Vm::interrupts`].
+    pub fn raise_interrupt(&self, vector: u8) {
+        self.interrupts.raise(vector);
+    }
+
+    /// Replaces the device the program reads input from and writes output to.
+    pub fn set_io(&mut self, io: impl Io + 'static) {
+        let context = self.context.get().map_err(|e| e.to_string()).unwrap();
+        let mut context = context.lock().unwrap();
+        context.io = IoHandle::new(io);
+    }
+
+    /// Makes calls fail when they don't preserve the callee-saved registers, see
+    /// [`crate::convention`].
+    pub fn check_calling_convention(&mut self, check: bool) {
+        let context = self.context.get().map_err(|e| e.to_string()).unwrap();
+        let mut context = context.lock().unwrap();
+        context.checker = check.then(Checker::new);
+    }
+
+    /// Maps `device` into memory at `address`, past the end of memory, see [`crate::device`].
+    pub fn map_device(
+        &mut self,
+        address: usize,
+        device: impl Device + 'static,
+    ) -> Result<(), String> {
+        let context = self.context.get().map_err(|e| e.to_string())?;
+        let mut context = context.lock().unwrap();
+        context.memory.map(address, device)
     }
 
     pub fn check_pc(&self) -> usize {
@@ -49,85 +116,117 @@ impl Vm {
         context.memory.address()
     }
 
-    pub fn get_instruction(&self) -> Instruction {
+    /// Decodes the instruction at the pc, returning it with its length in bytes.
+    pub fn get_instruction(&self) -> Result<(Instruction, usize), String> {
         let context = self.context.get().map_err(|e| e.to_string()).unwrap();
         let mut context = context.lock().unwrap();
         let pc = *context.registers.check_pc();
-        let pre_decoded = context.memory.get_instruction(pc);
-        Instruction::decode(pre_decoded)
+        context.memory.decode_instruction(pc)
     }
 
     pub fn run_program(&mut self, program: Program) -> Result<(), String> {
-        let program = CompiledProgram::from(program);
-        self.run(program)
+        let mut compiled = CompiledProgram::new_e();
+        compiled.compile_with(program, false, &self.plugin.opcodes())?;
+        self.run(compiled)
     }
 
     pub fn run(&mut self, program: CompiledProgram) -> Result<(), String> {
         let start_address = program.start_address;
-        self.plugin.load_all(&program.plugins, false)?;
+        for plugin in program.plugins.iter() {
+            let wasm = self.registry.resolve(plugin)?;
+            self.plugin.load(wasm, false)?;
+        }
 
         {
             let context = self.context.get().map_err(|e| e.to_string()).unwrap();
             let mut context = context.lock().unwrap();
             context.registers.set_pc(start_address);
+            let devices = context.memory.devices().clone();
             context.memory = Memory::from_raw(program.program, program.memory_address);
+            context.memory.set_devices(devices);
             context.base_address = start_address;
+            context.reset_stack();
+            context.interrupts.reset();
+            context.threads = Threads::new();
         }
 
-        'outer: while (self.check_pc() as usize) < self.check_address() {
-            let pc = self.check_pc();
-            let item = self.get_instruction();
-            let ins = OnInstructionValue {
-                opcode: item.opcode.clone(),
-                lhs: item.lhs.clone(),
-                rhs: item.rhs.clone(),
-                pc,
-            };
-            self.plugin.execute_plugin_fn(
-                "handle_instruction".to_string(),
-                ins.clone(),
-                true,
-                start_address,
-            )?;
-            // get plugin name from memory.
-            let plugin_name = match ins.opcode {
-                Opcode::Plugin(opvm2::opcode::PluginValue::Address(address)) => {
-                    let context = self.context.get().map_err(|e| e.to_string()).unwrap();
-                    let mut context = context.lock().unwrap();
-                    let address = address as usize;
-                    let plugin_name_bytes = context.memory.get_literal(address);
-                    let plugin_name = String::from_utf8(plugin_name_bytes.to_vec()).unwrap();
-                    plugin_name
+        let shared = self.context.get().map_err(|e| e.to_string())?;
+        if self.engine == Engine::Threaded && !self.plugin.is_active() {
+            let mut context = shared.lock().unwrap();
+            let mut threaded = Threaded::translate(&mut context.memory, start_address);
+            if threaded.run(&mut context)? == Exit::Halt {
+                return Ok(());
+            }
+            context.io.print("\n")?;
+            return Ok(());
+        }
+        loop {
+            // the context stays locked for the whole step, unless a plugin has to see it.
+            let mut context = shared.lock().unwrap();
+            let pc = *context.registers.check_pc();
+            if pc >= context.memory.address() {
+                // threads other than the main one finish there, the main one ends the program.
+                match context.end_thread()? {
+                    Some(next) => {
+                        context.registers.set_pc(next);
+                        continue;
+                    }
+                    None => break,
                 }
-                _ => item.opcode.to_string(),
-            };
-            match self.plugin.execute_plugin_fn(
-                format!("handle_{}", &plugin_name.to_lowercase()),
-                ins.clone(),
-                false,
-                start_address,
-            ) {
-                Ok(count) => {
-                    if count > 0 {
-                        continue 'outer;
+            }
+            let pc = context.poll_interrupt(pc)?;
+            context.registers.set_pc(pc);
+            let (item, length) = context.memory.decode_instruction(pc)?;
+
+            if self.plugin.is_active() {
+                // get plugin name from memory.
+                let plugin_name = match item.opcode {
+                    Opcode::Plugin(opvm2::opcode::PluginValue::Address(address)) => {
+                        let plugin_name_bytes = context.memory.get_literal(address as usize);
+                        String::from_utf8(plugin_name_bytes.to_vec()).unwrap()
                     }
+                    _ => item.opcode.to_string(),
+                };
+                // plugins lock the context themselves.
+                drop(context);
+                let ins = OnInstructionValue {
+                    opcode: item.opcode.clone(),
+                    lhs: item.lhs.clone(),
+                    rhs: item.rhs.clone(),
+                    pc,
+                };
+                self.plugin.execute_hooks(&ins, start_address)?;
+                if self
+                    .plugin
+                    .execute_opcode(&plugin_name.to_lowercase(), &ins, start_address)?
+                {
+                    continue;
                 }
-                Err(e) => return Err(e),
-            };
+                context = shared.lock().unwrap();
+            }
 
-            let context = self.context.get().map_err(|e| e.to_string()).unwrap();
-            let mut context = context.lock().unwrap();
-            let (lhs, rhs) = (
-                self.get_value(&mut context, &item.lhs)?,
-                self.get_value(&mut context, &item.rhs)?,
-            );
+            // `mov` writes to the memory its lhs refers to and `lea` takes the address of its rhs,
+            // neither of which is read.
+            let lhs = match (&item.opcode, &item.lhs) {
+                (Opcode::Mov, Operand::Offset(_)) => None,
+                _ => self.get_value(&mut context, &item.lhs)?,
+            };
+            let rhs = match (&item.opcode, &item.rhs) {
+                (Opcode::Lea, Operand::Offset(_)) => None,
+                _ => self.get_value(&mut context, &item.rhs)?,
+            };
 
             match item.opcode.clone() {
-                Opcode::Mov => {
-                    // todo: handle operands with offsets here?
-                    let lhs = item.lhs.get_register()?;
-                    context.registers.set(&lhs, rhs.expect("rhs is None"));
-                }
+                Opcode::Mov => match &item.lhs {
+                    Operand::Offset(offset) => {
+                        let address = offset.address(&context.registers);
+                        context.store(address, rhs.expect("rhs is None"))?;
+                    }
+                    _ => {
+                        let lhs = item.lhs.get_register()?;
+                        context.registers.set(&lhs, rhs.expect("rhs is None"));
+                    }
+                },
                 Opcode::Add => {
                     self.math(&mut context, &item.lhs, &item.rhs, item.opcode.clone())?
                 }
@@ -157,40 +256,86 @@ impl Vm {
                         .set(&item.lhs.get_register()?, lhs.expect("lhs is none") - 1);
                 }
                 Opcode::Print => {
-                    print!("{}", lhs.expect("lhs is none"));
+                    context.io.print(&lhs.expect("lhs is none").to_string())?;
+                }
+                Opcode::Printx => {
+                    context
+                        .io
+                        .print(&format!("{:x}", lhs.expect("lhs is none")))?;
+                }
+                Opcode::Putc => {
+                    context.io.write(&[lhs.expect("lhs is none") as u8])?;
+                }
+                Opcode::Puts => {
+                    let text = context
+                        .memory
+                        .get_literal(lhs.expect("lhs is none"))
+                        .to_vec();
+                    context.io.write(&text)?;
+                }
+                Opcode::Getc => {
+                    // the end of the input reads as 0.
+                    let byte = context.io.read_byte()?.unwrap_or(0);
+                    context
+                        .registers
+                        .set(&item.lhs.get_register()?, byte as usize);
+                }
+                Opcode::Readln => {
+                    let address = lhs.expect("lhs is none");
+                    let max = rhs.expect("rhs is none");
+                    if max == 0 {
+                        return Err(format!(
+                            "readln needs room for at least the terminator at ins {:#02X}.",
+                            context.registers.check_pc()
+                        ));
+                    }
+                    let line = context.io.read_line()?;
+                    let line = line.trim_end_matches(['\n', '\r']).as_bytes();
+                    // keep the last byte for the null terminator.
+                    let mut data = line[..line.len().min(max - 1)].to_vec();
+                    data.push(0);
+                    context.memory.write(address, &data)?;
                 }
                 Opcode::Push => {
                     context.stack.push(lhs.expect("lhs is none"));
                 }
                 Opcode::Pop => {
                     let lhs = item.lhs.get_register()?;
-                    let value = context.stack.pop().unwrap();
+                    let value = context.pop(&item.opcode)?;
                     context.registers.set(&lhs, value);
                 }
-                Opcode::Dup => {
-                    let peeked = *context.stack.peek().unwrap();
-                    context.stack.push(peeked);
+                Opcode::Depth => {
+                    let depth = context.stack.len();
+                    context.registers.set(&item.lhs.get_register()?, depth);
                 }
+                Opcode::Dup
+                | Opcode::Over
+                | Opcode::Pick
+                | Opcode::Swap
+                | Opcode::Rot
+                | Opcode::Drop
+                | Opcode::Pushall
+                | Opcode::Popall => context.stack_op(&item.opcode, lhs)?,
                 Opcode::Test => self.test(&mut context, &item.lhs, &item.rhs),
                 Opcode::Jmp => {
-                    context
-                        .registers
-                        .set_pc(start_address + lhs.expect("lhs is none"));
+                    let target =
+                        self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                    context.registers.set_pc(target);
                     continue;
                 }
                 Opcode::Je => {
                     if context.registers.check_equals_flag() {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
                 Opcode::Jne => {
                     if !context.registers.check_equals_flag() {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
@@ -198,9 +343,9 @@ impl Vm {
                     if context.registers.check_equals_flag()
                         || context.registers.check_less_than_flag()
                     {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
@@ -208,57 +353,148 @@ impl Vm {
                     if context.registers.check_equals_flag()
                         || context.registers.check_greater_than_flag()
                     {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
                 Opcode::Jl => {
                     if context.registers.check_less_than_flag() {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
                 Opcode::Jg => {
                     if context.registers.check_greater_than_flag() {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
                 Opcode::Jz => {
                     if context.registers.check_zero_flag() {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
                 Opcode::Jnz => {
                     if !context.registers.check_zero_flag() {
-                        context
-                            .registers
-                            .set_pc(start_address + lhs.expect("lhs is none"));
+                        let target =
+                            self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                        context.registers.set_pc(target);
                         continue;
                     }
                 }
                 Opcode::Call => {
-                    let call_stack_pointer = context.registers.check_pc() + 16;
-                    context.call_stack.push(call_stack_pointer);
-                    context
-                        .registers
-                        .set_pc(start_address + lhs.expect("lhs is none"));
+                    context.call(pc + length)?;
+                    let target =
+                        self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                    context.registers.set_pc(target);
                     continue;
                 }
                 Opcode::Return => {
-                    let return_address = context.call_stack.pop().unwrap();
+                    let return_address = context.ret(lhs.unwrap_or(0))?;
+                    context.registers.set_pc(return_address);
+                    continue;
+                }
+                Opcode::Lea => {
+                    let address = match &item.rhs {
+                        Operand::Offset(offset) => offset.address(&context.registers),
+                        _ => match item.rhs.get_relative(pc + length) {
+                            Some(address) => address,
+                            None => rhs.expect("rhs is none"),
+                        },
+                    };
+                    context.registers.set(&item.lhs.get_register()?, address);
+                }
+                Opcode::Int => {
+                    let handler = context.interrupt(lhs.expect("lhs is none"), pc + length)?;
+                    context.registers.set_pc(handler);
+                    continue;
+                }
+                Opcode::Iret => {
+                    let return_address = context.iret()?;
                     context.registers.set_pc(return_address);
                     continue;
                 }
+                Opcode::Cli => context.interrupts.set_enabled(false),
+                Opcode::Sti => context.interrupts.set_enabled(true),
+                Opcode::Ivt => {
+                    let handler = match item.rhs.get_relative(pc + length) {
+                        Some(address) => address,
+                        None => rhs.expect("rhs is none"),
+                    };
+                    context
+                        .interrupts
+                        .set_vector(lhs.expect("lhs is none"), handler)?;
+                }
+                Opcode::Timer => context.interrupts.set_timer(lhs.expect("lhs is none")),
+                Opcode::Spawn => {
+                    let entry =
+                        self.jump_target(&mut context, &item.lhs, pc + length, start_address)?;
+                    let id = context.spawn(entry)?;
+                    context.registers.set(&RETURN_VALUE, id);
+                }
+                Opcode::Yield => {
+                    let next = context.yield_thread(pc + length)?;
+                    context.registers.set_pc(next);
+                    continue;
+                }
+                Opcode::Join => {
+                    if let Some(next) = context.join(lhs.expect("lhs is none"), pc)? {
+                        context.registers.set_pc(next);
+                        continue;
+                    }
+                }
+                Opcode::Send => context.send(lhs.expect("lhs is none"), rhs.expect("rhs is none")),
+                Opcode::Recv => {
+                    let register = item.lhs.get_register()?;
+                    if let Some(next) = context.receive(&register, rhs.expect("rhs is none"), pc)? {
+                        context.registers.set_pc(next);
+                        continue;
+                    }
+                }
+                Opcode::Lock => {
+                    if let Some(next) = context.lock(lhs.expect("lhs is none"), pc)? {
+                        context.registers.set_pc(next);
+                        continue;
+                    }
+                }
+                Opcode::Unlock => context.unlock(lhs.expect("lhs is none"))?,
+                Opcode::Enter => context.enter(lhs.unwrap_or(0))?,
+                Opcode::Leave => context.leave()?,
+                Opcode::Fadd | Opcode::Fsub | Opcode::Fmul | Opcode::Fdiv | Opcode::Fsqrt => {
+                    self.float_math(&mut context, &item.lhs, &item.rhs, item.opcode.clone())?
+                }
+                Opcode::Fcmp => {
+                    let lhs = f64::from_bits(lhs.expect("lhs is none") as u64);
+                    let rhs = f64::from_bits(rhs.expect("rhs is none") as u64);
+                    context.compare_floats(lhs, rhs);
+                }
+                Opcode::Itof => {
+                    // integers are signed here, so that negative numbers stay negative.
+                    let value = rhs.expect("rhs is none") as i64 as f64;
+                    context
+                        .registers
+                        .set_float(&item.lhs.get_register()?, value);
+                }
+                Opcode::Ftoi => {
+                    // rounds towards zero, NaN becomes 0.
+                    let value = f64::from_bits(rhs.expect("rhs is none") as u64) as i64;
+                    context
+                        .registers
+                        .set(&item.lhs.get_register()?, value as usize);
+                }
+                Opcode::Printf => {
+                    let value = f64::from_bits(lhs.expect("lhs is none") as u64);
+                    context.io.print(&format_float(value, rhs))?;
+                }
                 Opcode::Assert => {
                     self.test(&mut context, &item.lhs, &item.rhs);
                     if !context.registers.check_equals_flag() {
@@ -283,10 +519,11 @@ impl Vm {
                     return Err(format!("Plugin for '{}' not found", s));
                 }
             }
-            context.registers.increment_pc();
+            context.registers.increment_pc(length);
         }
         // bug in rust perhaps? using print! causes a % to be outputted if no newline is printed afterwards.
-        println!("");
+        let context = shared.lock().unwrap();
+        context.io.print("\n")?;
         Ok(())
     }
 
@@ -308,6 +545,26 @@ impl Vm {
         }
     }
 
+    // labels are relative to `next`, the end of the instruction, registers and memory hold
+    // addresses and numbers are offsets from the start of the code.
+    fn jump_target(
+        &self,
+        context: &mut MutexGuard<MachineContext>,
+        operand: &Operand,
+        next: usize,
+        start_address: usize,
+    ) -> Result<usize, String> {
+        if let Some(address) = operand.get_relative(next) {
+            return Ok(address);
+        }
+        match operand {
+            Operand::Register(_) | Operand::Offset(_) => {
+                Ok(self.get_value(context, operand)?.expect("lhs is none"))
+            }
+            _ => Ok(start_address + self.get_value(context, operand)?.expect("lhs is none")),
+        }
+    }
+
     fn get_value(
         &self,
         context: &mut MutexGuard<MachineContext>,
@@ -315,37 +572,46 @@ impl Vm {
     ) -> Result<Option<usize>, String> {
         match operand {
             Operand::Number(n) => Ok(Some(*n)),
+            Operand::Float(f) => Ok(Some(f.to_bits() as usize)),
             Operand::Register(r) => Ok(Some(context.registers.get(&r))),
             Operand::Label(l) => match l {
                 LabelValue::Address(n) => Ok(Some(*n as usize)),
                 _ => Err(format!("Label '{:?}' is not an address", l)),
             },
-            // Operand::Offset(o) => {
-            // what we want to do here is get the value at the specified offset.
-            // if it is just a single lhs then we want to get the address?
-            // if it is a lhs and rhs then we want to get the value at the address + offset
-            // in the future, to get an address and specify an offset, we will do something like this:
-            // mov lhs, [rhs] + operand
-
-            // let lhs: Operand = o.lhs_operand.try_into()?;
-            // match o.rhs_operand {
-            //     Some(rhs) => {
-            //         let rhs: Operand = rhs.try_into()?;
-            //         self.math(context, &lhs, &rhs)?;
-            //     }
-            //     None => {
-            //         // get the address of the lhs.
-            //         // this should only work when X is a label?
-            //     }
-            // }
-            // // match the operator
-
-            // Ok(Some(value + offset))
-            // }
+            Operand::Offset(offset) => {
+                let address = offset.address(&context.registers);
+                Ok(Some(context.load(address)?))
+            }
             _ => Ok(None),
         }
     }
 
+    // `fsqrt` takes the square root of its rhs, or of its lhs if it only has one operand.
+    fn float_math(
+        &mut self,
+        context: &mut MutexGuard<MachineContext>,
+        lhs: &Operand,
+        rhs: &Operand,
+        operator: Opcode,
+    ) -> Result<(), String> {
+        let lhs_value = f64::from_bits(self.get_value(context, lhs)?.expect("lhs is none") as u64);
+        let rhs_value = self
+            .get_value(context, rhs)?
+            .map(|v| f64::from_bits(v as u64));
+        let value = match operator {
+            Opcode::Fadd => lhs_value + rhs_value.expect("rhs is none"),
+            Opcode::Fsub => lhs_value - rhs_value.expect("rhs is none"),
+            Opcode::Fmul => lhs_value * rhs_value.expect("rhs is none"),
+            Opcode::Fdiv => lhs_value / rhs_value.expect("rhs is none"),
+            Opcode::Fsqrt => rhs_value.unwrap_or(lhs_value).sqrt(),
+            _ => panic!("Invalid operator for float operation"),
+        };
+
+        context.registers.set_float(&lhs.get_register()?, value);
+
+        Ok(())
+    }
+
     fn math(
         &mut self,
         context: &mut MutexGuard<MachineContext>,
@@ -382,25 +648,46 @@ mod test {
     use crate::register::Register;
 
     fn run(input: Vec<Instruction>) -> Result<Vm, String> {
-        let mut vm = super::Vm::new_e();
-        let program = Program {
+        run_program(Program {
             instructions: input,
             labels: Labels::new(),
             plugins: vec![],
-        };
-        vm.run_program(program)?;
-        Ok(vm)
+            sections: Default::default(),
+            globals: vec![],
+        })
     }
 
     fn run_l(input: Vec<Instruction>, labels: Vec<(String, LabelValue)>) -> Result<Vm, String> {
-        let mut vm = super::Vm::new_e();
-        let program = Program {
+        run_program(Program {
             instructions: input,
             labels: Labels::from(labels),
             plugins: vec![],
-        };
-        vm.run_program(program)?;
-        Ok(vm)
+            sections: Default::default(),
+            globals: vec![],
+        })
+    }
+
+    // runs the program with both engines, which have to end up in the same state.
+    fn run_program(program: Program) -> Result<Vm, String> {
+        let mut threaded = super::Vm::new_e();
+        threaded.engine = Engine::Threaded;
+        let threaded_result = threaded.run_program(program.clone());
+        let mut vm = super::Vm::new_e();
+        let result = vm.run_program(program);
+        assert_eq!(result, threaded_result);
+        assert_eq!(read_state(&vm), read_state(&threaded));
+        result.map(|_| vm)
+    }
+
+    fn read_state(vm: &Vm) -> (Registers, Stack<usize>, Stack<usize>, Vec<u8>) {
+        let context = vm.context.get().map_err(|e| e.to_string()).unwrap();
+        let context = context.lock().unwrap();
+        (
+            context.registers.clone(),
+            context.stack.clone(),
+            context.call_stack.clone(),
+            context.memory.raw(),
+        )
     }
 
     fn read_registers(vm: &Vm) -> Registers {
@@ -415,9 +702,10 @@ mod test {
         context.stack.pop().ok_or("Stack is empty".to_string())
     }
 
-    use super::Vm;
+    use super::{Engine, Vm};
     use opvm2::parser::program::LabelValue;
     use opvm2::register::Registers;
+    use opvm2::stack::Stack;
     use test_case::test_case;
 
     #[test]
@@ -450,7 +738,7 @@ mod test {
             ),
         ];
         let vm = run(input)?;
-        assert_eq!(read_registers(&vm).ra, 30);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 30);
 
         Ok(())
     }
@@ -463,7 +751,7 @@ mod test {
             Operand::Number(10),
         )];
         let vm = run(input)?;
-        assert_eq!(read_registers(&vm).ra, 10);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
         Ok(())
     }
 
@@ -482,8 +770,8 @@ mod test {
             ),
         ];
         let vm = run(input).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 10);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 10);
     }
 
     #[test_case(Opcode::Add, "ra", 1, "rb", 2, 3; "can add rb + ra = 3")]
@@ -569,7 +857,7 @@ mod test {
             Instruction::new_l(Opcode::Pop, Operand::Register(Register::Ra)),
         ];
         let vm = run(input)?;
-        assert_eq!(read_registers(&vm).ra, 10);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
         Ok(())
     }
 
@@ -583,8 +871,8 @@ mod test {
             Instruction::new_l(Opcode::Pop, Operand::Register(Register::Rb)),
         ];
         let mut vm = run(input).unwrap();
-        assert_eq!(read_registers(&vm).ra, 30);
-        assert_eq!(read_registers(&vm).rb, 20);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 30);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 20);
         assert_eq!(pop_stack(&mut vm), Ok(10));
     }
 
@@ -592,9 +880,9 @@ mod test {
     fn can_jump() -> Result<(), String> {
         let input = vec![
             Instruction::new(
-                Opcode::Mov,
+                Opcode::Lea,
                 Operand::Register(Register::R0),
-                Operand::Number(64),
+                Operand::Label(LabelValue::Literal("target".to_string())),
             ),
             Instruction::new_l(Opcode::Jmp, Operand::Register(Register::R0)),
             Instruction::new(
@@ -613,10 +901,11 @@ mod test {
                 Operand::Number(30),
             ),
         ];
-        let vm = run(input)?;
-        assert_eq!(read_registers(&vm).ra, 0);
-        assert_eq!(read_registers(&vm).rb, 0);
-        assert_eq!(read_registers(&vm).rc, 30);
+        let labels = vec![("target".to_string(), LabelValue::Address(4))];
+        let vm = run_l(input, labels)?;
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 0);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 0);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 30);
         Ok(())
     }
 
@@ -645,9 +934,9 @@ mod test {
         ];
         let labels = vec![("start".to_string(), LabelValue::Address(3))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 0);
-        assert_eq!(read_registers(&vm).rb, 0);
-        assert_eq!(read_registers(&vm).rc, 30);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 0);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 0);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 30);
     }
 
     #[test]
@@ -692,10 +981,10 @@ mod test {
             ("end".to_string(), LabelValue::Address(7)),
         ];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 20);
-        assert_eq!(read_registers(&vm).rc, 1);
-        assert_ne!(read_registers(&vm).rd, 1);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 20);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 1);
+        assert_ne!(read_registers(&vm).get(&Register::Rd), 1);
     }
 
     #[test]
@@ -726,8 +1015,8 @@ mod test {
             ("end".to_string(), LabelValue::Address(5)),
         ];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 20);
-        assert_eq!(read_registers(&vm).rb, 20);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 20);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 20);
     }
 
     #[test]
@@ -760,9 +1049,9 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(6))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 10);
-        assert_eq!(read_registers(&vm).rc, 0);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 0);
     }
 
     #[test]
@@ -795,9 +1084,9 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(6))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 20);
-        assert_eq!(read_registers(&vm).rc, 0);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 20);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 0);
     }
 
     #[test]
@@ -830,9 +1119,9 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(6))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 10);
-        assert_ne!(read_registers(&vm).rc, 1);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 10);
+        assert_ne!(read_registers(&vm).get(&Register::Rc), 1);
     }
 
     #[test]
@@ -865,9 +1154,9 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(6))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 20);
-        assert_ne!(read_registers(&vm).rc, 1);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 20);
+        assert_ne!(read_registers(&vm).get(&Register::Rc), 1);
     }
 
     #[test]
@@ -900,9 +1189,9 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(6))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 20);
-        assert_eq!(read_registers(&vm).rc, 1);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 20);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 1);
     }
 
     #[test]
@@ -935,16 +1224,16 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(6))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 20);
-        assert_eq!(read_registers(&vm).rb, 10);
-        assert_eq!(read_registers(&vm).rc, 1);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 20);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 1);
     }
 
     #[test]
     fn nop_does_nothing() {
         let input = vec![Instruction::new_e(Opcode::Nop)];
         let vm = run(input).unwrap();
-        assert_eq!(read_registers(&vm).ra, 0);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 0);
     }
 
     #[test]
@@ -958,7 +1247,7 @@ mod test {
             ),
         ];
         let vm = run(input).unwrap();
-        assert_eq!(read_registers(&vm).ra, 0);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 0);
     }
 
     #[test]
@@ -987,9 +1276,9 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(6))];
         let vm = run_l(input, labels).unwrap();
-        assert_eq!(read_registers(&vm).ra, 10);
-        assert_eq!(read_registers(&vm).rb, 10);
-        assert_eq!(read_registers(&vm).rc, 1);
+        assert_eq!(read_registers(&vm).get(&Register::Ra), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rb), 10);
+        assert_eq!(read_registers(&vm).get(&Register::Rc), 1);
     }
 
     #[test]
@@ -1018,7 +1307,7 @@ mod test {
         ];
         let labels = vec![("end".to_string(), LabelValue::Address(5))];
         let vm = run_l(input, labels);
-        assert_eq!(vm.unwrap_err(), "Assertion failed at ins 0x20.".to_string());
+        assert_eq!(vm.unwrap_err(), "Assertion failed at ins 0x8.".to_string());
     }
 
     #[test]
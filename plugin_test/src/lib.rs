@@ -7,9 +7,11 @@ use opvm2::{
 };
 use serde::{Deserialize, Serialize};
 
+opvm2::plugin_name!("Test Plugin");
+
 #[plugin_fn]
-pub fn name() -> FnResult<String> {
-    Ok("Test Plugin".to_string())
+pub fn version() -> FnResult<String> {
+    Ok(env!("CARGO_PKG_VERSION").to_string())
 }
 
 #[plugin_fn]
@@ -89,6 +91,7 @@ pub fn handle_print_ascii(Json(ins): Json<OnInstructionValue>) -> FnResult<Optio
             LabelValue::Literal(literal) => unsafe {
                 print((literal).into())?;
             },
+            LabelValue::Data { .. } | LabelValue::Constant(_) => {}
         },
         _ => {}
     }